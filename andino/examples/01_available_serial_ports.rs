@@ -3,11 +3,27 @@
 // ***************************************************************************
 //
 //! This example shows how to list all available serial ports on the system using
-//! the `serialport` crate.
+//! the `serialport` crate, and which one `auto` selects as the Andino board.
+
+use andino::core::comm::SerialDeviceSelector;
 
 fn main() {
     let ports = serialport::available_ports().expect("No ports found!");
     for p in ports {
-        println!("{}", p.port_name);
+        match &p.port_type {
+            serialport::SerialPortType::UsbPort(usb_port_info) => println!(
+                "{} usb:{:04x}:{:04x}:{} {}",
+                p.port_name,
+                usb_port_info.vid,
+                usb_port_info.pid,
+                usb_port_info.serial_number.as_deref().unwrap_or_default(),
+                usb_port_info.product.as_deref().unwrap_or_default()
+            ),
+            _ => println!("{}", p.port_name),
+        }
+    }
+    match SerialDeviceSelector::Auto.resolve() {
+        Ok(port_name) => println!("auto: {}", port_name),
+        Err(err) => println!("auto: {}", err),
     }
 }
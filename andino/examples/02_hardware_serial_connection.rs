@@ -8,16 +8,20 @@
 //! - `ReadEncoderValues`
 //! - `SetMotorValues <left> <right>`
 //! - `SetPIDValues <kp> <ki> <kd> <ko>`
+//! - `ResetEncoders`
+//! - `SetMotorPWMValues <left> <right>`
 //!
 //! cargo run --example 02_hardware_serial_connection
 //!
+//! Use `--emulate` to talk to the in-process firmware emulator instead of a real board.
+//!
 
 use clap::Parser;
 
 #[derive(Parser, Debug)]
 #[command(author, version)]
 struct Args {
-    /// Serial device name.
+    /// Serial device name, `auto` or `usb:<vid>[:<pid>[:<serial number>]]`.
     #[arg(short, long, default_value_t = String::from("/dev/ttyUSB0"))]
     serial_device: String,
 
@@ -28,6 +32,14 @@ struct Args {
     /// Timeout for the serial connection in milliseconds.
     #[arg(short, long, default_value_t = 3000)]
     timeout: u64,
+
+    /// Maximum time to wait for the firmware to be ready in milliseconds.
+    #[arg(short, long, default_value_t = 5000)]
+    ready_timeout: u64,
+
+    /// Use the in-process firmware emulator instead of the serial device.
+    #[arg(long)]
+    emulate: bool,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -36,12 +48,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     log::info!("Creates an instance of HwSerialConnection");
     // Create a new serial connection
-    let mut serial_connection =
-        andino::core::comm::HwSerialConnection::new(args.serial_device, args.baud_rate, args.timeout)?;
+    let mut serial_connection = if args.emulate {
+        andino::core::comm::HwSerialConnection::from_transport(
+            andino::core::emulator::FirmwareEmulator::new(andino::core::emulator::EmulatorConfig::default()),
+            args.timeout,
+        )
+    } else {
+        let serial_device = args
+            .serial_device
+            .parse::<andino::core::comm::SerialDeviceSelector>()?
+            .resolve()?;
+        andino::core::comm::HwSerialConnection::new(serial_device, args.baud_rate, args.timeout)?
+    };
 
-    // TODO(francocipollone): Add a method to check if the serial connection is open and ready.
-    log::info!("Waits 3 seconds for the serial connection to be established");
-    std::thread::sleep(std::time::Duration::from_secs(3));
+    log::info!("Waits for the firmware to be ready");
+    serial_connection.wait_until_ready(args.ready_timeout)?;
 
     loop {
         // Ask the user for a command, the input can have several arguments
@@ -51,7 +72,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             \t - ReadEncoderValues
             \t - SetMotorValues <tps_left> <tps_right>
             \t - SetPIDValues <kp> <ki> <kd> <ko>
-            Note: <tps_left> and <tps_right> are ticks(encoder) per second."
+            \t - ResetEncoders
+            \t - SetMotorPWMValues <pwm_left> <pwm_right>
+            Note: <tps_left> and <tps_right> are ticks(encoder) per second.
+            Note: <pwm_left> and <pwm_right> range from -255 to 255."
         );
         println!("* Enter a command (or 'exit' to quit):");
         let mut input = String::new();
@@ -123,6 +147,27 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     ko: ko.unwrap(),
                 }
             }
+            "ResetEncoders" => andino::core::comm::SerialCommands::ResetEncoders,
+            "SetMotorPWMValues" => {
+                if input_args.len() < 3 {
+                    println!("SetMotorPWMValues command requires two arguments.");
+                    continue;
+                }
+                let left = input_args[1].parse::<i64>();
+                if left.is_err() {
+                    println!("Invalid value for left motor: {}", input_args[1]);
+                    continue;
+                }
+                let right = input_args[2].parse::<i64>();
+                if right.is_err() {
+                    println!("Invalid value for right motor: {}", input_args[2]);
+                    continue;
+                }
+                andino::core::comm::SerialCommands::SetMotorPWMValues {
+                    left: left.unwrap(),
+                    right: right.unwrap(),
+                }
+            }
             _ => {
                 println!("Unknown command: {}\nTry again", input_args[0]);
                 continue;
@@ -4,6 +4,9 @@
 //
 //! Example of how to use the `Hal` struct to communicate with the underlying
 //! hardware via serial port.
+//!
+//! Use `--emulate` to drive the in-process firmware emulator instead of a real robot,
+//! and `--record <FILE>` to record the serial traffic for `04_replay_recording`.
 
 use clap::Parser;
 use crossterm::{
@@ -26,7 +29,7 @@ struct Args {
     #[arg(short, long, default_value_t = 700)]
     ticks_per_revolution: u64,
 
-    /// Serial device name.
+    /// Serial device name, `auto` or `usb:<vid>[:<pid>[:<serial number>]]`.
     #[arg(short, long, default_value_t = String::from("/dev/ttyUSB0"))]
     serial_device: String,
 
@@ -37,6 +40,22 @@ struct Args {
     /// Timeout for the serial connection in milliseconds.
     #[arg(long, default_value_t = 3000)]
     timeout: u64,
+
+    /// Maximum time to wait for the firmware to be ready in milliseconds.
+    #[arg(short, long, default_value_t = 5000)]
+    ready_timeout: u64,
+
+    /// Use the in-process firmware emulator instead of the serial device.
+    #[arg(long)]
+    emulate: bool,
+
+    /// Record the serial traffic to this file.
+    #[arg(long)]
+    record: Option<std::path::PathBuf>,
+
+    /// Wire protocol: `ascii` or `binary`.
+    #[arg(long, default_value_t = andino::core::comm::Protocol::Ascii)]
+    protocol: andino::core::comm::Protocol,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -53,15 +72,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("\r  - 'q' or 'Esc' to quit");
 
     log::info!("Creates an instance of andino::core::hal::Hal");
-    let mut hal = andino::core::hal::Hal::new(&andino::core::hal::HalConfig {
-        serial_device: args.serial_device,
+    let hal_config = andino::core::hal::HalConfig {
+        serial_device: args.serial_device.parse()?,
         baud_rate: args.baud_rate,
         timeout: args.timeout,
         motor_ticks_per_revolution: args.ticks_per_revolution,
-    })?;
-    // TODO(francocipollone): Add a method to check if the serial connection is open and ready.
-    log::info!("Waits 3 seconds for the serial connection to be established");
-    std::thread::sleep(std::time::Duration::from_secs(3));
+        ready_timeout: args.ready_timeout,
+        record_file: args.record,
+        protocol: args.protocol,
+        ..Default::default()
+    };
+    let mut hal = if args.emulate {
+        andino::core::hal::Hal::with_transport(
+            &hal_config,
+            andino::core::emulator::FirmwareEmulator::new(andino::core::emulator::EmulatorConfig {
+                protocol: args.protocol,
+                ..Default::default()
+            }),
+        )?
+    } else {
+        andino::core::hal::Hal::new(&hal_config)?
+    };
+    println!("\rFirmware: {}", hal.firmware_info());
 
     terminal::enable_raw_mode()?;
     // Create a separate thread for getting the commands from the user
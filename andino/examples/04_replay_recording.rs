@@ -0,0 +1,68 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Example of how to replay a recording of the serial traffic through the `Hal`.
+//!
+//! Record a session with `03_hal_interface --record <FILE>` (or `RECORD_FILE` in the
+//! `dora_andino_hal` node) and pass the file to this example. The encoder reads are replayed
+//! through `Hal::poll_state` with the recorded times, printing the state of the wheels.
+
+use andino::core::comm::recording::{ReplayTransport, load_recording};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(author, version)]
+struct Args {
+    /// The recording to replay.
+    recording: std::path::PathBuf,
+
+    /// Encoder ticks per revolution.
+    #[arg(short, long, default_value_t = 700)]
+    ticks_per_revolution: u64,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+    let args = Args::parse();
+
+    let exchanges = load_recording(&args.recording)?;
+    // The readiness probe takes the encoder reads up to the first answered one, then every encoder read is a poll.
+    let mut poll_timestamps = exchanges
+        .iter()
+        .filter(|exchange| exchange.is_encoder_read())
+        .skip_while(|exchange| exchange.outcome != "ok")
+        .map(|exchange| exchange.timestamp)
+        .collect::<Vec<_>>()
+        .into_iter();
+    let Some(mut last_timestamp) = poll_timestamps.next() else {
+        println!("No answered encoder reads in the recording");
+        return Ok(());
+    };
+
+    let hal_config = andino::core::hal::HalConfig {
+        // Recorded timeouts are replayed as timeouts, keep them short.
+        timeout: 10,
+        motor_ticks_per_revolution: args.ticks_per_revolution,
+        ..Default::default()
+    };
+    let transport = ReplayTransport::new(exchanges, 1).skip_unmatched(true);
+    let mut hal = andino::core::hal::Hal::with_transport(&hal_config, transport)?;
+
+    for timestamp in poll_timestamps {
+        let delta_time = (timestamp - last_timestamp).as_secs_f64();
+        match hal.poll_state(delta_time) {
+            Ok(hal_state) => {
+                println!(
+                    "{:10.6} s: left: {:?} right: {:?}",
+                    timestamp.as_secs_f64(),
+                    hal_state.left_wheel_state,
+                    hal_state.right_wheel_state
+                );
+                last_timestamp = timestamp;
+            }
+            Err(err) => println!("{:10.6} s: {}", timestamp.as_secs_f64(), err),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,91 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Example measuring the control rate the `Hal` achieves against the firmware emulator.
+//!
+//! Every control cycle sets the speed of the motors and reads the encoders, first with
+//! `Hal::set_motor_speed` and `Hal::poll_state`, one round trip each, then with `Hal::cycle`,
+//! which pipelines both commands in a single round trip.
+
+use std::time::{Duration, Instant};
+
+use andino::core::comm::Protocol;
+use andino::core::emulator::{EmulatorConfig, FirmwareEmulator};
+use andino::core::hal::{Hal, HalConfig};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(author, version)]
+struct Args {
+    /// Number of control cycles to measure.
+    #[arg(short, long, default_value_t = 200)]
+    cycles: u32,
+
+    /// The emulated baud rate.
+    #[arg(short, long, default_value_t = 57600)]
+    baud_rate: u32,
+
+    /// Time the emulated firmware takes to process a command, in milliseconds.
+    #[arg(short = 'l', long, default_value_t = 1)]
+    processing_latency: u64,
+
+    /// Wire protocol: `ascii` or `binary`.
+    #[arg(short, long, default_value_t = Protocol::Ascii)]
+    protocol: Protocol,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+    let args = Args::parse();
+
+    let hal_config = HalConfig {
+        baud_rate: args.baud_rate,
+        timeout: 1000,
+        protocol: args.protocol,
+        ..Default::default()
+    };
+    let emulator_config = EmulatorConfig {
+        baud_rate: args.baud_rate,
+        processing_latency: Duration::from_millis(args.processing_latency),
+        protocol: args.protocol,
+        ..Default::default()
+    };
+    let mut hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(emulator_config))?;
+
+    let start = Instant::now();
+    for i in 0..args.cycles {
+        let speed = f64::from(i % 10);
+        hal.set_motor_speed(speed, -speed)?;
+        hal.poll_state(0.01)?;
+    }
+    let separate = start.elapsed() / args.cycles;
+
+    let start = Instant::now();
+    for i in 0..args.cycles {
+        let speed = f64::from(i % 10);
+        hal.cycle(Some((speed, -speed)), 0.01)?;
+    }
+    let pipelined = start.elapsed() / args.cycles;
+
+    println!(
+        "{} baud, {} ms processing latency, {} protocol, {} cycles",
+        args.baud_rate, args.processing_latency, args.protocol, args.cycles
+    );
+    println!(
+        "set_motor_speed + poll_state: {:6.2} ms per cycle, up to {:6.1} Hz",
+        separate.as_secs_f64() * 1000.0,
+        1.0 / separate.as_secs_f64()
+    );
+    println!(
+        "cycle:                        {:6.2} ms per cycle, up to {:6.1} Hz",
+        pipelined.as_secs_f64() * 1000.0,
+        1.0 / pipelined.as_secs_f64()
+    );
+    println!(
+        "Improvement: {:.0}%",
+        (separate.as_secs_f64() / pipelined.as_secs_f64() - 1.0) * 100.0
+    );
+    hal.set_motor_speed(0.0, 0.0)?;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Fuzz target for the response parser of `HwSerialConnection`.
+//!
+//! The first byte of the input selects the command and the protocol, the rest is what the firmware answers.
+//! The response goes through the whole receive path: framing, decoding and parsing.
+//!
+//! ```sh
+//! cargo +nightly fuzz run parse_response
+//! ```
+
+#![no_main]
+
+use andino::core::comm::transport::ScriptedTransport;
+use andino::core::comm::{HwSerialConnection, Protocol, SerialCommands};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((selector, received)) = data.split_first() else {
+        return;
+    };
+    let command = match selector % 11 {
+        0 => SerialCommands::ReadEncoderValues,
+        1 => SerialCommands::SetMotorValues { left: 100, right: -100 },
+        2 => SerialCommands::SetPIDValues {
+            kp: 30.0,
+            ki: 0.0,
+            kd: 10.0,
+            ko: 10.0,
+        },
+        3 => SerialCommands::ResetEncoders,
+        4 => SerialCommands::SetMotorPWMValues { left: 255, right: -255 },
+        5 => SerialCommands::ReadAnalogPin { pin: 0 },
+        6 => SerialCommands::ReadDigitalPin { pin: 2 },
+        7 => SerialCommands::WriteAnalogPin { pin: 5, value: 128 },
+        8 => SerialCommands::WriteDigitalPin { pin: 13, value: true },
+        9 => SerialCommands::ReadFirmwareInfo,
+        _ => SerialCommands::ReadPIDValues,
+    };
+    let protocol = if selector & 0x80 == 0 {
+        Protocol::Ascii
+    } else {
+        Protocol::Binary
+    };
+    let transport = ScriptedTransport::new(received.to_vec());
+    let mut connection = HwSerialConnection::from_transport(transport, 1000);
+    connection.set_protocol(protocol);
+    let _ = connection.send_command(command);
+});
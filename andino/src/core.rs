@@ -1,3 +1,6 @@
 pub mod comm;
+pub mod emulator;
+pub mod gpio;
 pub mod hal;
 pub mod sensors;
+pub mod sim;
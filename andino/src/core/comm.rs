@@ -6,29 +6,251 @@
 //! For further details regarding the hardware take a look at the
 //! firmware code at <https://github.com/Ekumen-OS/andino/tree/humble/andino_firmware>
 
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
 use thiserror::Error;
 
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod binary;
+pub mod discovery;
+pub mod recording;
+pub mod stats;
+pub mod transport;
+
+pub use discovery::SerialDeviceSelector;
+pub use recording::SerialRecorder;
+pub use stats::{CommStats, CommandStats, LatencyHistogram};
+pub use transport::Transport;
+
+/// Response of the firmware to commands that are accepted.
+const OK_RESPONSE: &str = "OK";
+/// Response of the firmware to commands that are unknown.
+const INVALID_COMMAND_RESPONSE: &str = "Invalid Command";
+/// Byte terminating every response of the firmware, which replies using `Serial.println`.
+const RESPONSE_TERMINATOR: u8 = b'\n';
+/// Responses longer than this without a terminator are considered malformed.
+const MAX_RESPONSE_LENGTH: usize = 256;
+/// Size of the chunks read from the transport.
+const READ_CHUNK_SIZE: usize = 64;
+/// Read timeout of the serial port in milliseconds. The response timeout is enforced on top of it.
+const SERIAL_PORT_READ_TIMEOUT: u64 = 10;
+/// Time to wait for the response to each readiness probe in milliseconds.
+const READINESS_PROBE_TIMEOUT: u64 = 100;
+
 /// Error type for the serial connection.
 #[derive(Debug, Error, PartialEq)]
 pub enum HwSerialConnectionError {
     #[error("Serial port connection error: {error}")]
-    /// File system error.
+    /// The serial port cannot be opened.
     SerialPortConnectionError { error: String },
-    #[error("Serial port read error: {error}")]
-    /// File system error.
-    WrongResponseError { error: String },
+    #[error("Serial port disconnected: {error}")]
+    /// The transport failed once open, e.g. because the device was unplugged.
+    DisconnectedError { error: String },
+    #[error("Serial port timeout error: {error}")]
+    /// No complete response arrived in time.
+    TimeoutError {
+        error: String,
+        /// The bytes of the incomplete response.
+        received: Vec<u8>,
+    },
+    #[error("Serial port malformed response error: {error}")]
+    /// The received bytes do not form a valid response to the command.
+    MalformedResponseError {
+        error: String,
+        /// The bytes of the response.
+        raw: Vec<u8>,
+    },
+    #[error("Unexpected response error: {error}")]
+    /// The response is valid, but not of the type expected for the command.
+    UnexpectedResponseError { error: String, response: SerialResponse },
+    #[error("Firmware not ready error: {error}")]
+    /// The firmware did not answer the readiness probes in time.
+    NotReadyError { error: String },
+    #[error("Serial recording error: {error}")]
+    /// A recording of the serial traffic cannot be written or read.
+    RecordingError { error: String },
+    #[error("Serial device discovery error: {error}")]
+    /// The serial device cannot be selected.
+    DeviceDiscoveryError { error: String },
+    #[error("Serial protocol error: {error}")]
+    /// The wire protocol is unknown.
+    ProtocolError { error: String },
+}
+
+impl HwSerialConnectionError {
+    /// Whether the connection to the device is lost, so it has to be reopened.
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(
+            self,
+            HwSerialConnectionError::SerialPortConnectionError { .. }
+                | HwSerialConnectionError::DisconnectedError { .. }
+        )
+    }
+
+    /// Whether the error affects a single exchange, so sending the command again may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            HwSerialConnectionError::TimeoutError { .. }
+                | HwSerialConnectionError::MalformedResponseError { .. }
+                | HwSerialConnectionError::UnexpectedResponseError { .. }
+        )
+    }
 }
 
 impl From<std::io::Error> for HwSerialConnectionError {
     fn from(source: std::io::Error) -> Self {
-        HwSerialConnectionError::SerialPortConnectionError {
+        HwSerialConnectionError::DisconnectedError {
             error: source.to_string(),
         }
     }
 }
 
+/// The wire protocol spoken with the firmware.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// Text commands terminated by `\r` and responses terminated by `\r\n`, spoken by the stock firmware.
+    #[default]
+    Ascii,
+    /// The same commands and responses in packets with a CRC and a sequence number, see [`binary`].
+    Binary,
+}
+
+impl Protocol {
+    /// Byte terminating every command.
+    pub(crate) fn command_terminator(&self) -> u8 {
+        match self {
+            Protocol::Ascii => b'\r',
+            Protocol::Binary => binary::FRAME_DELIMITER,
+        }
+    }
+
+    /// Byte terminating every response.
+    pub(crate) fn response_terminator(&self) -> u8 {
+        match self {
+            Protocol::Ascii => RESPONSE_TERMINATOR,
+            Protocol::Binary => binary::FRAME_DELIMITER,
+        }
+    }
+
+    /// Encodes a command prepared by [`HwSerialConnection::prepare_command_to_send`].
+    ///
+    /// # Arguments
+    ///
+    /// * `sequence` - The sequence number of the command. Only used by the binary protocol.
+    /// * `command_str` - The command, with its carriage return.
+    pub(crate) fn encode_command(&self, sequence: u8, command_str: &str) -> Vec<u8> {
+        match self {
+            Protocol::Ascii => command_str.as_bytes().to_vec(),
+            Protocol::Binary => binary::encode_packet(sequence, command_str.trim_end_matches('\r').as_bytes()),
+        }
+    }
+
+    /// Decodes a response frame, including its terminator.
+    ///
+    /// # Arguments
+    ///
+    /// * `sequence` - The sequence number of the command waiting for a response. Only used by the binary protocol.
+    /// * `frame` - The bytes of the frame.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(String))` - The response.
+    /// * `Ok(None)` - If the frame answers another command, so it has to be discarded.
+    /// * `Err(HwSerialConnectionError)` - An error if the frame is malformed.
+    pub(crate) fn decode_response(
+        &self,
+        sequence: u8,
+        frame: &[u8],
+    ) -> Result<Option<String>, HwSerialConnectionError> {
+        match self {
+            Protocol::Ascii => HwSerialConnection::decode_frame(frame).map(Some),
+            Protocol::Binary => {
+                let (response_sequence, payload) = binary::decode_packet(frame)?;
+                if response_sequence != sequence {
+                    log::debug!(
+                        "Discarding the response to command #{} while waiting for #{}",
+                        response_sequence,
+                        sequence
+                    );
+                    return Ok(None);
+                }
+                HwSerialConnection::decode_frame(&payload).map(Some)
+            }
+        }
+    }
+
+    /// Takes the response to a command out of the bytes received so far.
+    ///
+    /// Complete frames answering other commands are discarded along the way. It does no I/O, so the
+    /// blocking and the async connections frame the responses alike.
+    ///
+    /// # Arguments
+    ///
+    /// * `sequence` - The sequence number of the command waiting for a response. Only used by the binary protocol.
+    /// * `read_buffer` - The bytes received but not consumed yet. The frames taken are removed from it.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(String))` - The response, without its terminator.
+    /// * `Ok(None)` - If more bytes are needed to complete the response.
+    /// * `Err(HwSerialConnectionError)` - An error if a frame is malformed or no terminator arrives
+    ///   within `MAX_RESPONSE_LENGTH` bytes, in which case the read buffer is emptied.
+    pub(crate) fn take_response(
+        &self,
+        sequence: u8,
+        read_buffer: &mut Vec<u8>,
+    ) -> Result<Option<String>, HwSerialConnectionError> {
+        let terminator = self.response_terminator();
+        while let Some(terminator_position) = read_buffer.iter().position(|b| *b == terminator) {
+            let frame = read_buffer.drain(..=terminator_position).collect::<Vec<u8>>();
+            if let Some(response) = self.decode_response(sequence, &frame)? {
+                return Ok(Some(response));
+            }
+        }
+        if read_buffer.len() > MAX_RESPONSE_LENGTH {
+            let raw = std::mem::take(read_buffer);
+            return Err(HwSerialConnectionError::MalformedResponseError {
+                error: format!(
+                    "No line terminator in the first {} bytes: {:?}",
+                    MAX_RESPONSE_LENGTH,
+                    String::from_utf8_lossy(&raw)
+                ),
+                raw,
+            });
+        }
+        Ok(None)
+    }
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = HwSerialConnectionError;
+
+    /// Parses `ascii` or `binary`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "ascii" => Ok(Protocol::Ascii),
+            "binary" => Ok(Protocol::Binary),
+            other => Err(HwSerialConnectionError::ProtocolError {
+                error: format!("Unknown protocol '{}', expected 'ascii' or 'binary'", other),
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Ascii => write!(f, "ascii"),
+            Protocol::Binary => write!(f, "binary"),
+        }
+    }
+}
+
 /// Enum representing the commands that can be sent to the serial connection.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SerialCommands {
     /// Command to read encoder values.
     ReadEncoderValues,
@@ -36,23 +258,291 @@ pub enum SerialCommands {
     SetMotorValues { left: i64, right: i64 },
     /// Command to modify PID values of the motor controller.
     SetPIDValues { kp: f32, ki: f32, kd: f32, ko: f32 },
+    /// Command to reset the encoder counts to zero. It also resets the PID controllers.
+    ResetEncoders,
+    /// Command to set the raw PWM of the motors, bypassing the PID controllers. (-255 to 255)
+    SetMotorPWMValues { left: i64, right: i64 },
+    /// Command to read the value of an analog pin. (0 to 1023)
+    ReadAnalogPin { pin: u8 },
+    /// Command to read the value of a digital pin.
+    ReadDigitalPin { pin: u8 },
+    /// Command to write the PWM value of an analog output pin. (0 to 255)
+    WriteAnalogPin { pin: u8, value: u8 },
+    /// Command to set a digital pin high or low.
+    WriteDigitalPin { pin: u8, value: bool },
+    /// Command to read the version of the firmware and the commands it supports.
+    ReadFirmwareInfo,
+    /// Command to read the PID values of the motor controller, as set by [`SerialCommands::SetPIDValues`].
+    ReadPIDValues,
+}
+
+impl SerialCommands {
+    /// The kind of the command.
+    pub fn kind(&self) -> SerialCommandKind {
+        match self {
+            SerialCommands::ReadEncoderValues => SerialCommandKind::ReadEncoderValues,
+            SerialCommands::SetMotorValues { .. } => SerialCommandKind::SetMotorValues,
+            SerialCommands::SetPIDValues { .. } => SerialCommandKind::SetPIDValues,
+            SerialCommands::ResetEncoders => SerialCommandKind::ResetEncoders,
+            SerialCommands::SetMotorPWMValues { .. } => SerialCommandKind::SetMotorPWMValues,
+            SerialCommands::ReadAnalogPin { .. } => SerialCommandKind::ReadAnalogPin,
+            SerialCommands::ReadDigitalPin { .. } => SerialCommandKind::ReadDigitalPin,
+            SerialCommands::WriteAnalogPin { .. } => SerialCommandKind::WriteAnalogPin,
+            SerialCommands::WriteDigitalPin { .. } => SerialCommandKind::WriteDigitalPin,
+            SerialCommands::ReadFirmwareInfo => SerialCommandKind::ReadFirmwareInfo,
+            SerialCommands::ReadPIDValues => SerialCommandKind::ReadPIDValues,
+        }
+    }
+}
+
+/// The kinds of [`SerialCommands`], without their arguments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SerialCommandKind {
+    ReadEncoderValues,
+    SetMotorValues,
+    SetPIDValues,
+    ResetEncoders,
+    SetMotorPWMValues,
+    ReadAnalogPin,
+    ReadDigitalPin,
+    WriteAnalogPin,
+    WriteDigitalPin,
+    ReadFirmwareInfo,
+    ReadPIDValues,
+}
+
+impl SerialCommandKind {
+    /// All the kinds of commands.
+    pub const ALL: [SerialCommandKind; 11] = [
+        SerialCommandKind::ReadEncoderValues,
+        SerialCommandKind::SetMotorValues,
+        SerialCommandKind::SetPIDValues,
+        SerialCommandKind::ResetEncoders,
+        SerialCommandKind::SetMotorPWMValues,
+        SerialCommandKind::ReadAnalogPin,
+        SerialCommandKind::ReadDigitalPin,
+        SerialCommandKind::WriteAnalogPin,
+        SerialCommandKind::WriteDigitalPin,
+        SerialCommandKind::ReadFirmwareInfo,
+        SerialCommandKind::ReadPIDValues,
+    ];
+
+    /// The letter the command starts with on the wire.
+    pub fn letter(&self) -> char {
+        match self {
+            SerialCommandKind::ReadEncoderValues => 'e',
+            SerialCommandKind::SetMotorValues => 'm',
+            SerialCommandKind::SetPIDValues => 'u',
+            SerialCommandKind::ResetEncoders => 'r',
+            SerialCommandKind::SetMotorPWMValues => 'o',
+            SerialCommandKind::ReadAnalogPin => 'a',
+            SerialCommandKind::ReadDigitalPin => 'd',
+            SerialCommandKind::WriteAnalogPin => 'x',
+            SerialCommandKind::WriteDigitalPin => 'w',
+            SerialCommandKind::ReadFirmwareInfo => 'v',
+            SerialCommandKind::ReadPIDValues => 'i',
+        }
+    }
+
+    /// The kind of command starting with the given letter, if any.
+    pub fn from_letter(letter: char) -> Option<SerialCommandKind> {
+        SerialCommandKind::ALL.into_iter().find(|kind| kind.letter() == letter)
+    }
+}
+
+/// The firmware build on the board and what it supports, as answered to a [`SerialCommands::ReadFirmwareInfo`].
+///
+/// On the wire: `<version> <letters of the supported commands>[ <encoder ticks per revolution>]`,
+/// e.g. `1.2.0 emuroadxwvi 700`. Letters of commands unknown to this crate are ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FirmwareInfo {
+    /// The version of the firmware, `None` if the firmware does not report it.
+    pub version: Option<String>,
+    /// The commands the firmware accepts.
+    pub supported_commands: Vec<SerialCommandKind>,
+    /// The encoder ticks per revolution of the motors the firmware is configured with, if it reports them.
+    pub encoder_ticks_per_revolution: Option<u64>,
+}
+
+impl FirmwareInfo {
+    /// The firmware released before the version query, which accepts every command but the query itself
+    /// and the read of the PID values, introduced with it.
+    pub fn legacy() -> Self {
+        FirmwareInfo {
+            version: None,
+            supported_commands: SerialCommandKind::ALL
+                .into_iter()
+                .filter(|kind| {
+                    !matches!(
+                        kind,
+                        SerialCommandKind::ReadFirmwareInfo | SerialCommandKind::ReadPIDValues
+                    )
+                })
+                .collect(),
+            encoder_ticks_per_revolution: None,
+        }
+    }
+
+    /// Whether the firmware accepts the given kind of command.
+    pub fn supports(&self, command_kind: SerialCommandKind) -> bool {
+        self.supported_commands.contains(&command_kind)
+    }
+}
+
+impl std::fmt::Display for FirmwareInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "version {}, commands '{}'",
+            self.version.as_deref().unwrap_or("unknown"),
+            self.supported_commands
+                .iter()
+                .map(SerialCommandKind::letter)
+                .collect::<String>()
+        )?;
+        if let Some(encoder_ticks_per_revolution) = self.encoder_ticks_per_revolution {
+            write!(f, ", {} encoder ticks per revolution", encoder_ticks_per_revolution)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a command is retried after a transient error (see [`HwSerialConnectionError::is_transient`]).
+///
+/// The default policy does not retry.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of times the command is sent again.
+    pub max_retries: u32,
+    /// The time to wait before sending the command again in milliseconds.
+    pub retry_delay: u64,
+}
+
+impl RetryPolicy {
+    /// Whether a command that failed with the given error is sent again, after the given number of retries.
+    pub(crate) fn allows_retry(&self, error: &HwSerialConnectionError, retries: u32) -> bool {
+        error.is_transient() && retries < self.max_retries
+    }
+}
+
+/// The readiness probes of [`HwSerialConnection::wait_until_ready`].
+///
+/// It keeps the count of probes and tells when to stop, but neither does I/O nor reads the clock, so the
+/// blocking and the async connections probe the firmware alike.
+pub(crate) struct ReadinessProbes {
+    /// The maximum time to wait for the firmware in milliseconds.
+    ready_timeout: u64,
+    /// The number of probes sent so far.
+    attempts: usize,
+}
+
+impl ReadinessProbes {
+    pub(crate) fn new(ready_timeout: u64) -> Self {
+        ReadinessProbes {
+            ready_timeout,
+            attempts: 0,
+        }
+    }
+
+    /// The maximum time to wait for the firmware.
+    pub(crate) fn ready_timeout(&self) -> Duration {
+        Duration::from_millis(self.ready_timeout)
+    }
+
+    /// Starts a probe and returns how long to wait for its response, given the time left until the deadline.
+    pub(crate) fn start_probe(&mut self, time_left: Duration) -> Duration {
+        self.attempts += 1;
+        time_left.min(Duration::from_millis(READINESS_PROBE_TIMEOUT))
+    }
+
+    /// Handles the result of the last probe.
+    ///
+    /// # Arguments
+    ///
+    /// * `result` - The result of the probe.
+    /// * `elapsed` - The time since the first probe.
+    /// * `time_left` - The time left until the deadline.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(Ok(()))` - If the firmware answered with valid encoder values.
+    /// * `Some(Err(HwSerialConnectionError))` - An error if the deadline passed or the connection was lost.
+    /// * `None` - If another probe has to be sent.
+    pub(crate) fn handle_result(
+        &self,
+        result: Result<SerialResponse, HwSerialConnectionError>,
+        elapsed: Duration,
+        time_left: Duration,
+    ) -> Option<Result<(), HwSerialConnectionError>> {
+        match result {
+            Ok(SerialResponse::EncoderValues { .. }) => {
+                log::debug!(
+                    "Firmware ready after {} probes ({} ms)",
+                    self.attempts,
+                    elapsed.as_millis()
+                );
+                return Some(Ok(()));
+            }
+            Ok(response) => log::trace!("Unexpected response to readiness probe: {:?}", response),
+            Err(error) if error.is_connection_lost() => return Some(Err(error)),
+            Err(error) => log::trace!("Readiness probe failed: {}", error),
+        }
+        if time_left.is_zero() {
+            return Some(Err(HwSerialConnectionError::NotReadyError {
+                error: format!(
+                    "No valid response to {} encoder reads within {} ms",
+                    self.attempts, self.ready_timeout
+                ),
+            }));
+        }
+        None
+    }
 }
 
 /// Enum representing the response from the serial connection.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SerialResponse {
     /// Response containing the encoder values.
     EncoderValues { left: i64, right: i64 },
+    /// Response containing the value of an analog pin.
+    AnalogValue { value: u16 },
+    /// Response containing the value of a digital pin.
+    DigitalValue { value: bool },
+    /// Response containing the version and the capabilities of the firmware.
+    FirmwareInfo(FirmwareInfo),
+    /// Response containing the PID values of the motor controller.
+    PIDValues { kp: f32, ki: f32, kd: f32, ko: f32 },
+    /// Response acknowledging the command.
+    Ok,
     /// Response containing a message
     Other { message: String },
 }
 
 /// Abstracts the serial connection to the underlying hardware.
 /// This struct is used to send commands to the hardware and receive responses.
-/// It uses the `serialport` crate to handle the serial communication.
+/// The bytes are carried by a [`Transport`], by default a serial port opened with the `serialport` crate.
+///
+/// Responses are framed on the firmware line terminator, so they may arrive split across
+/// several reads, and any stale input is discarded before a new command is sent.
+/// The [`Protocol`] is ASCII unless set otherwise with [`HwSerialConnection::set_protocol`].
 #[derive(Debug)]
 pub struct HwSerialConnection {
-    serial_port: Box<dyn serialport::SerialPort>,
+    /// The transport carrying the bytes.
+    transport: Box<dyn Transport>,
+    /// Bytes received but not consumed yet.
+    read_buffer: Vec<u8>,
+    /// The time to wait for a complete response in milliseconds.
+    timeout: u64,
+    /// Records the exchanges, if set.
+    recorder: Option<SerialRecorder>,
+    /// How each kind of command is retried. Commands without a policy are not retried.
+    retry_policies: HashMap<SerialCommandKind, RetryPolicy>,
+    /// The wire protocol.
+    protocol: Protocol,
+    /// The sequence number of the last command sent.
+    sequence: u8,
+    /// Statistics of the commands sent and of the bytes transferred.
+    stats: CommStats,
 }
 
 impl HwSerialConnection {
@@ -70,22 +560,79 @@ impl HwSerialConnection {
     /// * `Err(HwSerialConnectionError)` - An error if the connection fails.
     ///
     pub fn new(serial_device: impl AsRef<str>, baud_rate: u32, timeout: u64) -> Result<Self, HwSerialConnectionError> {
-        let serial_port = serialport::new(serial_device.as_ref(), baud_rate)
-            // Set the serial port parameters
-            .parity(serialport::Parity::None)
-            .stop_bits(serialport::StopBits::One)
-            .data_bits(serialport::DataBits::Eight)
-            .flow_control(serialport::FlowControl::None)
-            .timeout(std::time::Duration::from_millis(timeout))
-            // Open the serial port
-            .open()
-            .map_err(|e| HwSerialConnectionError::SerialPortConnectionError { error: e.to_string() })?;
-        log::trace!("Serial port opened: {}", serial_device.as_ref());
-        Ok(HwSerialConnection { serial_port })
+        // Keep the reads short so the readiness probes are not held by the serial port timeout.
+        let serial_port = transport::open_serial_port(serial_device, baud_rate, timeout.min(SERIAL_PORT_READ_TIMEOUT))?;
+        Ok(HwSerialConnection::from_transport(serial_port, timeout))
+    }
+
+    /// Creates a new instance of `HwSerialConnection` over an already opened transport.
+    ///
+    /// # Arguments
+    ///
+    /// * `transport` - The transport used to exchange bytes with the hardware.
+    /// * `timeout` - The time to wait for a complete response in milliseconds.
+    ///
+    /// # Returns
+    ///
+    /// * `HwSerialConnection` - A new instance of `HwSerialConnection`.
+    pub fn from_transport(transport: impl Transport + 'static, timeout: u64) -> Self {
+        HwSerialConnection {
+            transport: Box::new(transport),
+            read_buffer: Vec::new(),
+            timeout,
+            recorder: None,
+            retry_policies: HashMap::new(),
+            protocol: Protocol::default(),
+            sequence: 0,
+            stats: CommStats::default(),
+        }
+    }
+
+    /// Sets the wire protocol, which has to match the one of the firmware.
+    ///
+    /// # Arguments
+    ///
+    /// * `protocol` - The protocol.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Sets how a kind of command is retried after a transient error.
+    ///
+    /// # Arguments
+    ///
+    /// * `command_kind` - The kind of command the policy applies to.
+    /// * `retry_policy` - The policy.
+    pub fn set_retry_policy(&mut self, command_kind: SerialCommandKind, retry_policy: RetryPolicy) {
+        self.retry_policies.insert(command_kind, retry_policy);
+    }
+
+    /// Records every exchange from now on, including the readiness probes.
+    ///
+    /// # Arguments
+    ///
+    /// * `recorder` - The recorder the exchanges are written to.
+    pub fn set_recorder(&mut self, recorder: SerialRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Gets the statistics of the communication since the connection was created or the statistics were taken.
+    ///
+    /// The readiness probes of [`HwSerialConnection::wait_until_ready`] only count towards the bytes transferred.
+    pub fn stats(&self) -> &CommStats {
+        &self.stats
+    }
+
+    /// Takes the statistics of the communication, starting over from zero.
+    pub fn take_stats(&mut self) -> CommStats {
+        std::mem::take(&mut self.stats)
     }
 
     /// Sends a command to the serial connection and returns the raw response.
     ///
+    /// Transient errors are retried according to the retry policy of the kind of command.
+    /// Every attempt is accounted in the [`HwSerialConnection::stats`].
+    ///
     /// # Arguments
     ///
     /// * `command` - The command to send to the serial connection.
@@ -96,17 +643,272 @@ impl HwSerialConnection {
     /// * `Err(HwSerialConnectionError)` - An error if the command fails.
     ///
     pub fn send_command(&mut self, command: SerialCommands) -> Result<SerialResponse, HwSerialConnectionError> {
+        let mut responses = self.send_commands(std::slice::from_ref(&command))?;
+        Ok(responses.remove(0))
+    }
+
+    /// Sends several commands back to back and returns their responses, in order.
+    ///
+    /// The commands are written at once and the responses read afterwards, so the link carries them in a
+    /// single round trip instead of one per command. The firmware handles them one after the other.
+    ///
+    /// When a command fails with a transient error, all the commands are sent again according to the retry
+    /// policy of the kind of command that failed, so only commands that can be repeated should be sent together.
+    ///
+    /// # Arguments
+    ///
+    /// * `commands` - The commands to send.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<SerialResponse>)` - The response to each command.
+    /// * `Err(HwSerialConnectionError)` - The error of the first command that fails.
+    pub fn send_commands(
+        &mut self,
+        commands: &[SerialCommands],
+    ) -> Result<Vec<SerialResponse>, HwSerialConnectionError> {
+        let mut retries = 0;
+        loop {
+            let results = self.exchange_all(commands, Duration::from_millis(self.timeout));
+            for (command, (result, latency)) in commands.iter().zip(&results) {
+                self.stats.record_exchange(command.kind(), result, *latency);
+            }
+            // The exchange stops at the first failure, which is the last result.
+            let failed_command = match results.last() {
+                Some((Err(_), _)) => Some(&commands[results.len() - 1]),
+                _ => None,
+            };
+            let responses = results
+                .into_iter()
+                .map(|(result, _)| result)
+                .collect::<Result<Vec<_>, _>>();
+            let (Err(error), Some(failed_command)) = (&responses, failed_command) else {
+                return responses;
+            };
+            let retry_policy = self
+                .retry_policies
+                .get(&failed_command.kind())
+                .cloned()
+                .unwrap_or_default();
+            if !retry_policy.allows_retry(error, retries) {
+                return responses;
+            }
+            retries += 1;
+            self.stats.record_retry(failed_command.kind());
+            log::debug!(
+                "Retrying {:?} ({}/{}) after: {}",
+                commands,
+                retries,
+                retry_policy.max_retries,
+                error
+            );
+            std::thread::sleep(Duration::from_millis(retry_policy.retry_delay));
+        }
+    }
+
+    /// Waits until the firmware answers, probing it by reading the encoders.
+    ///
+    /// The board resets when the serial port is opened and ignores commands until it boots,
+    /// so this should be called before sending any other command.
+    ///
+    /// # Arguments
+    ///
+    /// * `ready_timeout` - The maximum time to wait for the firmware in milliseconds.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the firmware answered with valid encoder values.
+    /// * `Err(HwSerialConnectionError)` - An error if the firmware did not answer in time
+    ///   or the connection failed.
+    pub fn wait_until_ready(&mut self, ready_timeout: u64) -> Result<(), HwSerialConnectionError> {
+        let mut probes = ReadinessProbes::new(ready_timeout);
+        let start = Instant::now();
+        let deadline = start + probes.ready_timeout();
+        loop {
+            let probe_timeout = probes.start_probe(deadline.saturating_duration_since(Instant::now()));
+            let result = self.exchange(&SerialCommands::ReadEncoderValues, probe_timeout);
+            if let Some(outcome) = probes.handle_result(
+                result,
+                start.elapsed(),
+                deadline.saturating_duration_since(Instant::now()),
+            ) {
+                return outcome;
+            }
+        }
+    }
+
+    /// Sends a command without waiting for its response, e.g. to stop the motors when shutting down.
+    ///
+    /// The response is discarded along with the stale input of the next exchange, and the command is not retried.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the command was written.
+    /// * `Err(HwSerialConnectionError)` - An error if the command cannot be written.
+    pub fn post_command(&mut self, command: SerialCommands) -> Result<(), HwSerialConnectionError> {
         let command_str = HwSerialConnection::prepare_command_to_send(&command);
-        log::trace!("Sending command: {}", command_str);
+        self.sequence = self.sequence.wrapping_add(1);
+        let command_bytes = self.protocol.encode_command(self.sequence, &command_str);
+        self.write_commands(&command_bytes)?;
+        self.transport.flush()?;
+        Ok(())
+    }
+
+    /// Sends a command and waits for its response up to the given timeout.
+    fn exchange(
+        &mut self,
+        command: &SerialCommands,
+        timeout: Duration,
+    ) -> Result<SerialResponse, HwSerialConnectionError> {
+        let (result, _) = self.exchange_all(std::slice::from_ref(command), timeout).remove(0);
+        result
+    }
+
+    /// Writes the commands at once and waits for each response, in order, up to the given timeout.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<(Result<SerialResponse, HwSerialConnectionError>, Duration)>` - The result of each command and the time
+    ///   from the write to its end. It stops at the first command that fails, as the responses that follow cannot be
+    ///   told apart from the ones to the failed command.
+    fn exchange_all(
+        &mut self,
+        commands: &[SerialCommands],
+        timeout: Duration,
+    ) -> Vec<(Result<SerialResponse, HwSerialConnectionError>, Duration)> {
+        let encoded_commands = commands
+            .iter()
+            .map(|command| {
+                let command_str = HwSerialConnection::prepare_command_to_send(command);
+                self.sequence = self.sequence.wrapping_add(1);
+                (self.sequence, self.protocol.encode_command(self.sequence, &command_str))
+            })
+            .collect::<Vec<(u8, Vec<u8>)>>();
+        let start = Instant::now();
+        let commands_bytes = encoded_commands
+            .iter()
+            .flat_map(|(_, command_bytes)| command_bytes.iter().copied())
+            .collect::<Vec<u8>>();
+        let mut write_result = self.write_commands(&commands_bytes);
+        let mut results = Vec::with_capacity(commands.len());
+        for (command, (sequence, command_bytes)) in commands.iter().zip(&encoded_commands) {
+            let result = std::mem::replace(&mut write_result, Ok(())).and_then(|_| {
+                log::trace!("Reading response from serial port");
+                let response_str = self.read_response(*sequence, timeout)?;
+                log::trace!("Received response: {}", response_str);
+                HwSerialConnection::parse_response(command, response_str)
+            });
+            if let Some(recorder) = &self.recorder {
+                recorder.record(command_bytes, &result);
+            }
+            let failed = result.is_err();
+            results.push((result, start.elapsed()));
+            if failed {
+                break;
+            }
+        }
+        results
+    }
+
+    /// Writes encoded commands, once the input of previous exchanges is discarded.
+    fn write_commands(&mut self, commands_bytes: &[u8]) -> Result<(), HwSerialConnectionError> {
+        // Make sure the next frame read is the response to these commands.
+        self.discard_stale_input()?;
+        log::trace!(
+            "Sending command: {}",
+            String::from_utf8_lossy(commands_bytes).escape_debug()
+        );
         // Send the command to the serial port
-        self.serial_port.write_all(command_str.as_bytes())?;
+        self.transport.write_all(commands_bytes)?;
+        self.stats.bytes_sent += commands_bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Discards the bytes received so far, which belong to previous exchanges.
+    fn discard_stale_input(&mut self) -> Result<(), HwSerialConnectionError> {
+        if !self.read_buffer.is_empty() {
+            log::trace!(
+                "Discarding stale input: {}",
+                String::from_utf8_lossy(&self.read_buffer).escape_debug()
+            );
+            self.read_buffer.clear();
+        }
+        self.transport.clear_input_buffer()?;
+        Ok(())
+    }
+
+    /// Reads from the transport until a complete response frame is available.
+    ///
+    /// # Arguments
+    ///
+    /// * `sequence` - The sequence number of the command waiting for the response.
+    /// * `timeout` - The maximum time to wait for the complete response.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(String)` - The response, without its line terminator.
+    /// * `Err(HwSerialConnectionError)` - An error if the frame is malformed, the timeout expires
+    ///   or the transport fails.
+    fn read_response(&mut self, sequence: u8, timeout: Duration) -> Result<String, HwSerialConnectionError> {
+        let deadline = Instant::now() + timeout;
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            if let Some(response) = self.protocol.take_response(sequence, &mut self.read_buffer)? {
+                return Ok(response);
+            }
+            if Instant::now() >= deadline {
+                return Err(HwSerialConnectionError::TimeoutError {
+                    error: format!(
+                        "No complete response after {} ms, received: {:?}",
+                        timeout.as_millis(),
+                        String::from_utf8_lossy(&self.read_buffer)
+                    ),
+                    received: self.read_buffer.clone(),
+                });
+            }
+            match self.transport.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(HwSerialConnectionError::DisconnectedError {
+                        error: "Connection closed by the device".to_string(),
+                    });
+                }
+                Ok(n) => {
+                    self.stats.bytes_received += n as u64;
+                    if let Some(recorder) = &self.recorder {
+                        recorder.capture(&chunk[..n]);
+                    }
+                    self.read_buffer.extend_from_slice(&chunk[..n]);
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
 
-        let mut response_buffer = vec![0; 32];
-        log::trace!("Reading response from serial port");
-        let n = self.serial_port.read(&mut response_buffer)?;
-        let response_str = String::from_utf8_lossy(&response_buffer[..n]).to_string();
-        log::trace!("Received response: {}", response_str);
-        HwSerialConnection::parse_response(&command, response_str)
+    /// Decodes a response frame, stripping its line terminator.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The bytes of the frame, including the terminator.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(String)` - The response carried by the frame.
+    /// * `Err(HwSerialConnectionError)` - An error if the frame is not valid UTF-8.
+    pub(crate) fn decode_frame(frame: &[u8]) -> Result<String, HwSerialConnectionError> {
+        let response = std::str::from_utf8(frame).map_err(|e| HwSerialConnectionError::MalformedResponseError {
+            error: format!("{}: {:?}", e, String::from_utf8_lossy(frame)),
+            raw: frame.to_vec(),
+        })?;
+        Ok(response.trim_end_matches(['\r', '\n']).to_string())
     }
 
     /// Prepares the command to be sent to the serial connection.
@@ -123,6 +925,14 @@ impl HwSerialConnection {
             SerialCommands::ReadEncoderValues => "e".to_string(),
             SerialCommands::SetMotorValues { left, right } => format!("m {} {}", left, right),
             SerialCommands::SetPIDValues { kp, ki, kd, ko } => format!("u {}:{}:{}:{}", kp, ki, kd, ko),
+            SerialCommands::ResetEncoders => "r".to_string(),
+            SerialCommands::SetMotorPWMValues { left, right } => format!("o {} {}", left, right),
+            SerialCommands::ReadAnalogPin { pin } => format!("a {}", pin),
+            SerialCommands::ReadDigitalPin { pin } => format!("d {}", pin),
+            SerialCommands::WriteAnalogPin { pin, value } => format!("x {} {}", pin, value),
+            SerialCommands::WriteDigitalPin { pin, value } => format!("w {} {}", pin, u8::from(*value)),
+            SerialCommands::ReadFirmwareInfo => "v".to_string(),
+            SerialCommands::ReadPIDValues => "i".to_string(),
         }
         // Add carriage return to the message.
         + "\r";
@@ -147,31 +957,91 @@ impl HwSerialConnection {
     ) -> Result<SerialResponse, HwSerialConnectionError> {
         // Verify the response is not empty
         if response.is_empty() {
-            return Err(HwSerialConnectionError::WrongResponseError {
+            return Err(HwSerialConnectionError::MalformedResponseError {
                 error: "Empty response from serial port".to_string(),
+                raw: Vec::new(),
             });
         }
         use itertools::Itertools;
+        let malformed = |error: String| HwSerialConnectionError::MalformedResponseError {
+            error,
+            raw: response.as_bytes().to_vec(),
+        };
         match command {
             SerialCommands::ReadEncoderValues => {
                 let splitted_response: Option<(&str, &str)> = response.split_whitespace().collect_tuple();
                 let values = if let Some(values) = splitted_response {
                     values
                 } else {
-                    return Err(HwSerialConnectionError::WrongResponseError {
-                        error: "Invalid response format for encoder values: ".to_string() + response.as_str(),
-                    });
+                    return Err(malformed(
+                        "Invalid response format for encoder values: ".to_string() + response.as_str(),
+                    ));
                 };
-                let left = values
-                    .0
-                    .parse::<i64>()
-                    .map_err(|e| HwSerialConnectionError::WrongResponseError { error: e.to_string() })?;
-                let right = values
-                    .1
-                    .parse::<i64>()
-                    .map_err(|e| HwSerialConnectionError::WrongResponseError { error: e.to_string() })?;
+                let left = values.0.parse::<i64>().map_err(|e| malformed(e.to_string()))?;
+                let right = values.1.parse::<i64>().map_err(|e| malformed(e.to_string()))?;
                 Ok(SerialResponse::EncoderValues { left, right })
             }
+            SerialCommands::ReadAnalogPin { .. } => {
+                let value = response
+                    .trim()
+                    .parse::<u16>()
+                    .map_err(|e| malformed(format!("Invalid response format for analog value: {}: {}", response, e)))?;
+                Ok(SerialResponse::AnalogValue { value })
+            }
+            SerialCommands::ReadDigitalPin { .. } => match response.trim() {
+                "0" => Ok(SerialResponse::DigitalValue { value: false }),
+                "1" => Ok(SerialResponse::DigitalValue { value: true }),
+                _ => Err(malformed(
+                    "Invalid response format for digital value: ".to_string() + response.as_str(),
+                )),
+            },
+            // Firmware that predates the query does not know it.
+            SerialCommands::ReadFirmwareInfo if response == INVALID_COMMAND_RESPONSE => {
+                Ok(SerialResponse::Other { message: response })
+            }
+            SerialCommands::ReadFirmwareInfo => {
+                let mut fields = response.split_whitespace();
+                let (Some(version), Some(commands)) = (fields.next(), fields.next()) else {
+                    return Err(malformed(
+                        "Invalid response format for firmware info: ".to_string() + response.as_str(),
+                    ));
+                };
+                let encoder_ticks_per_revolution = fields
+                    .next()
+                    .map(|ticks| ticks.parse::<u64>())
+                    .transpose()
+                    .map_err(|e| malformed(format!("Invalid encoder ticks per revolution: {}: {}", response, e)))?;
+                if fields.next().is_some() {
+                    return Err(malformed(
+                        "Invalid response format for firmware info: ".to_string() + response.as_str(),
+                    ));
+                }
+                Ok(SerialResponse::FirmwareInfo(FirmwareInfo {
+                    version: Some(version.to_string()),
+                    supported_commands: commands.chars().filter_map(SerialCommandKind::from_letter).collect(),
+                    encoder_ticks_per_revolution,
+                }))
+            }
+            SerialCommands::ReadPIDValues => {
+                let values = response
+                    .trim()
+                    .split(':')
+                    .map(|value| value.parse::<f32>())
+                    .collect::<Result<Vec<f32>, _>>()
+                    .map_err(|e| malformed(format!("Invalid response format for PID values: {}: {}", response, e)))?;
+                let [kp, ki, kd, ko] = values[..] else {
+                    return Err(malformed(
+                        "Invalid response format for PID values: ".to_string() + response.as_str(),
+                    ));
+                };
+                Ok(SerialResponse::PIDValues { kp, ki, kd, ko })
+            }
+            _ if response == OK_RESPONSE => Ok(SerialResponse::Ok),
+            // The gains must be acknowledged, otherwise the firmware did not take them.
+            SerialCommands::SetPIDValues { .. } => Err(HwSerialConnectionError::UnexpectedResponseError {
+                error: format!("PID values not acknowledged: {}", response),
+                response: SerialResponse::Other { message: response },
+            }),
             _ => Ok(SerialResponse::Other { message: response }),
         }
     }
@@ -179,10 +1049,17 @@ impl HwSerialConnection {
 
 #[cfg(test)]
 mod tests {
+    use super::FirmwareInfo;
     use super::HwSerialConnection;
     use super::HwSerialConnectionError;
+    use super::MAX_RESPONSE_LENGTH;
+    use super::Protocol;
+    use super::RetryPolicy;
+    use super::SerialCommandKind;
     use super::SerialCommands;
     use super::SerialResponse;
+    use super::transport::MemoryPipe;
+    use std::io::{Read, Write};
 
     #[test]
     fn test_prepare_command_to_send_read_encoders() {
@@ -210,6 +1087,56 @@ mod tests {
         assert_eq!(command_str, "u 1:2:3:4\r");
     }
 
+    #[test]
+    fn test_prepare_command_to_send_reset_encoders() {
+        let command = SerialCommands::ResetEncoders;
+        let command_str = HwSerialConnection::prepare_command_to_send(&command);
+        assert_eq!(command_str, "r\r");
+    }
+
+    #[test]
+    fn test_prepare_command_to_send_set_motor_pwm_values() {
+        let command = SerialCommands::SetMotorPWMValues { left: -255, right: 128 };
+        let command_str = HwSerialConnection::prepare_command_to_send(&command);
+        assert_eq!(command_str, "o -255 128\r");
+    }
+
+    #[test]
+    fn test_prepare_command_to_send_pin_io() {
+        let command_str = HwSerialConnection::prepare_command_to_send(&SerialCommands::ReadAnalogPin { pin: 3 });
+        assert_eq!(command_str, "a 3\r");
+        let command_str = HwSerialConnection::prepare_command_to_send(&SerialCommands::ReadDigitalPin { pin: 2 });
+        assert_eq!(command_str, "d 2\r");
+        let command_str =
+            HwSerialConnection::prepare_command_to_send(&SerialCommands::WriteAnalogPin { pin: 5, value: 128 });
+        assert_eq!(command_str, "x 5 128\r");
+        let command_str =
+            HwSerialConnection::prepare_command_to_send(&SerialCommands::WriteDigitalPin { pin: 13, value: true });
+        assert_eq!(command_str, "w 13 1\r");
+    }
+
+    #[test]
+    fn test_parse_response_pin_values() {
+        let parsed_response =
+            HwSerialConnection::parse_response(&SerialCommands::ReadAnalogPin { pin: 0 }, "512".to_string()).unwrap();
+        assert!(matches!(parsed_response, SerialResponse::AnalogValue { value: 512 }));
+        let parsed_response =
+            HwSerialConnection::parse_response(&SerialCommands::ReadDigitalPin { pin: 2 }, "1".to_string()).unwrap();
+        assert!(matches!(parsed_response, SerialResponse::DigitalValue { value: true }));
+        let parsed_response =
+            HwSerialConnection::parse_response(&SerialCommands::ReadAnalogPin { pin: 0 }, "high".to_string());
+        assert!(matches!(
+            parsed_response,
+            Err(HwSerialConnectionError::MalformedResponseError { .. })
+        ));
+        let parsed_response =
+            HwSerialConnection::parse_response(&SerialCommands::ReadDigitalPin { pin: 2 }, "2".to_string());
+        assert!(matches!(
+            parsed_response,
+            Err(HwSerialConnectionError::MalformedResponseError { .. })
+        ));
+    }
+
     #[test]
     fn test_parse_response_encoders() {
         let response = "123 456".to_string();
@@ -231,11 +1158,452 @@ mod tests {
         assert!(parsed_response.is_err());
         assert_eq!(
             parsed_response.unwrap_err(),
-            HwSerialConnectionError::WrongResponseError {
-                error: "Invalid response format for encoder values: ".to_string() + response.as_str()
+            HwSerialConnectionError::MalformedResponseError {
+                error: "Invalid response format for encoder values: ".to_string() + response.as_str(),
+                raw: response.into_bytes(),
+            }
+        );
+    }
+    #[test]
+    fn test_decode_frame() {
+        assert_eq!(HwSerialConnection::decode_frame(b"123 456\r\n").unwrap(), "123 456");
+        assert_eq!(HwSerialConnection::decode_frame(b"OK\n").unwrap(), "OK");
+        assert!(matches!(
+            HwSerialConnection::decode_frame(b"\xff\xfe\r\n"),
+            Err(HwSerialConnectionError::MalformedResponseError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_take_response() {
+        let mut read_buffer = b"12 -3".to_vec();
+        assert_eq!(Protocol::Ascii.take_response(0, &mut read_buffer).unwrap(), None);
+        read_buffer.extend_from_slice(b"4\r\nOK\r\n");
+        assert_eq!(
+            Protocol::Ascii.take_response(0, &mut read_buffer).unwrap(),
+            Some("12 -34".to_string())
+        );
+        assert_eq!(read_buffer, b"OK\r\n");
+        let mut read_buffer = vec![b'1'; MAX_RESPONSE_LENGTH + 1];
+        assert!(matches!(
+            Protocol::Ascii.take_response(0, &mut read_buffer),
+            Err(HwSerialConnectionError::MalformedResponseError { .. })
+        ));
+        assert!(read_buffer.is_empty());
+    }
+
+    #[test]
+    fn test_send_command_split_response() {
+        let (connection_end, mut device_end) = MemoryPipe::pair(100);
+        let mut connection = HwSerialConnection::from_transport(connection_end, 1000);
+        let device = std::thread::spawn(move || {
+            // Wait for the "e\r" command.
+            let mut command = [0; 2];
+            device_end.read_exact(&mut command).unwrap();
+            device_end.write_all(b"12").unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            device_end.write_all(b"3 45").unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            device_end.write_all(b"6\r\n").unwrap();
+        });
+        let response = connection.send_command(SerialCommands::ReadEncoderValues).unwrap();
+        device.join().unwrap();
+        assert!(matches!(
+            response,
+            SerialResponse::EncoderValues { left: 123, right: 456 }
+        ));
+    }
+
+    #[test]
+    fn test_send_commands_pipelined() {
+        let (connection_end, mut device_end) = MemoryPipe::pair(100);
+        let mut connection = HwSerialConnection::from_transport(connection_end, 1000);
+        connection.set_retry_policy(
+            SerialCommandKind::ReadEncoderValues,
+            RetryPolicy {
+                max_retries: 1,
+                retry_delay: 0,
+            },
+        );
+        let device = std::thread::spawn(move || {
+            // Both commands arrive before any response is sent. The garbled encoder read makes both be sent again.
+            for responses in [b"OK\r\n12 x\r\n".as_slice(), b"OK\r\n12 34\r\n".as_slice()] {
+                let mut commands = [0; 8];
+                device_end.read_exact(&mut commands).unwrap();
+                assert_eq!(&commands, b"m 1 2\re\r");
+                device_end.write_all(responses).unwrap();
+            }
+        });
+        let responses = connection
+            .send_commands(&[
+                SerialCommands::SetMotorValues { left: 1, right: 2 },
+                SerialCommands::ReadEncoderValues,
+            ])
+            .unwrap();
+        device.join().unwrap();
+        assert_eq!(
+            responses,
+            vec![
+                SerialResponse::Ok,
+                SerialResponse::EncoderValues { left: 12, right: 34 }
+            ]
+        );
+        let stats = connection.stats();
+        assert_eq!(stats.commands[&SerialCommandKind::SetMotorValues].successes, 2);
+        assert_eq!(stats.commands[&SerialCommandKind::ReadEncoderValues].parse_errors, 1);
+        assert_eq!(stats.commands[&SerialCommandKind::ReadEncoderValues].retries, 1);
+        assert_eq!(connection.send_commands(&[]).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_send_command_discards_stale_input() {
+        let (connection_end, mut device_end) = MemoryPipe::pair(100);
+        let mut connection = HwSerialConnection::from_transport(connection_end, 1000);
+        // Leftovers of a previous exchange.
+        device_end.write_all(b"OK\r\n1 2\r").unwrap();
+        let device = std::thread::spawn(move || {
+            // Wait for the "e\r" command.
+            let mut command = [0; 2];
+            device_end.read_exact(&mut command).unwrap();
+            device_end.write_all(b"123 456\r\n").unwrap();
+        });
+        let response = connection.send_command(SerialCommands::ReadEncoderValues).unwrap();
+        device.join().unwrap();
+        assert!(matches!(
+            response,
+            SerialResponse::EncoderValues { left: 123, right: 456 }
+        ));
+    }
+
+    #[test]
+    fn test_send_command_binary_protocol() {
+        use super::{Protocol, binary};
+
+        let (connection_end, mut device_end) = MemoryPipe::pair(100);
+        let mut connection = HwSerialConnection::from_transport(connection_end, 1000);
+        connection.set_protocol(Protocol::Binary);
+        let device = std::thread::spawn(move || {
+            let read_packet = |device_end: &mut MemoryPipe| {
+                let mut frame = Vec::new();
+                while frame.last() != Some(&binary::FRAME_DELIMITER) {
+                    let mut byte = [0; 1];
+                    device_end.read_exact(&mut byte).unwrap();
+                    frame.push(byte[0]);
+                }
+                binary::decode_packet(&frame).unwrap()
+            };
+            let (sequence, payload) = read_packet(&mut device_end);
+            assert_eq!(payload, b"e");
+            // A late response to an earlier command comes first.
+            let mut responses = binary::encode_packet(sequence.wrapping_sub(1), b"OK");
+            responses.extend(binary::encode_packet(sequence, b"123 456"));
+            device_end.write_all(&responses).unwrap();
+
+            let (sequence, payload) = read_packet(&mut device_end);
+            assert_eq!(payload, b"e");
+            let mut response = binary::encode_packet(sequence, b"123 456");
+            // A flipped bit turns 1 into 0.
+            response[2] ^= 0x01;
+            device_end.write_all(&response).unwrap();
+        });
+        assert_eq!(
+            connection.send_command(SerialCommands::ReadEncoderValues).unwrap(),
+            SerialResponse::EncoderValues { left: 123, right: 456 }
+        );
+        assert!(matches!(
+            connection.send_command(SerialCommands::ReadEncoderValues),
+            Err(HwSerialConnectionError::MalformedResponseError { .. })
+        ));
+        device.join().unwrap();
+    }
+
+    #[test]
+    fn test_parse_protocol() {
+        use super::Protocol;
+
+        assert_eq!("ascii".parse::<Protocol>().unwrap(), Protocol::Ascii);
+        assert_eq!("binary".parse::<Protocol>().unwrap(), Protocol::Binary);
+        assert_eq!(Protocol::Binary.to_string(), "binary");
+        assert!("cobs".parse::<Protocol>().is_err());
+    }
+
+    #[test]
+    fn test_send_command_long_response() {
+        let (connection_end, mut device_end) = MemoryPipe::pair(100);
+        let mut connection = HwSerialConnection::from_transport(connection_end, 1000);
+        let long_response = format!("{} {}\r\n", i64::MAX, i64::MIN);
+        assert!(long_response.len() > 32);
+        let device = std::thread::spawn(move || {
+            // Wait for the "e\r" command.
+            let mut command = [0; 2];
+            device_end.read_exact(&mut command).unwrap();
+            device_end.write_all(long_response.as_bytes()).unwrap();
+        });
+        let response = connection.send_command(SerialCommands::ReadEncoderValues).unwrap();
+        device.join().unwrap();
+        assert!(matches!(
+            response,
+            SerialResponse::EncoderValues {
+                left: i64::MAX,
+                right: i64::MIN
+            }
+        ));
+    }
+
+    #[test]
+    fn test_send_command_timeout() {
+        let (connection_end, mut device_end) = MemoryPipe::pair(10);
+        let mut connection = HwSerialConnection::from_transport(connection_end, 50);
+        // An incomplete response never gets its terminator.
+        device_end.write_all(b"123").unwrap();
+        let device = std::thread::spawn(move || {
+            // Wait for the "e\r" command.
+            let mut command = [0; 2];
+            device_end.read_exact(&mut command).unwrap();
+            device_end.write_all(b"123 4").unwrap();
+            device_end
+        });
+        let response = connection.send_command(SerialCommands::ReadEncoderValues);
+        device.join().unwrap();
+        assert_eq!(
+            response.unwrap_err(),
+            HwSerialConnectionError::TimeoutError {
+                error: "No complete response after 50 ms, received: \"123 4\"".to_string(),
+                received: b"123 4".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_send_command_retries_transient_errors() {
+        let (connection_end, mut device_end) = MemoryPipe::pair(100);
+        let mut connection = HwSerialConnection::from_transport(connection_end, 1000);
+        connection.set_retry_policy(
+            SerialCommandKind::ReadEncoderValues,
+            RetryPolicy {
+                max_retries: 2,
+                retry_delay: 0,
+            },
+        );
+        let device = std::thread::spawn(move || {
+            // Garbled responses to the first two reads, and a valid one to the third.
+            for response in [b"12 x\r\n".as_slice(), b"\xff\r\n".as_slice(), b"12 34\r\n".as_slice()] {
+                let mut command = [0; 2];
+                device_end.read_exact(&mut command).unwrap();
+                device_end.write_all(response).unwrap();
+            }
+            device_end
+        });
+        let response = connection.send_command(SerialCommands::ReadEncoderValues).unwrap();
+        let mut device_end = device.join().unwrap();
+        assert_eq!(response, SerialResponse::EncoderValues { left: 12, right: 34 });
+
+        // Other kinds of commands are not retried.
+        let device = std::thread::spawn(move || {
+            let mut command = [0; 2];
+            device_end.read_exact(&mut command).unwrap();
+            device_end.write_all(b"x\r\n").unwrap();
+        });
+        let response = connection.send_command(SerialCommands::ReadDigitalPin { pin: 2 });
+        device.join().unwrap();
+        assert!(matches!(
+            response,
+            Err(HwSerialConnectionError::MalformedResponseError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_send_command_stats() {
+        let (connection_end, mut device_end) = MemoryPipe::pair(100);
+        let mut connection = HwSerialConnection::from_transport(connection_end, 1000);
+        connection.set_retry_policy(
+            SerialCommandKind::ReadEncoderValues,
+            RetryPolicy {
+                max_retries: 1,
+                retry_delay: 0,
+            },
+        );
+        let device = std::thread::spawn(move || {
+            for response in [b"12 x\r\n".as_slice(), b"12 34\r\n".as_slice()] {
+                let mut command = [0; 2];
+                device_end.read_exact(&mut command).unwrap();
+                device_end.write_all(response).unwrap();
             }
+        });
+        connection.send_command(SerialCommands::ReadEncoderValues).unwrap();
+        device.join().unwrap();
+
+        let stats = connection.take_stats();
+        let encoder_stats = &stats.commands[&SerialCommandKind::ReadEncoderValues];
+        assert_eq!(encoder_stats.exchanges, 2);
+        assert_eq!(encoder_stats.successes, 1);
+        assert_eq!(encoder_stats.parse_errors, 1);
+        assert_eq!(encoder_stats.retries, 1);
+        assert_eq!(encoder_stats.latency.count(), 2);
+        assert_eq!(stats.bytes_sent, 4);
+        assert_eq!(stats.bytes_received, 13);
+        assert_eq!(connection.stats(), &super::CommStats::default());
+    }
+
+    #[test]
+    fn test_send_command_retries_timeouts() {
+        let (connection_end, device_end) = MemoryPipe::pair(10);
+        let mut connection = HwSerialConnection::from_transport(connection_end, 50);
+        connection.set_retry_policy(
+            SerialCommandKind::ReadEncoderValues,
+            RetryPolicy {
+                max_retries: 5,
+                retry_delay: 0,
+            },
+        );
+        drop(device_end);
+        // Without answers the command times out after every retry.
+        let start = std::time::Instant::now();
+        let response = connection.send_command(SerialCommands::ReadEncoderValues);
+        assert!(matches!(response, Err(HwSerialConnectionError::TimeoutError { .. })));
+        assert!(start.elapsed() >= std::time::Duration::from_millis(300));
+        assert!(!response.unwrap_err().is_connection_lost());
+        assert!(
+            HwSerialConnectionError::from(std::io::Error::from(std::io::ErrorKind::BrokenPipe)).is_connection_lost()
         );
     }
+
+    #[test]
+    fn test_send_command_malformed_frame() {
+        let (connection_end, mut device_end) = MemoryPipe::pair(100);
+        let mut connection = HwSerialConnection::from_transport(connection_end, 1000);
+        let device = std::thread::spawn(move || {
+            // Wait for the "e\r" command.
+            let mut command = [0; 2];
+            device_end.read_exact(&mut command).unwrap();
+            device_end.write_all(&[b'1'; MAX_RESPONSE_LENGTH + 1]).unwrap();
+        });
+        let response = connection.send_command(SerialCommands::ReadEncoderValues);
+        device.join().unwrap();
+        assert!(matches!(
+            response,
+            Err(HwSerialConnectionError::MalformedResponseError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_response_ok() {
+        let response = "OK".to_string();
+        let command = SerialCommands::ResetEncoders;
+        let parsed_response = HwSerialConnection::parse_response(&command, response).unwrap();
+        assert!(matches!(parsed_response, SerialResponse::Ok));
+    }
+
+    #[test]
+    fn test_parse_response_pid_values() {
+        let command = SerialCommands::ReadPIDValues;
+        assert_eq!(
+            HwSerialConnection::parse_response(&command, "20.5:0:12:10".to_string()).unwrap(),
+            SerialResponse::PIDValues {
+                kp: 20.5,
+                ki: 0.0,
+                kd: 12.0,
+                ko: 10.0
+            }
+        );
+        for response in ["20:0:12", "20:0:12:10:1", "20:x:12:10"] {
+            assert!(matches!(
+                HwSerialConnection::parse_response(&command, response.to_string()),
+                Err(HwSerialConnectionError::MalformedResponseError { .. })
+            ));
+        }
+
+        // Setting the values must be acknowledged.
+        let command = SerialCommands::SetPIDValues {
+            kp: 20.0,
+            ki: 0.0,
+            kd: 12.0,
+            ko: 10.0,
+        };
+        assert_eq!(
+            HwSerialConnection::parse_response(&command, "OK".to_string()).unwrap(),
+            SerialResponse::Ok
+        );
+        assert!(matches!(
+            HwSerialConnection::parse_response(&command, "Invalid Command".to_string()),
+            Err(HwSerialConnectionError::UnexpectedResponseError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_wait_until_ready() {
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+
+        let config = EmulatorConfig {
+            boot_time: std::time::Duration::from_millis(300),
+            ..Default::default()
+        };
+        let mut connection = HwSerialConnection::from_transport(FirmwareEmulator::new(config), 1000);
+        let start = std::time::Instant::now();
+        connection.wait_until_ready(2000).unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(300));
+        assert!(start.elapsed() < std::time::Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_wait_until_ready_timeout() {
+        let (connection_end, _device_end) = MemoryPipe::pair(10);
+        let mut connection = HwSerialConnection::from_transport(connection_end, 1000);
+        let start = std::time::Instant::now();
+        let result = connection.wait_until_ready(250);
+        assert!(matches!(result, Err(HwSerialConnectionError::NotReadyError { .. })));
+        assert!(start.elapsed() < std::time::Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_parse_response_firmware_info() {
+        let command = SerialCommands::ReadFirmwareInfo;
+        assert_eq!(HwSerialConnection::prepare_command_to_send(&command), "v\r");
+        let parsed_response = HwSerialConnection::parse_response(&command, "1.2.0 emrzv 700".to_string()).unwrap();
+        assert_eq!(
+            parsed_response,
+            SerialResponse::FirmwareInfo(FirmwareInfo {
+                version: Some("1.2.0".to_string()),
+                supported_commands: vec![
+                    SerialCommandKind::ReadEncoderValues,
+                    SerialCommandKind::SetMotorValues,
+                    SerialCommandKind::ResetEncoders,
+                    SerialCommandKind::ReadFirmwareInfo,
+                ],
+                encoder_ticks_per_revolution: Some(700),
+            })
+        );
+        let parsed_response = HwSerialConnection::parse_response(&command, "1.2.0 em".to_string()).unwrap();
+        assert!(matches!(
+            parsed_response,
+            SerialResponse::FirmwareInfo(FirmwareInfo {
+                encoder_ticks_per_revolution: None,
+                ..
+            })
+        ));
+        let parsed_response = HwSerialConnection::parse_response(&command, "Invalid Command".to_string()).unwrap();
+        assert!(matches!(parsed_response, SerialResponse::Other { .. }));
+        assert!(matches!(
+            HwSerialConnection::parse_response(&command, "1.2.0 em many".to_string()),
+            Err(HwSerialConnectionError::MalformedResponseError { .. })
+        ));
+        assert!(matches!(
+            HwSerialConnection::parse_response(&command, "1.2.0".to_string()),
+            Err(HwSerialConnectionError::MalformedResponseError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_firmware_info() {
+        let legacy = FirmwareInfo::legacy();
+        assert!(legacy.supports(SerialCommandKind::SetMotorPWMValues));
+        assert!(!legacy.supports(SerialCommandKind::ReadFirmwareInfo));
+        assert_eq!(legacy.to_string(), "version unknown, commands 'emuroadxw'");
+        for kind in SerialCommandKind::ALL {
+            assert_eq!(SerialCommandKind::from_letter(kind.letter()), Some(kind));
+        }
+    }
+
     #[test]
     fn test_parse_response_other() {
         let response = "Random Msg".to_string();
@@ -249,3 +1617,83 @@ mod tests {
         }
     }
 }
+
+#[cfg(test)]
+mod property_tests {
+    use super::transport::ScriptedTransport;
+    use super::{HwSerialConnection, Protocol, SerialCommands, SerialResponse, binary};
+    use crate::core::emulator::FirmwareEmulator;
+    use proptest::prelude::*;
+
+    fn finite_f32() -> impl Strategy<Value = f32> {
+        any::<f32>().prop_filter("finite", |value| value.is_finite())
+    }
+
+    fn serial_command() -> impl Strategy<Value = SerialCommands> {
+        prop_oneof![
+            Just(SerialCommands::ReadEncoderValues),
+            (any::<i64>(), any::<i64>()).prop_map(|(left, right)| SerialCommands::SetMotorValues { left, right }),
+            (finite_f32(), finite_f32(), finite_f32(), finite_f32())
+                .prop_map(|(kp, ki, kd, ko)| SerialCommands::SetPIDValues { kp, ki, kd, ko }),
+            Just(SerialCommands::ResetEncoders),
+            (any::<i64>(), any::<i64>()).prop_map(|(left, right)| SerialCommands::SetMotorPWMValues { left, right }),
+            any::<u8>().prop_map(|pin| SerialCommands::ReadAnalogPin { pin }),
+            any::<u8>().prop_map(|pin| SerialCommands::ReadDigitalPin { pin }),
+            (any::<u8>(), any::<u8>()).prop_map(|(pin, value)| SerialCommands::WriteAnalogPin { pin, value }),
+            (any::<u8>(), any::<bool>()).prop_map(|(pin, value)| SerialCommands::WriteDigitalPin { pin, value }),
+            Just(SerialCommands::ReadFirmwareInfo),
+            Just(SerialCommands::ReadPIDValues),
+        ]
+    }
+
+    fn protocol() -> impl Strategy<Value = Protocol> {
+        prop_oneof![Just(Protocol::Ascii), Just(Protocol::Binary)]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_command_round_trip(command in serial_command()) {
+            let command_str = HwSerialConnection::prepare_command_to_send(&command);
+            let line = command_str.strip_suffix('\r').unwrap();
+            prop_assert_eq!(FirmwareEmulator::parse_command(line), Some(command.clone()));
+
+            let (_, payload) = binary::decode_packet(&Protocol::Binary.encode_command(1, &command_str)).unwrap();
+            prop_assert_eq!(payload, line.as_bytes());
+        }
+
+        #[test]
+        fn prop_parse_response_never_panics(command in serial_command(), response in any::<String>()) {
+            let _ = HwSerialConnection::parse_response(&command, response);
+        }
+
+        #[test]
+        fn prop_send_command_never_panics(
+            command in serial_command(),
+            protocol in protocol(),
+            received in proptest::collection::vec(any::<u8>(), 0..512),
+        ) {
+            let transport = ScriptedTransport::new(received);
+            let mut connection = HwSerialConnection::from_transport(transport, 1000);
+            connection.set_protocol(protocol);
+            let _ = connection.send_command(command);
+        }
+
+        #[test]
+        fn prop_encoder_response_variants(
+            left in any::<i64>(),
+            right in any::<i64>(),
+            leading in "[ \t]{0,3}",
+            separator in "[ \t]{1,3}",
+            trailing in "[ \t]{0,3}",
+            terminator in prop_oneof![Just("\n"), Just("\r\n"), Just("\r\r\n")],
+        ) {
+            let frame = format!("{}{}{}{}{}{}", leading, left, separator, right, trailing, terminator);
+            let transport = ScriptedTransport::new(frame.into_bytes());
+            let mut connection = HwSerialConnection::from_transport(transport, 1000);
+            prop_assert_eq!(
+                connection.send_command(SerialCommands::ReadEncoderValues).unwrap(),
+                SerialResponse::EncoderValues { left, right }
+            );
+        }
+    }
+}
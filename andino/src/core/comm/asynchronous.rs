@@ -0,0 +1,500 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Async counterpart of [`HwSerialConnection`], on tokio.
+//!
+//! [`AsyncHwSerialConnection`] speaks the same protocol and applies the same framing, retry policies
+//! and recording, but waits for the responses without blocking the thread.
+//!
+//! Its futures can be dropped at any await point, e.g. by `tokio::time::timeout` or `tokio::select!`,
+//! without desynchronizing the connection: the state of the exchange is kept in the connection, so
+//! the next command first completes the write of a cancelled one and discards its response.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+use super::{
+    HwSerialConnection, HwSerialConnectionError, Protocol, READ_CHUNK_SIZE, ReadinessProbes, RetryPolicy,
+    SerialCommandKind, SerialCommands, SerialRecorder, SerialResponse,
+};
+
+/// An async bidirectional byte stream connected to the firmware (or a stand-in for it).
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Unpin + Send + std::fmt::Debug {
+    /// Discards the bytes that were received but not read yet, if the transport supports it.
+    fn clear_input_buffer(&mut self) -> std::io::Result<()>;
+}
+
+/// Real serial ports and pseudo-terminals opened via the `tokio-serial` crate.
+impl AsyncTransport for tokio_serial::SerialStream {
+    fn clear_input_buffer(&mut self) -> std::io::Result<()> {
+        Ok(tokio_serial::SerialPort::clear(self, tokio_serial::ClearBuffer::Input)?)
+    }
+}
+
+/// In-memory pipes, e.g. to connect to a stand-in for the firmware running in another task.
+impl AsyncTransport for tokio::io::DuplexStream {
+    fn clear_input_buffer(&mut self) -> std::io::Result<()> {
+        // Bytes cannot be discarded without reading them, stale input is left to the framing.
+        Ok(())
+    }
+}
+
+/// Opens a serial port with the parameters expected by the Andino firmware, for async use.
+///
+/// It must be called from within a tokio runtime.
+///
+/// # Arguments
+///
+/// * `serial_device` - The name of the serial device to connect to. It can also be a pseudo-terminal.
+/// * `baud_rate` - The baud rate for the serial connection.
+///
+/// # Returns
+///
+/// * `Ok(tokio_serial::SerialStream)` - The opened serial port.
+/// * `Err(HwSerialConnectionError)` - An error if the port cannot be opened.
+pub fn open_async_serial_port(
+    serial_device: impl AsRef<str>,
+    baud_rate: u32,
+) -> Result<tokio_serial::SerialStream, HwSerialConnectionError> {
+    let serial_port = tokio_serial::new(serial_device.as_ref(), baud_rate)
+        .parity(tokio_serial::Parity::None)
+        .stop_bits(tokio_serial::StopBits::One)
+        .data_bits(tokio_serial::DataBits::Eight)
+        .flow_control(tokio_serial::FlowControl::None);
+    let serial_port = tokio_serial::SerialStream::open(&serial_port)
+        .map_err(|e| HwSerialConnectionError::SerialPortConnectionError { error: e.to_string() })?;
+    log::trace!("Serial port opened: {}", serial_device.as_ref());
+    Ok(serial_port)
+}
+
+/// Async serial connection to the underlying hardware.
+///
+/// See [`HwSerialConnection`] for the blocking version.
+#[derive(Debug)]
+pub struct AsyncHwSerialConnection {
+    /// The transport carrying the bytes.
+    transport: Box<dyn AsyncTransport>,
+    /// Bytes received but not consumed yet.
+    read_buffer: Vec<u8>,
+    /// Bytes of the last command not written yet, left by a cancelled exchange.
+    write_buffer: Vec<u8>,
+    /// Responses to cancelled exchanges that are still to be discarded.
+    outstanding_responses: usize,
+    /// The time to wait for a complete response in milliseconds.
+    timeout: u64,
+    /// Records the exchanges, if set.
+    recorder: Option<SerialRecorder>,
+    /// How each kind of command is retried. Commands without a policy are not retried.
+    retry_policies: HashMap<SerialCommandKind, RetryPolicy>,
+    /// The wire protocol.
+    protocol: Protocol,
+    /// The sequence number of the last command sent.
+    sequence: u8,
+}
+
+impl AsyncHwSerialConnection {
+    /// Creates a new instance of `AsyncHwSerialConnection` over a serial port.
+    ///
+    /// It must be called from within a tokio runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `serial_device` - The name of the serial device to connect to.
+    /// * `baud_rate` - The baud rate for the serial connection.
+    /// * `timeout` - The time to wait for a complete response in milliseconds.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(AsyncHwSerialConnection)` - A new instance of `AsyncHwSerialConnection`.
+    /// * `Err(HwSerialConnectionError)` - An error if the connection fails.
+    pub fn new(serial_device: impl AsRef<str>, baud_rate: u32, timeout: u64) -> Result<Self, HwSerialConnectionError> {
+        let serial_port = open_async_serial_port(serial_device, baud_rate)?;
+        Ok(AsyncHwSerialConnection::from_transport(serial_port, timeout))
+    }
+
+    /// Creates a new instance of `AsyncHwSerialConnection` over an already opened transport.
+    ///
+    /// # Arguments
+    ///
+    /// * `transport` - The transport used to exchange bytes with the hardware.
+    /// * `timeout` - The time to wait for a complete response in milliseconds.
+    pub fn from_transport(transport: impl AsyncTransport + 'static, timeout: u64) -> Self {
+        AsyncHwSerialConnection {
+            transport: Box::new(transport),
+            read_buffer: Vec::new(),
+            write_buffer: Vec::new(),
+            outstanding_responses: 0,
+            timeout,
+            recorder: None,
+            retry_policies: HashMap::new(),
+            protocol: Protocol::default(),
+            sequence: 0,
+        }
+    }
+
+    /// Sets the wire protocol, which has to match the one of the firmware.
+    ///
+    /// # Arguments
+    ///
+    /// * `protocol` - The protocol.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Records every exchange from now on, including the readiness probes. Cancelled exchanges are not recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `recorder` - The recorder the exchanges are written to.
+    pub fn set_recorder(&mut self, recorder: SerialRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Sets how a kind of command is retried after a transient error.
+    ///
+    /// # Arguments
+    ///
+    /// * `command_kind` - The kind of command the policy applies to.
+    /// * `retry_policy` - The policy.
+    pub fn set_retry_policy(&mut self, command_kind: SerialCommandKind, retry_policy: RetryPolicy) {
+        self.retry_policies.insert(command_kind, retry_policy);
+    }
+
+    /// Sends a command and waits for its response.
+    ///
+    /// Transient errors are retried according to the retry policy of the kind of command.
+    /// If the future is dropped after the command started to be written, the command is completed
+    /// and its response discarded by the next call.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(SerialResponse)` - The response from the hardware.
+    /// * `Err(HwSerialConnectionError)` - An error if the command fails.
+    pub async fn send_command(&mut self, command: SerialCommands) -> Result<SerialResponse, HwSerialConnectionError> {
+        let retry_policy = self.retry_policies.get(&command.kind()).cloned().unwrap_or_default();
+        let mut retries = 0;
+        loop {
+            match self.exchange(&command, Duration::from_millis(self.timeout)).await {
+                Err(error) if retry_policy.allows_retry(&error, retries) => {
+                    retries += 1;
+                    log::debug!(
+                        "Retrying {:?} ({}/{}) after: {}",
+                        command,
+                        retries,
+                        retry_policy.max_retries,
+                        error
+                    );
+                    tokio::time::sleep(Duration::from_millis(retry_policy.retry_delay)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Waits until the firmware answers, probing it by reading the encoders.
+    ///
+    /// See [`HwSerialConnection::wait_until_ready`].
+    ///
+    /// # Arguments
+    ///
+    /// * `ready_timeout` - The maximum time to wait for the firmware in milliseconds.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the firmware answered with valid encoder values.
+    /// * `Err(HwSerialConnectionError)` - An error if the firmware did not answer in time
+    ///   or the connection failed.
+    pub async fn wait_until_ready(&mut self, ready_timeout: u64) -> Result<(), HwSerialConnectionError> {
+        let mut probes = ReadinessProbes::new(ready_timeout);
+        let start = Instant::now();
+        let deadline = start + probes.ready_timeout();
+        loop {
+            let probe_timeout = probes.start_probe(deadline.saturating_duration_since(Instant::now()));
+            let result = self.exchange(&SerialCommands::ReadEncoderValues, probe_timeout).await;
+            if let Some(outcome) = probes.handle_result(
+                result,
+                start.elapsed(),
+                deadline.saturating_duration_since(Instant::now()),
+            ) {
+                return outcome;
+            }
+        }
+    }
+
+    /// Sends a command and waits for its response up to the given timeout.
+    async fn exchange(
+        &mut self,
+        command: &SerialCommands,
+        timeout: Duration,
+    ) -> Result<SerialResponse, HwSerialConnectionError> {
+        self.settle_cancelled_exchange(timeout).await?;
+        let command_str = HwSerialConnection::prepare_command_to_send(command);
+        self.sequence = self.sequence.wrapping_add(1);
+        let command_bytes = self.protocol.encode_command(self.sequence, &command_str);
+        let result = self.transmit(command, command_bytes.clone(), timeout).await;
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&command_bytes, &result);
+        }
+        result
+    }
+
+    /// Completes the write of a cancelled command and discards the responses to cancelled commands.
+    async fn settle_cancelled_exchange(&mut self, timeout: Duration) -> Result<(), HwSerialConnectionError> {
+        if !self.write_buffer.is_empty() {
+            log::debug!(
+                "Completing the write of a cancelled command: {}",
+                String::from_utf8_lossy(&self.write_buffer).escape_debug()
+            );
+            self.flush_write_buffer().await?;
+        }
+        while self.outstanding_responses > 0 {
+            match self.read_response(timeout).await {
+                Ok(response) => {
+                    log::debug!("Discarding the response to a cancelled command: {}", response);
+                    self.outstanding_responses -= 1;
+                }
+                // The response is lost, anything arriving later is discarded as stale input.
+                Err(error) if error.is_transient() => self.outstanding_responses = 0,
+                Err(error) => return Err(error),
+            }
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.discard_captured();
+        }
+        Ok(())
+    }
+
+    /// Writes an encoded command and reads its response.
+    async fn transmit(
+        &mut self,
+        command: &SerialCommands,
+        command_bytes: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<SerialResponse, HwSerialConnectionError> {
+        // Make sure the next frame read is the response to this command.
+        self.discard_stale_input()?;
+        log::trace!(
+            "Sending command: {}",
+            String::from_utf8_lossy(&command_bytes).escape_debug()
+        );
+        self.write_buffer = command_bytes;
+        // Accounted for until the response is read, in case the exchange is cancelled.
+        self.outstanding_responses += 1;
+        self.flush_write_buffer().await?;
+
+        log::trace!("Reading response from serial port");
+        let response_str = self.read_response(timeout).await;
+        self.outstanding_responses = 0;
+        let response_str = response_str?;
+        log::trace!("Received response: {}", response_str);
+        HwSerialConnection::parse_response(command, response_str)
+    }
+
+    /// Discards the bytes received so far, which belong to previous exchanges.
+    fn discard_stale_input(&mut self) -> Result<(), HwSerialConnectionError> {
+        if !self.read_buffer.is_empty() {
+            log::trace!(
+                "Discarding stale input: {}",
+                String::from_utf8_lossy(&self.read_buffer).escape_debug()
+            );
+            self.read_buffer.clear();
+        }
+        self.transport.clear_input_buffer()?;
+        Ok(())
+    }
+
+    /// Writes the pending bytes of the current command. Only the bytes not written yet are left if cancelled.
+    async fn flush_write_buffer(&mut self) -> Result<(), HwSerialConnectionError> {
+        while !self.write_buffer.is_empty() {
+            let written = self.transport.write(&self.write_buffer).await?;
+            if written == 0 {
+                return Err(HwSerialConnectionError::DisconnectedError {
+                    error: "Connection closed by the device".to_string(),
+                });
+            }
+            self.write_buffer.drain(..written);
+        }
+        self.transport.flush().await?;
+        Ok(())
+    }
+
+    /// Reads from the transport until a complete response frame is available.
+    ///
+    /// See [`HwSerialConnection`] for the framing. The bytes read are kept in the read buffer if cancelled.
+    async fn read_response(&mut self, timeout: Duration) -> Result<String, HwSerialConnectionError> {
+        let deadline = Instant::now() + timeout;
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            if let Some(response) = self.protocol.take_response(self.sequence, &mut self.read_buffer)? {
+                return Ok(response);
+            }
+            match tokio::time::timeout_at(deadline, self.transport.read(&mut chunk)).await {
+                Err(_) => {
+                    return Err(HwSerialConnectionError::TimeoutError {
+                        error: format!(
+                            "No complete response after {} ms, received: {:?}",
+                            timeout.as_millis(),
+                            String::from_utf8_lossy(&self.read_buffer)
+                        ),
+                        received: self.read_buffer.clone(),
+                    });
+                }
+                Ok(Ok(0)) => {
+                    return Err(HwSerialConnectionError::DisconnectedError {
+                        error: "Connection closed by the device".to_string(),
+                    });
+                }
+                Ok(Ok(n)) => {
+                    if let Some(recorder) = &self.recorder {
+                        recorder.capture(&chunk[..n]);
+                    }
+                    self.read_buffer.extend_from_slice(&chunk[..n]);
+                }
+                Ok(Err(e))
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+                    ) => {}
+                Ok(Err(e)) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Stand-in for the firmware answering a fixed script of commands over an in-memory pipe.
+#[cfg(test)]
+pub(crate) fn spawn_scripted_firmware(
+    mut firmware_end: tokio::io::DuplexStream,
+    script: Vec<(&'static str, &'static str)>,
+) -> tokio::task::JoinHandle<tokio::io::DuplexStream> {
+    tokio::spawn(async move {
+        for (expected_command, response) in script {
+            let mut command = Vec::new();
+            while command.last() != Some(&b'\r') {
+                let mut byte = [0; 1];
+                firmware_end.read_exact(&mut byte).await.unwrap();
+                command.push(byte[0]);
+            }
+            assert_eq!(String::from_utf8_lossy(&command), expected_command);
+            firmware_end.write_all(response.as_bytes()).await.unwrap();
+        }
+        firmware_end
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_async_send_command() {
+        let (connection_end, firmware_end) = tokio::io::duplex(64);
+        let firmware = spawn_scripted_firmware(firmware_end, vec![("e\r", "12 -34\r\n"), ("r\r", "OK\r\n")]);
+        let mut connection = AsyncHwSerialConnection::from_transport(connection_end, 1000);
+        assert_eq!(
+            connection
+                .send_command(SerialCommands::ReadEncoderValues)
+                .await
+                .unwrap(),
+            SerialResponse::EncoderValues { left: 12, right: -34 }
+        );
+        assert_eq!(
+            connection.send_command(SerialCommands::ResetEncoders).await.unwrap(),
+            SerialResponse::Ok
+        );
+        firmware.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_send_command_timeout() {
+        let (connection_end, _firmware_end) = tokio::io::duplex(64);
+        let mut connection = AsyncHwSerialConnection::from_transport(connection_end, 50);
+        assert!(matches!(
+            connection.send_command(SerialCommands::ReadEncoderValues).await,
+            Err(HwSerialConnectionError::TimeoutError { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_async_cancelled_command_response_is_discarded() {
+        let (connection_end, mut firmware_end) = tokio::io::duplex(64);
+        let mut connection = AsyncHwSerialConnection::from_transport(connection_end, 1000);
+        // The firmware answers late, after the encoder read was cancelled.
+        let firmware = tokio::spawn(async move {
+            let mut command = [0; 2];
+            firmware_end.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"e\r");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            firmware_end.write_all(b"12 -34\r\n").await.unwrap();
+            firmware_end.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"r\r");
+            firmware_end.write_all(b"OK\r\n").await.unwrap();
+        });
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(20),
+            connection.send_command(SerialCommands::ReadEncoderValues),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        assert_eq!(
+            connection.send_command(SerialCommands::ResetEncoders).await.unwrap(),
+            SerialResponse::Ok
+        );
+        firmware.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_cancelled_write_is_completed() {
+        // A tiny pipe, so the command cannot be written at once.
+        let (connection_end, mut firmware_end) = tokio::io::duplex(4);
+        let mut connection = AsyncHwSerialConnection::from_transport(connection_end, 1000);
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(20),
+            connection.send_command(SerialCommands::SetMotorValues { left: 100, right: 100 }),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        let firmware = tokio::spawn(async move {
+            let mut command = [0; 10];
+            firmware_end.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"m 100 100\r");
+            firmware_end.write_all(b"OK\r\n").await.unwrap();
+            let mut command = [0; 2];
+            firmware_end.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"e\r");
+            firmware_end.write_all(b"1 2\r\n").await.unwrap();
+        });
+        assert_eq!(
+            connection
+                .send_command(SerialCommands::ReadEncoderValues)
+                .await
+                .unwrap(),
+            SerialResponse::EncoderValues { left: 1, right: 2 }
+        );
+        firmware.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_wait_until_ready() {
+        let (connection_end, firmware_end) = tokio::io::duplex(64);
+        let firmware = spawn_scripted_firmware(firmware_end, vec![("e\r", "\r\n"), ("e\r", "0 0\r\n")]);
+        let mut connection = AsyncHwSerialConnection::from_transport(connection_end, 1000);
+        connection.wait_until_ready(1000).await.unwrap();
+        firmware.await.unwrap();
+
+        let (connection_end, _firmware_end) = tokio::io::duplex(64);
+        let mut connection = AsyncHwSerialConnection::from_transport(connection_end, 1000);
+        assert!(matches!(
+            connection.wait_until_ready(200).await,
+            Err(HwSerialConnectionError::NotReadyError { .. })
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Binary framing of the serial protocol, with integrity checks and sequence numbers.
+//!
+//! Every command and response travels in a packet:
+//!
+//! ```text
+//! [sequence number: u8][payload][CRC-16/CCITT-FALSE of the sequence number and the payload: u16, big endian]
+//! ```
+//!
+//! COBS encoded, so it contains no zero bytes, and terminated by a zero byte. The payload is the text of
+//! the command or of the response in the ASCII protocol without its terminator (e.g. `e` and `123 456`),
+//! so both protocols share the commands and the parsing of the responses.
+//!
+//! The firmware answers with the sequence number of the command, so a late response to an earlier
+//! command is told apart from the expected one. Packets that fail the CRC are dropped by the firmware,
+//! which leaves the command unanswered, and rejected as malformed by the host.
+
+use super::HwSerialConnectionError;
+
+/// Byte terminating every packet.
+pub const FRAME_DELIMITER: u8 = 0;
+/// Size of the sequence number and the CRC around the payload.
+const PACKET_OVERHEAD: usize = 3;
+
+/// Computes the CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF) of the given bytes.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Encodes the given bytes with Consistent Overhead Byte Stuffing, so the result contains no zero bytes.
+///
+/// The frame delimiter is not appended.
+pub fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len() + data.len() / 254 + 2);
+    let mut code_position = 0;
+    encoded.push(0);
+    for byte in data {
+        if *byte != 0 {
+            encoded.push(*byte);
+        }
+        let block_length = encoded.len() - code_position;
+        if *byte == 0 || block_length == 0xFF {
+            encoded[code_position] = block_length as u8;
+            code_position = encoded.len();
+            encoded.push(0);
+        }
+    }
+    encoded[code_position] = (encoded.len() - code_position) as u8;
+    encoded
+}
+
+/// Decodes bytes encoded with [`cobs_encode`], without the frame delimiter.
+///
+/// # Returns
+///
+/// * `Some(Vec<u8>)` - The decoded bytes.
+/// * `None` - If the bytes are not a valid COBS encoding.
+pub fn cobs_decode(encoded: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut position = 0;
+    while position < encoded.len() {
+        let code = encoded[position] as usize;
+        if code == 0 || position + code > encoded.len() {
+            return None;
+        }
+        let block = &encoded[position + 1..position + code];
+        if block.contains(&0) {
+            return None;
+        }
+        decoded.extend_from_slice(block);
+        position += code;
+        // A block shorter than the maximum stands for a zero byte, unless it is the last one.
+        if code < 0xFF && position < encoded.len() {
+            decoded.push(0);
+        }
+    }
+    Some(decoded)
+}
+
+/// Builds the packet carrying the given payload, terminated by the frame delimiter.
+///
+/// # Arguments
+///
+/// * `sequence` - The sequence number of the command, echoed by the response.
+/// * `payload` - The text of the command or of the response.
+pub fn encode_packet(sequence: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(payload.len() + PACKET_OVERHEAD);
+    packet.push(sequence);
+    packet.extend_from_slice(payload);
+    packet.extend_from_slice(&crc16(&packet).to_be_bytes());
+    let mut frame = cobs_encode(&packet);
+    frame.push(FRAME_DELIMITER);
+    frame
+}
+
+/// Unpacks a packet built with [`encode_packet`].
+///
+/// # Arguments
+///
+/// * `frame` - The bytes of the packet, with or without the frame delimiter.
+///
+/// # Returns
+///
+/// * `Ok((u8, Vec<u8>))` - The sequence number and the payload.
+/// * `Err(HwSerialConnectionError)` - A malformed response error if the encoding or the CRC is invalid.
+pub fn decode_packet(frame: &[u8]) -> Result<(u8, Vec<u8>), HwSerialConnectionError> {
+    let malformed = |error: &str| HwSerialConnectionError::MalformedResponseError {
+        error: format!("{}: {:02x?}", error, frame),
+        raw: frame.to_vec(),
+    };
+    let encoded = frame.strip_suffix(&[FRAME_DELIMITER]).unwrap_or(frame);
+    let packet = cobs_decode(encoded).ok_or_else(|| malformed("Invalid COBS encoding"))?;
+    if packet.len() < PACKET_OVERHEAD {
+        return Err(malformed("Packet too short"));
+    }
+    let (content, crc) = packet.split_at(packet.len() - 2);
+    if crc16(content).to_be_bytes() != crc {
+        return Err(malformed("CRC mismatch"));
+    }
+    Ok((content[0], content[1..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc16() {
+        // Check value of CRC-16/CCITT-FALSE.
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(b""), 0xFFFF);
+    }
+
+    #[test]
+    fn test_cobs() {
+        let cases: [(&[u8], &[u8]); 5] = [
+            (&[], &[0x01]),
+            (&[0x00], &[0x01, 0x01]),
+            (&[0x11, 0x22, 0x00, 0x33], &[0x03, 0x11, 0x22, 0x02, 0x33]),
+            (&[0x11, 0x00, 0x00], &[0x02, 0x11, 0x01, 0x01]),
+            (&[0x00, 0x11, 0x00], &[0x01, 0x02, 0x11, 0x01]),
+        ];
+        for (decoded, encoded) in cases {
+            assert_eq!(cobs_encode(decoded), encoded);
+            assert_eq!(cobs_decode(encoded).unwrap(), decoded);
+        }
+        // Blocks of the maximum length carry no implicit zero.
+        let long = (1..=600).map(|i| (i % 255 + 1) as u8).collect::<Vec<u8>>();
+        let encoded = cobs_encode(&long);
+        assert!(!encoded.contains(&0));
+        assert_eq!(cobs_decode(&encoded).unwrap(), long);
+        assert_eq!(cobs_decode(&[0x05, 0x11]), None);
+        assert_eq!(cobs_decode(&[0x02, 0x00]), None);
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let frame = encode_packet(7, b"123 -456");
+        assert_eq!(frame.last(), Some(&FRAME_DELIMITER));
+        assert!(!frame[..frame.len() - 1].contains(&FRAME_DELIMITER));
+        assert_eq!(decode_packet(&frame).unwrap(), (7, b"123 -456".to_vec()));
+        assert_eq!(decode_packet(&encode_packet(0, b"")).unwrap(), (0, Vec::new()));
+    }
+
+    #[test]
+    fn test_packet_integrity() {
+        let frame = encode_packet(7, b"123 456");
+        // Every single bit flip is caught, either by the COBS decoding or by the CRC.
+        for position in 0..frame.len() - 1 {
+            for bit in 0..8 {
+                let mut corrupted = frame.clone();
+                corrupted[position] ^= 1 << bit;
+                if corrupted[position] == FRAME_DELIMITER {
+                    continue;
+                }
+                assert!(
+                    matches!(
+                        decode_packet(&corrupted),
+                        Err(HwSerialConnectionError::MalformedResponseError { .. })
+                    ),
+                    "Undetected flip of bit {} of byte {}",
+                    bit,
+                    position
+                );
+            }
+        }
+        assert!(decode_packet(&[0x01, FRAME_DELIMITER]).is_err());
+    }
+}
+
+#[cfg(test)]
+mod property_tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn prop_packet_round_trip(sequence in any::<u8>(), payload in proptest::collection::vec(any::<u8>(), 0..600)) {
+            let frame = encode_packet(sequence, &payload);
+            prop_assert!(!frame[..frame.len() - 1].contains(&FRAME_DELIMITER));
+            prop_assert_eq!(decode_packet(&frame).unwrap(), (sequence, payload));
+        }
+
+        #[test]
+        fn prop_decode_packet_never_panics(frame in proptest::collection::vec(any::<u8>(), 0..600)) {
+            let _ = decode_packet(&frame);
+        }
+    }
+}
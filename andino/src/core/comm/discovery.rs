@@ -0,0 +1,276 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Discovery of the serial device of the Andino board.
+//!
+//! Device names like `/dev/ttyUSB0` depend on the order in which USB-serial adapters enumerate,
+//! so the board can also be selected by its USB vendor ID, product ID and serial number.
+
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+use super::HwSerialConnectionError;
+
+/// USB vendor and product IDs of the boards and USB-serial chips the Andino ships with.
+///
+/// Used by [`SerialDeviceSelector::Auto`]. The CP210x adapters of the RPLidar are left out on purpose, and so are
+/// the FT232R chips (`0403:6001`) of some Arduino Nanos, as many unrelated USB-serial adapters use them too: select
+/// such a board by its serial number, e.g. `usb:0403:6001:A1B2C3`.
+pub const KNOWN_BOARD_IDS: &[(u16, u16)] = &[
+    // Arduino Uno R3.
+    (0x2341, 0x0043),
+    // Arduino Uno.
+    (0x2341, 0x0001),
+    // Arduino Nano clones, CH340.
+    (0x1a86, 0x7523),
+];
+
+/// Selects the serial device to connect to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SerialDeviceSelector {
+    /// The path of the device, e.g. `/dev/ttyUSB0`. It can also be a pseudo-terminal.
+    Path(String),
+    /// The USB serial port matching all the given fields.
+    Usb {
+        /// USB vendor ID.
+        vid: Option<u16>,
+        /// USB product ID.
+        pid: Option<u16>,
+        /// Serial number of the USB device.
+        serial_number: Option<String>,
+    },
+    /// The only USB serial port with one of the [`KNOWN_BOARD_IDS`].
+    Auto,
+}
+
+impl Default for SerialDeviceSelector {
+    fn default() -> Self {
+        SerialDeviceSelector::Path(String::from("/dev/ttyUSB0"))
+    }
+}
+
+impl std::str::FromStr for SerialDeviceSelector {
+    type Err = HwSerialConnectionError;
+
+    /// Parses a selector: `auto`, `usb:<vid>[:<pid>[:<serial number>]]` with hexadecimal IDs and
+    /// empty fields matching anything (e.g. `usb:2341:0043` or `usb:::85734323231351E0B1A1`),
+    /// or otherwise the path of the device.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "auto" {
+            return Ok(SerialDeviceSelector::Auto);
+        }
+        let Some(usb_selector) = s.strip_prefix("usb:") else {
+            return Ok(SerialDeviceSelector::Path(s.to_string()));
+        };
+        let fields = usb_selector.splitn(3, ':').collect::<Vec<&str>>();
+        let parse_id = |field: Option<&&str>| -> Result<Option<u16>, HwSerialConnectionError> {
+            match field {
+                None => Ok(None),
+                Some(&"") => Ok(None),
+                Some(id) => u16::from_str_radix(id.trim_start_matches("0x"), 16)
+                    .map(Some)
+                    .map_err(|e| HwSerialConnectionError::DeviceDiscoveryError {
+                        error: format!("Invalid USB ID '{}' in '{}': {}", id, s, e),
+                    }),
+            }
+        };
+        Ok(SerialDeviceSelector::Usb {
+            vid: parse_id(fields.first())?,
+            pid: parse_id(fields.get(1))?,
+            serial_number: fields
+                .get(2)
+                .filter(|serial_number| !serial_number.is_empty())
+                .map(|serial_number| serial_number.to_string()),
+        })
+    }
+}
+
+impl std::fmt::Display for SerialDeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerialDeviceSelector::Path(path) => write!(f, "{}", path),
+            SerialDeviceSelector::Usb {
+                vid,
+                pid,
+                serial_number,
+            } => {
+                let id = |id: &Option<u16>| id.map(|id| format!("{:04x}", id)).unwrap_or_default();
+                write!(
+                    f,
+                    "usb:{}:{}:{}",
+                    id(vid),
+                    id(pid),
+                    serial_number.as_deref().unwrap_or_default()
+                )
+            }
+            SerialDeviceSelector::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+impl SerialDeviceSelector {
+    /// Finds the path of the selected device among the serial ports of the system.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(String)` - The path of the device. A `Path` selector is returned as is.
+    /// * `Err(HwSerialConnectionError)` - An error if the ports cannot be listed,
+    ///   or no port or more than one match.
+    pub fn resolve(&self) -> Result<String, HwSerialConnectionError> {
+        if let SerialDeviceSelector::Path(path) = self {
+            return Ok(path.clone());
+        }
+        let ports = serialport::available_ports().map_err(|e| HwSerialConnectionError::DeviceDiscoveryError {
+            error: format!("Cannot list the serial ports: {}", e),
+        })?;
+        self.resolve_from(&ports)
+    }
+
+    /// Whether the given port is selected.
+    pub fn matches(&self, port: &SerialPortInfo) -> bool {
+        match (self, &port.port_type) {
+            (SerialDeviceSelector::Path(path), _) => port.port_name == *path,
+            (
+                SerialDeviceSelector::Usb {
+                    vid,
+                    pid,
+                    serial_number,
+                },
+                SerialPortType::UsbPort(usb_port_info),
+            ) => {
+                vid.is_none_or(|vid| vid == usb_port_info.vid)
+                    && pid.is_none_or(|pid| pid == usb_port_info.pid)
+                    && serial_number
+                        .as_ref()
+                        .is_none_or(|serial_number| usb_port_info.serial_number.as_ref() == Some(serial_number))
+            }
+            (SerialDeviceSelector::Auto, SerialPortType::UsbPort(UsbPortInfo { vid, pid, .. })) => {
+                KNOWN_BOARD_IDS.contains(&(*vid, *pid))
+            }
+            _ => false,
+        }
+    }
+
+    // Picks the only port matching the selector.
+    fn resolve_from(&self, ports: &[SerialPortInfo]) -> Result<String, HwSerialConnectionError> {
+        let matching_ports = ports
+            .iter()
+            .filter(|port| self.matches(port))
+            .map(|port| port.port_name.clone())
+            .collect::<Vec<String>>();
+        match &matching_ports[..] {
+            [port_name] => {
+                log::debug!("Serial device '{}' selected by '{}'", port_name, self);
+                Ok(port_name.clone())
+            }
+            [] => Err(HwSerialConnectionError::DeviceDiscoveryError {
+                error: format!("No serial port matches '{}'", self),
+            }),
+            _ => Err(HwSerialConnectionError::DeviceDiscoveryError {
+                error: format!(
+                    "Several serial ports match '{}': {}, select one by serial number",
+                    self,
+                    matching_ports.join(", ")
+                ),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usb_port(port_name: &str, vid: u16, pid: u16, serial_number: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: port_name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: Some(serial_number.to_string()),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    fn ports() -> Vec<SerialPortInfo> {
+        vec![
+            // RPLidar.
+            usb_port("/dev/ttyUSB0", 0x10c4, 0xea60, "0001"),
+            usb_port("/dev/ttyUSB1", 0x1a86, 0x7523, "A1"),
+            SerialPortInfo {
+                port_name: "/dev/ttyS0".to_string(),
+                port_type: SerialPortType::PciPort,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_parse_serial_device_selector() {
+        assert_eq!(
+            "/dev/ttyACM0".parse::<SerialDeviceSelector>().unwrap(),
+            SerialDeviceSelector::Path("/dev/ttyACM0".to_string())
+        );
+        assert_eq!(
+            "auto".parse::<SerialDeviceSelector>().unwrap(),
+            SerialDeviceSelector::Auto
+        );
+        let selector = "usb:2341:0x0043:8573".parse::<SerialDeviceSelector>().unwrap();
+        assert_eq!(
+            selector,
+            SerialDeviceSelector::Usb {
+                vid: Some(0x2341),
+                pid: Some(0x0043),
+                serial_number: Some("8573".to_string()),
+            }
+        );
+        assert_eq!(selector.to_string(), "usb:2341:0043:8573");
+        assert_eq!(
+            "usb:::8573".parse::<SerialDeviceSelector>().unwrap(),
+            SerialDeviceSelector::Usb {
+                vid: None,
+                pid: None,
+                serial_number: Some("8573".to_string()),
+            }
+        );
+        assert!("usb:arduino".parse::<SerialDeviceSelector>().is_err());
+    }
+
+    #[test]
+    fn test_resolve_serial_device_selector() {
+        let ports = ports();
+        assert_eq!(SerialDeviceSelector::Auto.resolve_from(&ports).unwrap(), "/dev/ttyUSB1");
+        let selector = "usb:10c4:ea60".parse::<SerialDeviceSelector>().unwrap();
+        assert_eq!(selector.resolve_from(&ports).unwrap(), "/dev/ttyUSB0");
+        let selector = "usb:::A1".parse::<SerialDeviceSelector>().unwrap();
+        assert_eq!(selector.resolve_from(&ports).unwrap(), "/dev/ttyUSB1");
+        let selector = "usb:2341".parse::<SerialDeviceSelector>().unwrap();
+        assert!(matches!(
+            selector.resolve_from(&ports),
+            Err(HwSerialConnectionError::DeviceDiscoveryError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_auto_serial_device_selector_skips_generic_adapters() {
+        let mut ports = ports();
+        ports.push(usb_port("/dev/ttyUSB2", 0x0403, 0x6001, "C3"));
+        assert_eq!(SerialDeviceSelector::Auto.resolve_from(&ports).unwrap(), "/dev/ttyUSB1");
+        let selector = "usb:0403:6001:C3".parse::<SerialDeviceSelector>().unwrap();
+        assert_eq!(selector.resolve_from(&ports).unwrap(), "/dev/ttyUSB2");
+    }
+
+    #[test]
+    fn test_resolve_ambiguous_serial_device_selector() {
+        let mut ports = ports();
+        ports.push(usb_port("/dev/ttyUSB2", 0x1a86, 0x7523, "B2"));
+        assert!(matches!(
+            SerialDeviceSelector::Auto.resolve_from(&ports),
+            Err(HwSerialConnectionError::DeviceDiscoveryError { .. })
+        ));
+        let selector = "usb:1a86:7523:B2".parse::<SerialDeviceSelector>().unwrap();
+        assert_eq!(selector.resolve_from(&ports).unwrap(), "/dev/ttyUSB2");
+    }
+}
@@ -0,0 +1,437 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Recording of the serial traffic and its replay.
+//!
+//! A [`SerialRecorder`] attached to a `HwSerialConnection` writes every exchange to a file, one line each:
+//!
+//! ```text
+//! <seconds since the recording started>\t<command>\t<bytes received>\t<outcome>
+//! ```
+//!
+//! Commands, received bytes and outcomes are escaped so they fit in a line: `\\`, `\r`, `\n`, `\t`
+//! and `\xNN` for any other non-printable byte. The outcome is `ok` or the error of the exchange.
+//!
+//! A [`ReplayTransport`] feeds the bytes of a recording back as answers to the same commands, so a
+//! captured session can be reproduced offline, e.g. through a `Hal` polled with the recorded times.
+
+use std::collections::VecDeque;
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{HwSerialConnectionError, Protocol, SerialResponse, Transport, binary};
+
+/// Outcome of the exchanges that succeeded.
+const OK_OUTCOME: &str = "ok";
+
+/// A command sent to the firmware and what was received in response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedExchange {
+    /// Time since the recording started.
+    pub timestamp: Duration,
+    /// The command as sent, including its terminator.
+    pub command: Vec<u8>,
+    /// The bytes received while waiting for the response.
+    pub received: Vec<u8>,
+    /// `ok` or the error of the exchange.
+    pub outcome: String,
+}
+
+impl RecordedExchange {
+    /// Whether the exchange was a read of the encoders, i.e. a `Hal::poll_state`.
+    pub fn is_encoder_read(&self) -> bool {
+        self.command == b"e\r" || binary::decode_packet(&self.command).is_ok_and(|(_, payload)| payload == b"e")
+    }
+
+    // Formats the exchange as a line of a recording, without the line terminator.
+    fn to_line(&self) -> String {
+        format!(
+            "{:.6}\t{}\t{}\t{}",
+            self.timestamp.as_secs_f64(),
+            escape(&self.command),
+            escape(&self.received),
+            escape(self.outcome.as_bytes())
+        )
+    }
+}
+
+impl std::str::FromStr for RecordedExchange {
+    type Err = HwSerialConnectionError;
+
+    /// Parses a line of a recording.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid_line = || HwSerialConnectionError::RecordingError {
+            error: format!("Invalid recording line: {:?}", s),
+        };
+        let fields = s.split('\t').collect::<Vec<&str>>();
+        let [timestamp, command, received, outcome] = fields[..] else {
+            return Err(invalid_line());
+        };
+        let timestamp = timestamp.parse::<f64>().map_err(|_| invalid_line())?;
+        if !timestamp.is_finite() || timestamp < 0.0 {
+            return Err(invalid_line());
+        }
+        Ok(RecordedExchange {
+            timestamp: Duration::from_secs_f64(timestamp),
+            command: unescape(command).ok_or_else(invalid_line)?,
+            received: unescape(received).ok_or_else(invalid_line)?,
+            outcome: String::from_utf8_lossy(&unescape(outcome).ok_or_else(invalid_line)?).to_string(),
+        })
+    }
+}
+
+/// Reads a recording written by a [`SerialRecorder`].
+///
+/// # Arguments
+///
+/// * `path` - The path of the recording.
+///
+/// # Returns
+///
+/// * `Ok(Vec<RecordedExchange>)` - The recorded exchanges, in order.
+/// * `Err(HwSerialConnectionError)` - An error if the file cannot be read or is not a recording.
+pub fn load_recording(path: impl AsRef<Path>) -> Result<Vec<RecordedExchange>, HwSerialConnectionError> {
+    let file = std::fs::File::open(path.as_ref()).map_err(|e| HwSerialConnectionError::RecordingError {
+        error: format!("Cannot open {}: {}", path.as_ref().display(), e),
+    })?;
+    std::io::BufReader::new(file)
+        .lines()
+        .map(|line| {
+            line.map_err(|e| HwSerialConnectionError::RecordingError { error: e.to_string() })
+                .and_then(|line| line.parse())
+        })
+        .collect()
+}
+
+/// Records the exchanges of a `HwSerialConnection`.
+///
+/// Clones share the same output, so a recording can continue across reconnections.
+#[derive(Clone)]
+pub struct SerialRecorder {
+    state: Arc<Mutex<RecorderState>>,
+}
+
+struct RecorderState {
+    /// Where the exchanges are written to.
+    writer: Box<dyn Write + Send>,
+    /// The instant the recording started.
+    start: Instant,
+    /// The bytes received in the current exchange.
+    received: Vec<u8>,
+}
+
+impl std::fmt::Debug for SerialRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SerialRecorder").finish_non_exhaustive()
+    }
+}
+
+impl SerialRecorder {
+    /// Creates a recorder that writes to a new file, replacing any existing one.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the recording.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(SerialRecorder)` - The recorder.
+    /// * `Err(HwSerialConnectionError)` - An error if the file cannot be created.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, HwSerialConnectionError> {
+        let file = std::fs::File::create(path.as_ref()).map_err(|e| HwSerialConnectionError::RecordingError {
+            error: format!("Cannot create {}: {}", path.as_ref().display(), e),
+        })?;
+        Ok(SerialRecorder::new(std::io::BufWriter::new(file)))
+    }
+
+    /// Creates a recorder that writes to the given writer.
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        SerialRecorder {
+            state: Arc::new(Mutex::new(RecorderState {
+                writer: Box::new(writer),
+                start: Instant::now(),
+                received: Vec::new(),
+            })),
+        }
+    }
+
+    // Accumulates bytes received during the current exchange.
+    pub(crate) fn capture(&self, bytes: &[u8]) {
+        self.state.lock().unwrap().received.extend_from_slice(bytes);
+    }
+
+    // Drops the bytes received outside of a recorded exchange, e.g. the responses to cancelled commands.
+    #[cfg(feature = "async")]
+    pub(crate) fn discard_captured(&self) {
+        self.state.lock().unwrap().received.clear();
+    }
+
+    // Writes the current exchange. Failing to record does not fail the exchange, it is only logged.
+    pub(crate) fn record(&self, command: &[u8], result: &Result<SerialResponse, HwSerialConnectionError>) {
+        let mut state = self.state.lock().unwrap();
+        let exchange = RecordedExchange {
+            timestamp: state.start.elapsed(),
+            command: command.to_vec(),
+            received: std::mem::take(&mut state.received),
+            outcome: match result {
+                Ok(_) => OK_OUTCOME.to_string(),
+                Err(e) => e.to_string(),
+            },
+        };
+        let line = exchange.to_line();
+        if let Err(e) = writeln!(state.writer, "{}", line).and_then(|_| state.writer.flush()) {
+            log::warn!("Failed to record the serial exchange: {}", e);
+        }
+    }
+}
+
+/// Transport answering the commands with the bytes of a recording.
+///
+/// Every command written must match the next one in the recording, otherwise the write fails,
+/// unless the transport skips the unmatched exchanges. When the recorded bytes of an exchange are consumed, reads time out as they did when recording.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    /// The exchanges not replayed yet.
+    exchanges: VecDeque<RecordedExchange>,
+    /// Bytes of the command being written.
+    command: Vec<u8>,
+    /// Bytes to be read.
+    pending: VecDeque<u8>,
+    /// The read timeout.
+    timeout: Duration,
+    /// Whether recorded exchanges for other commands are skipped instead of failing the write.
+    skip_unmatched: bool,
+    /// The wire protocol of the recording.
+    protocol: Protocol,
+}
+
+impl ReplayTransport {
+    /// Creates a transport that replays the given exchanges.
+    ///
+    /// # Arguments
+    ///
+    /// * `exchanges` - The exchanges to replay, in order.
+    /// * `timeout` - The read timeout in milliseconds. Keep it short so recorded timeouts replay quickly.
+    pub fn new(exchanges: impl IntoIterator<Item = RecordedExchange>, timeout: u64) -> Self {
+        ReplayTransport {
+            exchanges: exchanges.into_iter().collect(),
+            command: Vec::new(),
+            pending: VecDeque::new(),
+            timeout: Duration::from_millis(timeout),
+            skip_unmatched: false,
+            protocol: Protocol::default(),
+        }
+    }
+
+    /// Sets the wire protocol the recording was made with, ASCII by default.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Skips the recorded exchanges for commands other than the one written, instead of failing.
+    /// Commands that are not in the rest of the recording get no response.
+    ///
+    /// Useful to replay only part of a session, e.g. only the encoder reads through `Hal::poll_state`.
+    pub fn skip_unmatched(mut self, skip_unmatched: bool) -> Self {
+        self.skip_unmatched = skip_unmatched;
+        self
+    }
+
+    /// Number of exchanges not replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.exchanges.len()
+    }
+}
+
+impl Read for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pending.is_empty() {
+            std::thread::sleep(self.timeout);
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Operation timed out"));
+        }
+        let n = buf.len().min(self.pending.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for ReplayTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for byte in buf {
+            self.command.push(*byte);
+            if *byte != self.protocol.command_terminator() {
+                continue;
+            }
+            let command = std::mem::take(&mut self.command);
+            if self.skip_unmatched && !self.exchanges.is_empty() {
+                let Some(position) = self.exchanges.iter().position(|exchange| exchange.command == command) else {
+                    // Never recorded, e.g. the firmware info query in a recording predating it: left unanswered.
+                    log::debug!(
+                        "Command not in the rest of the recording, left unanswered: {:?}",
+                        String::from_utf8_lossy(&command)
+                    );
+                    continue;
+                };
+                self.exchanges.drain(..position);
+            }
+            let Some(exchange) = self.exchanges.pop_front() else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "End of the recording, cannot answer {:?}",
+                        String::from_utf8_lossy(&command)
+                    ),
+                ));
+            };
+            if exchange.command != command {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Replay diverged at {:?}: expected {:?}, got {:?}",
+                        exchange.timestamp,
+                        String::from_utf8_lossy(&exchange.command),
+                        String::from_utf8_lossy(&command)
+                    ),
+                ));
+            }
+            self.pending.extend(exchange.received);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn clear_input_buffer(&mut self) -> std::io::Result<()> {
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+// Escapes bytes so they fit in a field of a recording line.
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for byte in bytes {
+        match byte {
+            b'\\' => escaped.push_str("\\\\"),
+            b'\r' => escaped.push_str("\\r"),
+            b'\n' => escaped.push_str("\\n"),
+            b'\t' => escaped.push_str("\\t"),
+            0x20..=0x7e => escaped.push(char::from(*byte)),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+// Reverts `escape`, returning `None` on invalid escape sequences.
+fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.bytes();
+    while let Some(byte) = chars.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match chars.next()? {
+            b'\\' => bytes.push(b'\\'),
+            b'r' => bytes.push(b'\r'),
+            b'n' => bytes.push(b'\n'),
+            b't' => bytes.push(b'\t'),
+            b'x' => {
+                let hex = [chars.next()?, chars.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            _ => return None,
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_round_trip() {
+        let bytes = b"12 -3\r\n\\\t\x00\xff".to_vec();
+        let escaped = escape(&bytes);
+        assert_eq!(escaped, "12 -3\\r\\n\\\\\\t\\x00\\xff");
+        assert_eq!(unescape(&escaped), Some(bytes));
+        assert_eq!(unescape("\\q"), None);
+        assert_eq!(unescape("\\x0"), None);
+    }
+
+    #[test]
+    fn test_recorded_exchange_line_round_trip() {
+        let exchange = RecordedExchange {
+            timestamp: Duration::from_micros(1_500_250),
+            command: b"e\r".to_vec(),
+            received: b"10 2\xfe0\r\n".to_vec(),
+            outcome: "Malformed frame\twith a tab".to_string(),
+        };
+        let line = exchange.to_line();
+        assert_eq!(line.parse::<RecordedExchange>().unwrap(), exchange);
+        assert!("1.0\te\\r\tOK".parse::<RecordedExchange>().is_err());
+        assert!("-1.0\te\\r\t\tok".parse::<RecordedExchange>().is_err());
+    }
+
+    #[test]
+    fn test_replay_transport() {
+        let exchanges = ["0.0\te\\r\t0 0\\r\\n\tok", "0.1\tr\\r\t\ttimeout"]
+            .iter()
+            .map(|line| line.parse::<RecordedExchange>().unwrap());
+        let mut transport = ReplayTransport::new(exchanges, 1);
+        let mut buffer = [0; 8];
+        transport.write_all(b"e\r").unwrap();
+        let n = transport.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"0 0\r\n");
+        assert_eq!(
+            transport.read(&mut buffer).unwrap_err().kind(),
+            std::io::ErrorKind::TimedOut
+        );
+        assert_eq!(
+            transport.write_all(b"e\r").unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        assert_eq!(transport.remaining(), 0);
+        assert_eq!(
+            transport.write_all(b"e\r").unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_replay_transport_skip_unmatched() {
+        let exchanges = ["0.0\tr\\r\tOK\\r\\n\tok", "0.1\te\\r\t5 6\\r\\n\tok"]
+            .iter()
+            .map(|line| line.parse::<RecordedExchange>().unwrap());
+        let mut transport = ReplayTransport::new(exchanges, 1).skip_unmatched(true);
+        let mut buffer = [0; 8];
+        transport.write_all(b"v\r").unwrap();
+        assert_eq!(
+            transport.read(&mut buffer).unwrap_err().kind(),
+            std::io::ErrorKind::TimedOut
+        );
+        assert_eq!(transport.remaining(), 2);
+        transport.write_all(b"e\r").unwrap();
+        let n = transport.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"5 6\r\n");
+        assert_eq!(
+            transport.write_all(b"e\r").unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+    }
+}
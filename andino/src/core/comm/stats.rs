@@ -0,0 +1,295 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Statistics of the serial communication, to tell how healthy the link to the firmware is.
+//!
+//! A `HwSerialConnection` counts, per kind of command, the exchanges and how they ended, and keeps a
+//! histogram of their round-trip latency. It also counts the bytes written to and read from the transport.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::{HwSerialConnectionError, SerialCommandKind};
+
+/// Upper bounds of the buckets of the latency histograms in milliseconds.
+/// Latencies above the last bound are counted in an extra, unbounded bucket.
+pub const LATENCY_BUCKET_BOUNDS: [u64; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+
+/// Histogram of round-trip latencies, with the buckets of [`LATENCY_BUCKET_BOUNDS`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+    /// Number of latencies in each bucket, the last one being unbounded.
+    buckets: [u64; LATENCY_BUCKET_BOUNDS.len() + 1],
+    /// Sum of all the latencies.
+    total: Duration,
+    /// The largest latency.
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    /// Adds a latency to the histogram.
+    pub fn record(&mut self, latency: Duration) {
+        let bucket = LATENCY_BUCKET_BOUNDS
+            .iter()
+            .position(|bound| latency <= Duration::from_millis(*bound))
+            .unwrap_or(LATENCY_BUCKET_BOUNDS.len());
+        self.buckets[bucket] += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// Adds the latencies of another histogram to this one.
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    /// Gets the number of latencies in the histogram.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Gets the number of latencies in each bucket, along with the upper bound of the bucket.
+    /// The bound of the last bucket is `None`.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        LATENCY_BUCKET_BOUNDS
+            .iter()
+            .map(|bound| Some(Duration::from_millis(*bound)))
+            .chain(std::iter::once(None))
+            .zip(self.buckets.iter().copied())
+    }
+
+    /// Gets the mean latency, `None` if the histogram is empty.
+    pub fn mean(&self) -> Option<Duration> {
+        let count = self.count();
+        (count > 0).then(|| self.total.div_f64(count as f64))
+    }
+
+    /// Gets the largest latency, `None` if the histogram is empty.
+    pub fn max(&self) -> Option<Duration> {
+        (self.count() > 0).then_some(self.max)
+    }
+
+    /// Estimates a percentile of the latencies.
+    ///
+    /// # Arguments
+    ///
+    /// * `percentile` - The percentile, from 0.0 to 100.0.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(Duration)` - The upper bound of the bucket the percentile falls in, capped to the largest latency.
+    /// * `None` - If the histogram is empty.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0 * count as f64).ceil() as u64).max(1);
+        let mut accumulated = 0;
+        for (bound, bucket_count) in self.buckets() {
+            accumulated += bucket_count;
+            if accumulated >= rank {
+                return Some(bound.map_or(self.max, |bound| bound.min(self.max)));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+/// Statistics of the exchanges of a kind of command.
+///
+/// Every attempt counts as an exchange, so a command retried twice adds three exchanges and two retries.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommandStats {
+    /// Number of exchanges.
+    pub exchanges: u64,
+    /// Number of exchanges answered with a valid response.
+    pub successes: u64,
+    /// Number of exchanges without a complete response in time.
+    pub timeouts: u64,
+    /// Number of exchanges answered with a malformed or unexpected response.
+    pub parse_errors: u64,
+    /// Number of exchanges that failed otherwise, e.g. because the device was unplugged.
+    pub other_errors: u64,
+    /// Number of times a command was sent again after a transient error.
+    pub retries: u64,
+    /// Round-trip latency of the exchanges that got a response, valid or not.
+    pub latency: LatencyHistogram,
+}
+
+impl CommandStats {
+    /// Adds the statistics of another kind of command, or of another period, to these.
+    pub fn merge(&mut self, other: &CommandStats) {
+        self.exchanges += other.exchanges;
+        self.successes += other.successes;
+        self.timeouts += other.timeouts;
+        self.parse_errors += other.parse_errors;
+        self.other_errors += other.other_errors;
+        self.retries += other.retries;
+        self.latency.merge(&other.latency);
+    }
+}
+
+/// Statistics of the communication over a serial connection.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommStats {
+    /// Statistics of each kind of command sent.
+    pub commands: HashMap<SerialCommandKind, CommandStats>,
+    /// Number of bytes written to the transport.
+    pub bytes_sent: u64,
+    /// Number of bytes read from the transport.
+    pub bytes_received: u64,
+}
+
+impl CommStats {
+    /// Gets the statistics of all the kinds of commands together.
+    pub fn total(&self) -> CommandStats {
+        self.commands
+            .values()
+            .fold(CommandStats::default(), |mut total, command_stats| {
+                total.merge(command_stats);
+                total
+            })
+    }
+
+    /// Adds the statistics of another period, e.g. of a connection that was lost, to these.
+    pub fn merge(&mut self, other: &CommStats) {
+        for (command_kind, command_stats) in &other.commands {
+            self.commands.entry(*command_kind).or_default().merge(command_stats);
+        }
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+    }
+
+    /// Counts an exchange.
+    ///
+    /// # Arguments
+    ///
+    /// * `command_kind` - The kind of command sent.
+    /// * `result` - How the exchange ended.
+    /// * `latency` - The time from sending the command to the end of the exchange.
+    pub(crate) fn record_exchange<T>(
+        &mut self,
+        command_kind: SerialCommandKind,
+        result: &Result<T, HwSerialConnectionError>,
+        latency: Duration,
+    ) {
+        let command_stats = self.commands.entry(command_kind).or_default();
+        command_stats.exchanges += 1;
+        let answered = match result {
+            Ok(_) => {
+                command_stats.successes += 1;
+                true
+            }
+            Err(HwSerialConnectionError::TimeoutError { .. }) => {
+                command_stats.timeouts += 1;
+                false
+            }
+            Err(
+                HwSerialConnectionError::MalformedResponseError { .. }
+                | HwSerialConnectionError::UnexpectedResponseError { .. },
+            ) => {
+                command_stats.parse_errors += 1;
+                true
+            }
+            Err(_) => {
+                command_stats.other_errors += 1;
+                false
+            }
+        };
+        if answered {
+            command_stats.latency.record(latency);
+        }
+    }
+
+    /// Counts a command sent again after a transient error.
+    pub(crate) fn record_retry(&mut self, command_kind: SerialCommandKind) {
+        self.commands.entry(command_kind).or_default().retries += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.mean(), None);
+        assert_eq!(histogram.percentile(50.0), None);
+        for latency in [3, 4, 4, 8, 15, 1500] {
+            histogram.record(Duration::from_millis(latency));
+        }
+        assert_eq!(histogram.count(), 6);
+        assert_eq!(histogram.mean(), Some(Duration::from_millis(1534).div_f64(6.0)));
+        assert_eq!(histogram.max(), Some(Duration::from_millis(1500)));
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_millis(5)));
+        assert_eq!(histogram.percentile(80.0), Some(Duration::from_millis(20)));
+        assert_eq!(histogram.percentile(100.0), Some(Duration::from_millis(1500)));
+        let buckets = histogram.buckets().collect::<Vec<_>>();
+        assert_eq!(buckets[2], (Some(Duration::from_millis(5)), 3));
+        assert_eq!(buckets[LATENCY_BUCKET_BOUNDS.len()], (None, 1));
+
+        // The estimate never exceeds the largest latency.
+        let mut histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_micros(300));
+        assert_eq!(histogram.percentile(99.0), Some(Duration::from_micros(300)));
+    }
+
+    #[test]
+    fn test_comm_stats() {
+        let mut stats = CommStats::default();
+        let encoders = SerialCommandKind::ReadEncoderValues;
+        stats.record_exchange(encoders, &Ok(()), Duration::from_millis(4));
+        stats.record_exchange::<()>(
+            encoders,
+            &Err(HwSerialConnectionError::TimeoutError {
+                error: "timeout".to_string(),
+                received: Vec::new(),
+            }),
+            Duration::from_millis(100),
+        );
+        stats.record_retry(encoders);
+        stats.record_exchange::<()>(
+            SerialCommandKind::SetMotorValues,
+            &Err(HwSerialConnectionError::MalformedResponseError {
+                error: "garbage".to_string(),
+                raw: b"O?\r\n".to_vec(),
+            }),
+            Duration::from_millis(6),
+        );
+        stats.record_exchange::<()>(
+            SerialCommandKind::SetMotorValues,
+            &Err(HwSerialConnectionError::DisconnectedError {
+                error: "unplugged".to_string(),
+            }),
+            Duration::from_millis(1),
+        );
+        stats.bytes_sent = 10;
+
+        let encoder_stats = &stats.commands[&encoders];
+        assert_eq!(encoder_stats.exchanges, 2);
+        assert_eq!(encoder_stats.successes, 1);
+        assert_eq!(encoder_stats.timeouts, 1);
+        assert_eq!(encoder_stats.retries, 1);
+        // Timeouts do not count towards the latency.
+        assert_eq!(encoder_stats.latency.count(), 1);
+
+        let total = stats.total();
+        assert_eq!(total.exchanges, 4);
+        assert_eq!(total.parse_errors, 1);
+        assert_eq!(total.other_errors, 1);
+        assert_eq!(total.latency.count(), 2);
+        assert_eq!(total.latency.max(), Some(Duration::from_millis(6)));
+
+        let mut merged = stats.clone();
+        merged.merge(&stats);
+        assert_eq!(merged.total().exchanges, 8);
+        assert_eq!(merged.bytes_sent, 20);
+    }
+}
@@ -0,0 +1,256 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Byte transports over which the serial protocol can be spoken.
+//!
+//! `HwSerialConnection` only needs something it can write commands to and read
+//! responses from. The [`Transport`] trait captures that, so the connection can run
+//! over a real serial port, a pseudo-terminal, a TCP socket or an in-memory pipe.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use super::HwSerialConnectionError;
+
+/// A bidirectional byte stream connected to the firmware (or a stand-in for it).
+///
+/// Reads are expected to block for at most the transport's timeout and to fail with
+/// [`std::io::ErrorKind::TimedOut`] (or [`std::io::ErrorKind::WouldBlock`]) when no data arrived.
+pub trait Transport: Read + Write + Send + std::fmt::Debug {
+    /// Discards the bytes that were received but not read yet.
+    fn clear_input_buffer(&mut self) -> std::io::Result<()>;
+}
+
+/// Real serial ports and pseudo-terminals opened via the `serialport` crate.
+impl Transport for Box<dyn serialport::SerialPort> {
+    fn clear_input_buffer(&mut self) -> std::io::Result<()> {
+        Ok(self.clear(serialport::ClearBuffer::Input)?)
+    }
+}
+
+/// Pseudo-terminals, e.g. the ones created by `serialport::TTYPort::pair`.
+#[cfg(unix)]
+impl Transport for serialport::TTYPort {
+    fn clear_input_buffer(&mut self) -> std::io::Result<()> {
+        Ok(serialport::SerialPort::clear(self, serialport::ClearBuffer::Input)?)
+    }
+}
+
+/// TCP sockets, e.g. a serial-to-network bridge. Set a read timeout on the stream before using it.
+impl Transport for std::net::TcpStream {
+    fn clear_input_buffer(&mut self) -> std::io::Result<()> {
+        self.set_nonblocking(true)?;
+        let mut buffer = [0; 64];
+        let result = loop {
+            match self.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.set_nonblocking(false)?;
+        result
+    }
+}
+
+/// Opens a serial port with the parameters expected by the Andino firmware.
+///
+/// # Arguments
+///
+/// * `serial_device` - The name of the serial device to connect to. It can also be a pseudo-terminal.
+/// * `baud_rate` - The baud rate for the serial connection.
+/// * `timeout` - The timeout for the serial connection in milliseconds.
+///
+/// # Returns
+///
+/// * `Ok(Box<dyn serialport::SerialPort>)` - The opened serial port.
+/// * `Err(HwSerialConnectionError)` - An error if the port cannot be opened.
+pub fn open_serial_port(
+    serial_device: impl AsRef<str>,
+    baud_rate: u32,
+    timeout: u64,
+) -> Result<Box<dyn serialport::SerialPort>, HwSerialConnectionError> {
+    let serial_port = serialport::new(serial_device.as_ref(), baud_rate)
+        // Set the serial port parameters
+        .parity(serialport::Parity::None)
+        .stop_bits(serialport::StopBits::One)
+        .data_bits(serialport::DataBits::Eight)
+        .flow_control(serialport::FlowControl::None)
+        .timeout(Duration::from_millis(timeout))
+        // Open the serial port
+        .open()
+        .map_err(|e| HwSerialConnectionError::SerialPortConnectionError { error: e.to_string() })?;
+    log::trace!("Serial port opened: {}", serial_device.as_ref());
+    Ok(serial_port)
+}
+
+/// Bytes flowing in one direction of a [`MemoryPipe`].
+#[derive(Debug, Default)]
+struct PipeChannel {
+    buffer: Mutex<VecDeque<u8>>,
+    data_available: Condvar,
+}
+
+/// One end of an in-memory, bidirectional byte pipe.
+///
+/// Whatever is written to one end can be read from the other one. Useful to connect
+/// a `HwSerialConnection` to a stand-in for the firmware running in another thread.
+#[derive(Debug)]
+pub struct MemoryPipe {
+    /// Bytes written by the other end.
+    incoming: Arc<PipeChannel>,
+    /// Bytes written by this end.
+    outgoing: Arc<PipeChannel>,
+    /// The read timeout.
+    timeout: Duration,
+}
+
+impl MemoryPipe {
+    /// Creates the two connected ends of a pipe.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The read timeout for both ends in milliseconds.
+    pub fn pair(timeout: u64) -> (MemoryPipe, MemoryPipe) {
+        let a_to_b = Arc::new(PipeChannel::default());
+        let b_to_a = Arc::new(PipeChannel::default());
+        let timeout = Duration::from_millis(timeout);
+        (
+            MemoryPipe {
+                incoming: Arc::clone(&b_to_a),
+                outgoing: Arc::clone(&a_to_b),
+                timeout,
+            },
+            MemoryPipe {
+                incoming: a_to_b,
+                outgoing: b_to_a,
+                timeout,
+            },
+        )
+    }
+}
+
+impl Read for MemoryPipe {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let buffer = self.incoming.buffer.lock().unwrap();
+        let (mut buffer, _) = self
+            .incoming
+            .data_available
+            .wait_timeout_while(buffer, self.timeout, |buffer| buffer.is_empty())
+            .unwrap();
+        if buffer.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Operation timed out"));
+        }
+        let n = buf.len().min(buffer.len());
+        for (dst, src) in buf.iter_mut().zip(buffer.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryPipe {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.outgoing.buffer.lock().unwrap().extend(buf);
+        self.outgoing.data_available.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryPipe {
+    fn clear_input_buffer(&mut self) -> std::io::Result<()> {
+        self.incoming.buffer.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+/// A transport answering with scripted bytes, then reporting the device as closed.
+///
+/// Whatever is written to it is discarded. Used by the property tests and the fuzz target to feed arbitrary
+/// responses through the receive path.
+#[cfg(any(test, fuzzing))]
+#[derive(Debug)]
+pub struct ScriptedTransport {
+    /// The bytes still to be read.
+    received: VecDeque<u8>,
+}
+
+#[cfg(any(test, fuzzing))]
+impl ScriptedTransport {
+    /// Creates a transport answering with the given bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `received` - The bytes to be read from the transport.
+    pub fn new(received: impl Into<VecDeque<u8>>) -> Self {
+        ScriptedTransport {
+            received: received.into(),
+        }
+    }
+}
+
+#[cfg(any(test, fuzzing))]
+impl Read for ScriptedTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.received.len());
+        for (dst, src) in buf.iter_mut().zip(self.received.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(any(test, fuzzing))]
+impl Write for ScriptedTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(any(test, fuzzing))]
+impl Transport for ScriptedTransport {
+    fn clear_input_buffer(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_pipe_round_trip() {
+        let (mut a, mut b) = MemoryPipe::pair(100);
+        a.write_all(b"e\r").unwrap();
+        let mut buffer = [0; 8];
+        let n = b.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"e\r");
+
+        b.write_all(b"0 0\r\n").unwrap();
+        let n = a.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"0 0\r\n");
+    }
+
+    #[test]
+    fn test_memory_pipe_read_timeout() {
+        let (mut a, _b) = MemoryPipe::pair(10);
+        let mut buffer = [0; 8];
+        let err = a.read(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+}
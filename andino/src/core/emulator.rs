@@ -0,0 +1,722 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! In-process emulator of the Andino firmware.
+//!
+//! [`FirmwareEmulator`] is a [`Transport`] that understands the same serial text
+//! protocol as the firmware at <https://github.com/Ekumen-OS/andino/tree/humble/andino_firmware>
+//! and replies like it would. Behind the protocol it simulates the two motors, their
+//! PID speed controllers and the encoders, so `HwSerialConnection` and `Hal` can be
+//! exercised without a robot.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::core::comm::{Protocol, SerialCommandKind, SerialCommands, Transport, binary};
+
+/// Rate at which the firmware runs the PID loop, in Hz.
+const PID_RATE: f64 = 30.0;
+/// Maximum PWM value accepted by the motor driver.
+const MAX_PWM: f64 = 255.0;
+/// The firmware stops the motors when no motor command arrives for this long.
+const AUTO_STOP_INTERVAL: Duration = Duration::from_millis(3000);
+/// Bits sent over the line per byte: start bit, eight data bits and stop bit.
+const BITS_PER_BYTE: f64 = 10.0;
+
+/// Response sent by the firmware when a command is accepted.
+const OK_RESPONSE: &str = "OK";
+/// Response sent by the firmware when a command is unknown.
+const INVALID_COMMAND_RESPONSE: &str = "Invalid Command";
+
+/// Configuration of the [`FirmwareEmulator`].
+#[derive(Clone, Debug)]
+pub struct EmulatorConfig {
+    /// The emulated baud rate, used to compute how long a response takes to be transmitted.
+    pub baud_rate: u32,
+    /// Time the firmware takes to process a command before it starts replying.
+    pub processing_latency: Duration,
+    /// Read timeout, as it would be configured on a serial port.
+    pub timeout: Duration,
+    /// Time the board takes to boot after the port is opened, during which input is ignored.
+    pub boot_time: Duration,
+    /// Speed of the wheels at full PWM, in encoder ticks per second.
+    pub max_ticks_per_second: f64,
+    /// Time constant of the first-order motor dynamics, in seconds.
+    pub motor_time_constant: f64,
+    /// The version reported by the firmware. When `None`, the version query and the read of the PID values
+    /// are unknown commands, like on firmware released before them.
+    pub firmware_version: Option<String>,
+    /// The wire protocol. The binary one serves as a reference implementation of its firmware side.
+    pub protocol: Protocol,
+}
+
+impl Default for EmulatorConfig {
+    fn default() -> Self {
+        EmulatorConfig {
+            baud_rate: 57600,
+            processing_latency: Duration::from_millis(1),
+            timeout: Duration::from_millis(10),
+            boot_time: Duration::ZERO,
+            max_ticks_per_second: 1400.0,
+            motor_time_constant: 0.1,
+            firmware_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            protocol: Protocol::Ascii,
+        }
+    }
+}
+
+/// Gains of the firmware PID controllers.
+#[derive(Clone, Debug)]
+struct PidGains {
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    ko: f64,
+}
+
+impl Default for PidGains {
+    // Default gains of the firmware.
+    fn default() -> Self {
+        PidGains {
+            kp: 30.0,
+            ki: 0.0,
+            kd: 10.0,
+            ko: 10.0,
+        }
+    }
+}
+
+/// A simulated motor with its encoder and PID speed controller.
+#[derive(Debug, Default)]
+struct EmulatedMotor {
+    /// Continuous position of the wheel in encoder ticks.
+    position: f64,
+    /// Speed of the wheel in encoder ticks per second.
+    speed: f64,
+    /// PWM applied to the motor, within [-MAX_PWM, MAX_PWM].
+    pwm: f64,
+    /// Target speed in encoder ticks per PID frame.
+    setpoint: f64,
+    /// Encoder count seen in the previous PID frame.
+    last_encoder_count: i64,
+    /// Ticks per frame measured in the previous PID frame.
+    last_input: f64,
+    /// Accumulated integral term of the PID.
+    integral_term: f64,
+}
+
+impl EmulatedMotor {
+    /// The count reported by the encoder.
+    fn encoder_count(&self) -> i64 {
+        self.position.floor() as i64
+    }
+
+    /// Stops the motor and clears the PID state.
+    fn reset_pid(&mut self) {
+        self.setpoint = 0.0;
+        self.pwm = 0.0;
+        self.integral_term = 0.0;
+        self.last_input = 0.0;
+        self.last_encoder_count = self.encoder_count();
+    }
+
+    /// Runs one frame of the PID, mirroring the incremental controller of the firmware.
+    fn compute_pid(&mut self, gains: &PidGains) {
+        let encoder_count = self.encoder_count();
+        let input = (encoder_count - self.last_encoder_count) as f64;
+        let error = self.setpoint - input;
+        let ko = if gains.ko == 0.0 { 1.0 } else { gains.ko };
+        let mut output = (gains.kp * error - gains.kd * (input - self.last_input) + self.integral_term) / ko + self.pwm;
+        if output >= MAX_PWM {
+            output = MAX_PWM;
+        } else if output <= -MAX_PWM {
+            output = -MAX_PWM;
+        } else {
+            self.integral_term += gains.ki * error;
+        }
+        self.pwm = output;
+        self.last_encoder_count = encoder_count;
+        self.last_input = input;
+    }
+
+    /// Integrates the motor dynamics over `dt` seconds.
+    fn step(&mut self, dt: f64, config: &EmulatorConfig) {
+        let target_speed = self.pwm / MAX_PWM * config.max_ticks_per_second;
+        self.speed += (target_speed - self.speed) * (1.0 - (-dt / config.motor_time_constant).exp());
+        self.position += self.speed * dt;
+    }
+}
+
+/// Emulates the Andino firmware behind a [`Transport`].
+///
+/// Commands written to the emulator are executed when their terminating carriage return
+/// (or, with the binary protocol, the packet delimiter) arrives. Responses become readable
+/// after the processing latency plus the time they take to be transmitted at the configured
+/// baud rate, one after the other. The simulation advances with the wall clock, so the
+/// motors keep moving between commands like on the real robot.
+#[derive(Debug)]
+pub struct FirmwareEmulator {
+    config: EmulatorConfig,
+    /// Bytes of the command being received.
+    input: Vec<u8>,
+    /// Responses waiting to be read, along with the instant they become available.
+    pending_responses: VecDeque<(Instant, VecDeque<u8>)>,
+    /// Wall-clock instant the bytes written so far are completely received.
+    input_line_free_at: Instant,
+    /// Wall-clock instant the responses so far are completely transmitted.
+    output_line_free_at: Instant,
+    /// Left and right motors.
+    motors: [EmulatedMotor; 2],
+    /// Gains of the PID controllers.
+    pid_gains: PidGains,
+    /// Whether the PID controllers are driving the motors.
+    moving: bool,
+    /// Values of the GPIO pins, zero when never set.
+    pin_values: HashMap<u8, u16>,
+    /// Simulated time since the emulator was created.
+    sim_time: Duration,
+    /// Simulated time at which the last motor command arrived.
+    last_motor_command: Duration,
+    /// Wall-clock time not yet simulated, as the simulation advances in whole PID frames.
+    unsimulated_time: Duration,
+    /// Wall-clock instant the simulation was last advanced to.
+    last_update: Instant,
+    /// Wall-clock instant the board finishes booting.
+    booted_at: Instant,
+}
+
+impl FirmwareEmulator {
+    /// Creates a new emulator with the motors stopped and the encoders at zero.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration of the emulator.
+    pub fn new(config: EmulatorConfig) -> Self {
+        let now = Instant::now();
+        let booted_at = now + config.boot_time;
+        FirmwareEmulator {
+            config,
+            input: Vec::new(),
+            pending_responses: VecDeque::new(),
+            input_line_free_at: now,
+            output_line_free_at: now,
+            motors: Default::default(),
+            pid_gains: PidGains::default(),
+            moving: false,
+            pin_values: HashMap::new(),
+            sim_time: Duration::ZERO,
+            last_motor_command: Duration::ZERO,
+            unsimulated_time: Duration::ZERO,
+            last_update: now,
+            booted_at,
+        }
+    }
+
+    /// Sets the value read from a GPIO pin, e.g. to emulate a pressed bumper.
+    ///
+    /// Analog reads return the value as is, while digital reads return whether it is non-zero.
+    /// Writes to a pin also change the value read from it.
+    ///
+    /// # Arguments
+    ///
+    /// * `pin` - The pin number.
+    /// * `value` - The value of the pin.
+    pub fn set_pin_value(&mut self, pin: u8, value: u16) {
+        self.pin_values.insert(pin, value);
+    }
+
+    /// Parses a command line the way the firmware does.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The command without its terminating carriage return.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(SerialCommands)` - The command, if it is known and its arguments are valid.
+    /// * `None` - Otherwise.
+    pub fn parse_command(line: &str) -> Option<SerialCommands> {
+        let mut tokens = line.split_whitespace();
+        let command = match (tokens.next()?, tokens.next(), tokens.next()) {
+            ("e", None, None) => SerialCommands::ReadEncoderValues,
+            ("r", None, None) => SerialCommands::ResetEncoders,
+            ("v", None, None) => SerialCommands::ReadFirmwareInfo,
+            ("i", None, None) => SerialCommands::ReadPIDValues,
+            ("o", Some(left), Some(right)) => SerialCommands::SetMotorPWMValues {
+                left: left.parse().ok()?,
+                right: right.parse().ok()?,
+            },
+            ("a", Some(pin), None) => SerialCommands::ReadAnalogPin { pin: pin.parse().ok()? },
+            ("d", Some(pin), None) => SerialCommands::ReadDigitalPin { pin: pin.parse().ok()? },
+            ("x", Some(pin), Some(value)) => SerialCommands::WriteAnalogPin {
+                pin: pin.parse().ok()?,
+                value: value.parse().ok()?,
+            },
+            ("w", Some(pin), Some(value)) => SerialCommands::WriteDigitalPin {
+                pin: pin.parse().ok()?,
+                value: value.parse::<u8>().ok()? != 0,
+            },
+            ("m", Some(left), Some(right)) => SerialCommands::SetMotorValues {
+                left: left.parse().ok()?,
+                right: right.parse().ok()?,
+            },
+            ("u", Some(gains), None) => {
+                let gains = gains
+                    .split(':')
+                    .map(|gain| gain.parse::<f32>().ok())
+                    .collect::<Option<Vec<_>>>()?;
+                let [kp, ki, kd, ko] = gains[..] else {
+                    return None;
+                };
+                SerialCommands::SetPIDValues { kp, ki, kd, ko }
+            }
+            _ => return None,
+        };
+        if tokens.next().is_some() {
+            return None;
+        }
+        Some(command)
+    }
+
+    /// Advances the simulation up to `now`.
+    fn advance_to(&mut self, now: Instant) {
+        let frame = Duration::from_secs_f64(1.0 / PID_RATE);
+        self.unsimulated_time += now.saturating_duration_since(self.last_update);
+        self.last_update = self.last_update.max(now);
+        while self.unsimulated_time >= frame {
+            self.unsimulated_time -= frame;
+            self.sim_time += frame;
+            let driven = self.moving || self.motors.iter().any(|motor| motor.pwm != 0.0);
+            if driven && self.sim_time - self.last_motor_command > AUTO_STOP_INTERVAL {
+                log::trace!("Emulator: no motor command received recently, stopping the motors");
+                self.stop_motors();
+            }
+            for motor in self.motors.iter_mut() {
+                if self.moving {
+                    motor.compute_pid(&self.pid_gains);
+                }
+                motor.step(frame.as_secs_f64(), &self.config);
+            }
+        }
+    }
+
+    /// Stops the motors the way the firmware does.
+    fn stop_motors(&mut self) {
+        self.moving = false;
+        self.motors.iter_mut().for_each(EmulatedMotor::reset_pid);
+    }
+
+    /// Executes a command and returns the response the firmware would send.
+    fn execute(&mut self, line: &str) -> String {
+        log::trace!("Emulator: executing command: {}", line);
+        let Some(command) = FirmwareEmulator::parse_command(line) else {
+            return INVALID_COMMAND_RESPONSE.to_string();
+        };
+        match command {
+            SerialCommands::ReadEncoderValues => {
+                format!("{} {}", self.motors[0].encoder_count(), self.motors[1].encoder_count())
+            }
+            SerialCommands::SetMotorValues { left, right } => {
+                self.last_motor_command = self.sim_time;
+                if left == 0 && right == 0 {
+                    self.stop_motors();
+                } else {
+                    self.moving = true;
+                }
+                self.motors[0].setpoint = left as f64 / PID_RATE;
+                self.motors[1].setpoint = right as f64 / PID_RATE;
+                OK_RESPONSE.to_string()
+            }
+            SerialCommands::ResetEncoders => {
+                for motor in self.motors.iter_mut() {
+                    motor.position = 0.0;
+                    motor.reset_pid();
+                }
+                OK_RESPONSE.to_string()
+            }
+            SerialCommands::SetMotorPWMValues { left, right } => {
+                // The PID controllers are disabled until the next speed command.
+                self.last_motor_command = self.sim_time;
+                self.stop_motors();
+                self.motors[0].pwm = (left as f64).clamp(-MAX_PWM, MAX_PWM);
+                self.motors[1].pwm = (right as f64).clamp(-MAX_PWM, MAX_PWM);
+                OK_RESPONSE.to_string()
+            }
+            SerialCommands::ReadAnalogPin { pin } => self.pin_values.get(&pin).copied().unwrap_or(0).to_string(),
+            SerialCommands::ReadDigitalPin { pin } => {
+                u8::from(self.pin_values.get(&pin).is_some_and(|value| *value != 0)).to_string()
+            }
+            SerialCommands::WriteAnalogPin { pin, value } => {
+                self.pin_values.insert(pin, value as u16);
+                OK_RESPONSE.to_string()
+            }
+            SerialCommands::WriteDigitalPin { pin, value } => {
+                self.pin_values.insert(pin, value as u16);
+                OK_RESPONSE.to_string()
+            }
+            SerialCommands::SetPIDValues { kp, ki, kd, ko } => {
+                self.pid_gains = PidGains {
+                    kp: kp as f64,
+                    ki: ki as f64,
+                    kd: kd as f64,
+                    ko: ko as f64,
+                };
+                OK_RESPONSE.to_string()
+            }
+            SerialCommands::ReadFirmwareInfo => match &self.config.firmware_version {
+                Some(version) => format!(
+                    "{} {}",
+                    version,
+                    SerialCommandKind::ALL
+                        .iter()
+                        .map(SerialCommandKind::letter)
+                        .collect::<String>()
+                ),
+                None => INVALID_COMMAND_RESPONSE.to_string(),
+            },
+            // Introduced along with the version query.
+            SerialCommands::ReadPIDValues if self.config.firmware_version.is_none() => {
+                INVALID_COMMAND_RESPONSE.to_string()
+            }
+            SerialCommands::ReadPIDValues => format!(
+                "{}:{}:{}:{}",
+                self.pid_gains.kp, self.pid_gains.ki, self.pid_gains.kd, self.pid_gains.ko
+            ),
+        }
+    }
+
+    /// Executes a received command, without its terminator, and encodes the response in the configured protocol.
+    ///
+    /// Binary packets that fail the integrity checks are dropped without a response, like the firmware does.
+    fn respond(&mut self, input: &[u8]) -> Option<Vec<u8>> {
+        match self.config.protocol {
+            Protocol::Ascii => Some((self.execute(&String::from_utf8_lossy(input)) + "\r\n").into_bytes()),
+            Protocol::Binary => match binary::decode_packet(input) {
+                Ok((sequence, payload)) => {
+                    let response = self.execute(&String::from_utf8_lossy(&payload));
+                    Some(binary::encode_packet(sequence, response.as_bytes()))
+                }
+                Err(error) => {
+                    log::trace!("Emulator: dropping invalid packet: {}", error);
+                    None
+                }
+            },
+        }
+    }
+
+    /// Time it takes to transmit `bytes` bytes at the configured baud rate.
+    fn transmission_time(&self, bytes: usize) -> Duration {
+        Duration::from_secs_f64(bytes as f64 * BITS_PER_BYTE / self.config.baud_rate as f64)
+    }
+}
+
+impl Write for FirmwareEmulator {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let now = Instant::now();
+        self.advance_to(now);
+        if now < self.booted_at {
+            log::trace!("Emulator: ignoring input while booting");
+            return Ok(buf.len());
+        }
+        // Bytes travel one after the other, and so do the responses: a command written right after another
+        // is received later, and its response is transmitted once the previous one is.
+        let mut received_at = now.max(self.input_line_free_at);
+        for byte in buf {
+            received_at += self.transmission_time(1);
+            if *byte != self.config.protocol.command_terminator() {
+                if !(self.config.protocol == Protocol::Ascii && *byte == b'\n') {
+                    self.input.push(*byte);
+                }
+                continue;
+            }
+            let input = std::mem::take(&mut self.input);
+            let Some(response) = self.respond(&input) else {
+                continue;
+            };
+            let transmitted_at = (received_at + self.config.processing_latency).max(self.output_line_free_at);
+            let available_at = transmitted_at + self.transmission_time(response.len());
+            self.output_line_free_at = available_at;
+            self.pending_responses.push_back((available_at, response.into()));
+        }
+        self.input_line_free_at = received_at;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for FirmwareEmulator {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = Instant::now() + self.config.timeout;
+        loop {
+            let now = Instant::now();
+            self.advance_to(now);
+            match self.pending_responses.front_mut() {
+                Some((available_at, response)) if *available_at <= now => {
+                    let n = buf.len().min(response.len());
+                    for (dst, src) in buf.iter_mut().zip(response.drain(..n)) {
+                        *dst = src;
+                    }
+                    if response.is_empty() {
+                        self.pending_responses.pop_front();
+                    }
+                    return Ok(n);
+                }
+                Some((available_at, _)) if *available_at <= deadline => {
+                    std::thread::sleep(*available_at - now);
+                }
+                _ => {
+                    std::thread::sleep(deadline.saturating_duration_since(now));
+                    return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Operation timed out"));
+                }
+            }
+        }
+    }
+}
+
+impl Transport for FirmwareEmulator {
+    fn clear_input_buffer(&mut self) -> std::io::Result<()> {
+        // Only the responses already transmitted are in the input buffer of the host.
+        let now = Instant::now();
+        self.pending_responses.retain(|(available_at, _)| *available_at > now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(emulator: &mut FirmwareEmulator, command: &str) -> String {
+        emulator.write_all(command.as_bytes()).unwrap();
+        let mut buffer = [0; 64];
+        let n = emulator.read(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..n]).to_string()
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert!(matches!(
+            FirmwareEmulator::parse_command("e"),
+            Some(SerialCommands::ReadEncoderValues)
+        ));
+        assert!(matches!(
+            FirmwareEmulator::parse_command("m 100 -200"),
+            Some(SerialCommands::SetMotorValues { left: 100, right: -200 })
+        ));
+        assert!(matches!(
+            FirmwareEmulator::parse_command("u 1:2:3.5:4"),
+            Some(SerialCommands::SetPIDValues {
+                kp: 1.0,
+                ki: 2.0,
+                kd: 3.5,
+                ko: 4.0
+            })
+        ));
+        assert!(matches!(
+            FirmwareEmulator::parse_command("r"),
+            Some(SerialCommands::ResetEncoders)
+        ));
+        assert!(matches!(
+            FirmwareEmulator::parse_command("o -255 30"),
+            Some(SerialCommands::SetMotorPWMValues { left: -255, right: 30 })
+        ));
+        assert!(matches!(
+            FirmwareEmulator::parse_command("a 3"),
+            Some(SerialCommands::ReadAnalogPin { pin: 3 })
+        ));
+        assert!(matches!(
+            FirmwareEmulator::parse_command("w 13 1"),
+            Some(SerialCommands::WriteDigitalPin { pin: 13, value: true })
+        ));
+        assert!(FirmwareEmulator::parse_command("m 100").is_none());
+        assert!(FirmwareEmulator::parse_command("u 1:2:3").is_none());
+        assert!(FirmwareEmulator::parse_command("e 1").is_none());
+        assert!(FirmwareEmulator::parse_command("z").is_none());
+    }
+
+    #[test]
+    fn test_responses() {
+        let mut emulator = FirmwareEmulator::new(EmulatorConfig::default());
+        assert_eq!(exchange(&mut emulator, "e\r"), "0 0\r\n");
+        assert_eq!(exchange(&mut emulator, "m 100 100\r"), "OK\r\n");
+        assert_eq!(exchange(&mut emulator, "u 20.5:0:12:10\r"), "OK\r\n");
+        assert_eq!(exchange(&mut emulator, "i\r"), "20.5:0:12:10\r\n");
+        assert_eq!(exchange(&mut emulator, "r\r"), "OK\r\n");
+        assert_eq!(exchange(&mut emulator, "o 100 100\r"), "OK\r\n");
+        assert_eq!(exchange(&mut emulator, "z\r"), "Invalid Command\r\n");
+        assert_eq!(
+            exchange(&mut emulator, "v\r"),
+            format!("{} emuroadxwvi\r\n", env!("CARGO_PKG_VERSION"))
+        );
+
+        let mut emulator = FirmwareEmulator::new(EmulatorConfig {
+            firmware_version: None,
+            ..Default::default()
+        });
+        assert_eq!(exchange(&mut emulator, "v\r"), "Invalid Command\r\n");
+        assert_eq!(exchange(&mut emulator, "i\r"), "Invalid Command\r\n");
+    }
+
+    #[test]
+    fn test_binary_protocol() {
+        use crate::core::comm::binary;
+
+        let mut emulator = FirmwareEmulator::new(EmulatorConfig {
+            protocol: Protocol::Binary,
+            ..Default::default()
+        });
+        emulator.write_all(&binary::encode_packet(42, b"e")).unwrap();
+        let mut buffer = [0; 64];
+        let n = emulator.read(&mut buffer).unwrap();
+        assert_eq!(binary::decode_packet(&buffer[..n]).unwrap(), (42, b"0 0".to_vec()));
+
+        // A corrupted packet is dropped.
+        let mut packet = binary::encode_packet(43, b"r");
+        packet[1] ^= 0x04;
+        emulator.write_all(&packet).unwrap();
+        assert_eq!(
+            emulator.read(&mut buffer).unwrap_err().kind(),
+            std::io::ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn test_pin_io() {
+        let mut emulator = FirmwareEmulator::new(EmulatorConfig::default());
+        emulator.set_pin_value(0, 700);
+        assert_eq!(exchange(&mut emulator, "a 0\r"), "700\r\n");
+        assert_eq!(exchange(&mut emulator, "d 2\r"), "0\r\n");
+        assert_eq!(exchange(&mut emulator, "w 2 1\r"), "OK\r\n");
+        assert_eq!(exchange(&mut emulator, "d 2\r"), "1\r\n");
+        assert_eq!(exchange(&mut emulator, "x 5 200\r"), "OK\r\n");
+        assert_eq!(exchange(&mut emulator, "a 5\r"), "200\r\n");
+    }
+
+    #[test]
+    fn test_response_latency() {
+        let config = EmulatorConfig {
+            processing_latency: Duration::from_millis(20),
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut emulator = FirmwareEmulator::new(config);
+        let start = Instant::now();
+        assert_eq!(exchange(&mut emulator, "e\r"), "0 0\r\n");
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_pipelined_commands() {
+        // 10 bits per byte take 8.3 ms at 1200 baud.
+        let config = EmulatorConfig {
+            baud_rate: 1200,
+            timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let mut emulator = FirmwareEmulator::new(config);
+        let start = Instant::now();
+        emulator.write_all(b"e\re\r").unwrap();
+        let mut buffer = [0; 64];
+        let n = emulator.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"0 0\r\n");
+        // The first command, the processing latency and the first response.
+        assert!(start.elapsed() >= Duration::from_millis(2 * 8 + 1 + 5 * 8));
+        let n = emulator.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"0 0\r\n");
+        // The second response follows the first one.
+        assert!(start.elapsed() >= Duration::from_millis(2 * 8 + 1 + 2 * 5 * 8));
+    }
+
+    #[test]
+    fn test_input_ignored_while_booting() {
+        let config = EmulatorConfig {
+            boot_time: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut emulator = FirmwareEmulator::new(config);
+        emulator.write_all(b"e\r").unwrap();
+        let mut buffer = [0; 8];
+        assert!(emulator.read(&mut buffer).is_err());
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(exchange(&mut emulator, "e\r"), "0 0\r\n");
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let config = EmulatorConfig {
+            timeout: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut emulator = FirmwareEmulator::new(config);
+        let mut buffer = [0; 8];
+        let err = emulator.read(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_motors_reach_target_speed() {
+        let mut emulator = FirmwareEmulator::new(EmulatorConfig::default());
+        exchange(&mut emulator, "m 600 -300\r");
+        // Fast-forward the simulation instead of waiting.
+        let start = emulator.last_update;
+        emulator.advance_to(start + Duration::from_secs(2));
+        let (left_start, right_start) = (emulator.motors[0].encoder_count(), emulator.motors[1].encoder_count());
+        emulator.advance_to(start + Duration::from_secs(3));
+        let left_speed = emulator.motors[0].encoder_count() - left_start;
+        let right_speed = emulator.motors[1].encoder_count() - right_start;
+        assert!((left_speed - 600).abs() <= 30, "left speed: {}", left_speed);
+        assert!((right_speed + 300).abs() <= 15, "right speed: {}", right_speed);
+    }
+
+    #[test]
+    fn test_reset_encoders() {
+        let mut emulator = FirmwareEmulator::new(EmulatorConfig::default());
+        exchange(&mut emulator, "m 600 600\r");
+        let start = emulator.last_update;
+        emulator.advance_to(start + Duration::from_secs(1));
+        assert!(emulator.motors[0].encoder_count() > 0);
+        exchange(&mut emulator, "r\r");
+        assert_eq!(exchange(&mut emulator, "e\r"), "0 0\r\n");
+        // The speed target is cleared as well.
+        assert_eq!(emulator.motors[0].setpoint, 0.0);
+        assert_eq!(emulator.motors[1].setpoint, 0.0);
+    }
+
+    #[test]
+    fn test_motors_raw_pwm() {
+        let mut emulator = FirmwareEmulator::new(EmulatorConfig::default());
+        exchange(&mut emulator, "o 255 -500\r");
+        assert_eq!(emulator.motors[0].pwm, MAX_PWM);
+        assert_eq!(emulator.motors[1].pwm, -MAX_PWM);
+        let start = emulator.last_update;
+        emulator.advance_to(start + Duration::from_secs(1));
+        // Full PWM drives the wheels close to their maximum speed.
+        let max_ticks = EmulatorConfig::default().max_ticks_per_second as i64;
+        assert!(emulator.motors[0].encoder_count() > max_ticks * 8 / 10);
+        assert!(emulator.motors[1].encoder_count() < -max_ticks * 8 / 10);
+        // The raw PWM is also stopped when no motor command arrives.
+        emulator.advance_to(start + Duration::from_secs(4));
+        assert_eq!(emulator.motors[0].pwm, 0.0);
+        assert_eq!(emulator.motors[1].pwm, 0.0);
+    }
+
+    #[test]
+    fn test_motors_auto_stop() {
+        let mut emulator = FirmwareEmulator::new(EmulatorConfig::default());
+        exchange(&mut emulator, "m 600 600\r");
+        let start = emulator.last_update;
+        emulator.advance_to(start + Duration::from_secs(5));
+        assert!(!emulator.moving);
+        let ticks = emulator.motors[0].encoder_count();
+        emulator.advance_to(start + Duration::from_secs(6));
+        assert_eq!(emulator.motors[0].encoder_count(), ticks);
+    }
+}
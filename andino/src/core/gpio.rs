@@ -0,0 +1,140 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Named GPIO pins of the board, e.g. bumpers, a battery voltage divider or status LEDs.
+
+use thiserror::Error;
+
+/// Error type for the GPIO configuration.
+#[derive(Debug, Error, PartialEq)]
+pub enum GpioConfigError {
+    #[error("Invalid GPIO pin configuration: {error}")]
+    /// The pin configuration cannot be parsed.
+    InvalidPinConfig { error: String },
+}
+
+/// How a GPIO pin is used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GpioPinKind {
+    /// Digital input, read as high or low.
+    DigitalInput,
+    /// Analog input, read as a value from 0 to 1023.
+    AnalogInput,
+    /// Digital output, set high or low.
+    DigitalOutput,
+    /// PWM output, set to a value from 0 to 255.
+    AnalogOutput,
+}
+
+impl GpioPinKind {
+    /// Whether the pin is read from.
+    pub fn is_input(&self) -> bool {
+        matches!(self, GpioPinKind::DigitalInput | GpioPinKind::AnalogInput)
+    }
+}
+
+impl std::str::FromStr for GpioPinKind {
+    type Err = GpioConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "digital_input" => Ok(GpioPinKind::DigitalInput),
+            "analog_input" => Ok(GpioPinKind::AnalogInput),
+            "digital_output" => Ok(GpioPinKind::DigitalOutput),
+            "analog_output" => Ok(GpioPinKind::AnalogOutput),
+            _ => Err(GpioConfigError::InvalidPinConfig {
+                error: format!(
+                    "Unknown pin kind '{}', expected one of: digital_input, analog_input, digital_output, analog_output",
+                    s
+                ),
+            }),
+        }
+    }
+}
+
+/// Configuration of a named GPIO pin.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GpioPinConfig {
+    /// The name used to refer to the pin (e.g., "left_bumper").
+    pub name: String,
+    /// The pin number on the board.
+    pub pin: u8,
+    /// How the pin is used.
+    pub kind: GpioPinKind,
+}
+
+impl std::str::FromStr for GpioPinConfig {
+    type Err = GpioConfigError;
+
+    /// Parses a pin configuration with the format `<name>:<kind>:<pin>`, e.g. `left_bumper:digital_input:2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.trim().split(':').collect::<Vec<&str>>();
+        let [name, kind, pin] = fields[..] else {
+            return Err(GpioConfigError::InvalidPinConfig {
+                error: format!("Expected '<name>:<kind>:<pin>', got '{}'", s),
+            });
+        };
+        if name.is_empty() {
+            return Err(GpioConfigError::InvalidPinConfig {
+                error: format!("Empty pin name in '{}'", s),
+            });
+        }
+        let pin = pin.parse::<u8>().map_err(|e| GpioConfigError::InvalidPinConfig {
+            error: format!("Invalid pin number in '{}': {}", s, e),
+        })?;
+        Ok(GpioPinConfig {
+            name: name.to_string(),
+            pin,
+            kind: kind.parse()?,
+        })
+    }
+}
+
+/// Value of a GPIO pin.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GpioValue {
+    /// Value of a digital pin.
+    Digital(bool),
+    /// Value of an analog pin: 0 to 1023 when read, 0 to 255 when written.
+    Analog(u16),
+}
+
+impl GpioValue {
+    /// The value as a number, with digital values mapped to 0.0 and 1.0.
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            GpioValue::Digital(value) => f64::from(u8::from(*value)),
+            GpioValue::Analog(value) => f64::from(*value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gpio_pin_config() {
+        let config = "left_bumper:digital_input:2".parse::<GpioPinConfig>().unwrap();
+        assert_eq!(
+            config,
+            GpioPinConfig {
+                name: "left_bumper".to_string(),
+                pin: 2,
+                kind: GpioPinKind::DigitalInput,
+            }
+        );
+        let config = " battery:analog_input:0".parse::<GpioPinConfig>().unwrap();
+        assert_eq!(config.kind, GpioPinKind::AnalogInput);
+        assert_eq!(config.pin, 0);
+    }
+
+    #[test]
+    fn test_parse_gpio_pin_config_error() {
+        assert!("left_bumper:digital_input".parse::<GpioPinConfig>().is_err());
+        assert!("left_bumper:input:2".parse::<GpioPinConfig>().is_err());
+        assert!("left_bumper:digital_input:300".parse::<GpioPinConfig>().is_err());
+        assert!(":digital_input:2".parse::<GpioPinConfig>().is_err());
+    }
+}
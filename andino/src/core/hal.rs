@@ -1,8 +1,38 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
 use thiserror::Error;
 
-use crate::core::comm::{HwSerialConnection, HwSerialConnectionError};
+#[cfg(feature = "async")]
+pub mod asynchronous;
+mod limits;
+use limits::SpeedLimiter;
+pub use limits::{SpeedClamp, WheelLimits};
+mod reconnect;
+use reconnect::Reconnector;
+pub use reconnect::{ConnectionStatus, ReconnectPolicy};
+pub mod polling;
+pub use polling::{PollingHal, PollingHandle, Sample};
+pub mod robot;
+pub use robot::{Capabilities, RobotHal};
+pub mod supervisor;
+pub use supervisor::{RobotState, SupervisedHal};
+
+use crate::core::comm::{
+    CommStats, FirmwareInfo, HwSerialConnection, HwSerialConnectionError, Protocol, RetryPolicy, SerialCommandKind,
+    SerialCommands, SerialDeviceSelector, SerialRecorder, SerialResponse, Transport,
+};
+use crate::core::gpio::{GpioPinConfig, GpioPinKind, GpioValue};
 
-use crate::core::sensors::{Wheel, WheelState};
+use crate::core::sensors::{RadiansPerSecond, Wheel, WheelCalibration, WheelState};
+
+/// Maximum PWM value accepted by the motor driver of the firmware.
+const MAX_PWM: f64 = 255.0;
+/// Relative difference tolerated between the PID gains set and the ones read back, as the firmware may round them.
+const PID_GAINS_TOLERANCE: f32 = 1e-3;
+/// The commands the HAL cannot work without.
+const REQUIRED_COMMANDS: [SerialCommandKind; 2] =
+    [SerialCommandKind::ReadEncoderValues, SerialCommandKind::SetMotorValues];
 
 /// Error type for hardware abstraction layer (HAL) operations.
 #[derive(Debug, Error)]
@@ -10,19 +40,231 @@ pub enum HalError {
     #[error(transparent)]
     /// Error communicating with the hardware.
     HardwareCommunicationError(#[from] HwSerialConnectionError),
+    #[error("Unknown GPIO pin: {name}")]
+    /// The GPIO pin is not in the configuration.
+    UnknownGpioPin { name: String },
+    #[error("Invalid GPIO operation: {error}")]
+    /// The operation does not match how the GPIO pin is configured.
+    InvalidGpioOperation { error: String },
+    #[error("Hardware disconnected: {error}")]
+    /// The connection to the hardware was lost and has not been reestablished yet.
+    Disconnected { error: String },
+    #[error("Incompatible firmware: {error}")]
+    /// The firmware lacks a command the HAL cannot work without.
+    IncompatibleFirmware { error: String },
+    #[error("Unsupported command: {error}")]
+    /// The firmware does not support the command.
+    UnsupportedCommand { error: String },
+    #[error("Invalid PID gains: {error}")]
+    /// The PID gains cannot be parsed.
+    InvalidPidGains { error: String },
+    #[error("Invalid wheel calibration: {error}")]
+    /// The calibration of a wheel cannot be parsed.
+    InvalidWheelCalibration { error: String },
+    #[error("Invalid wheel limits: {error}")]
+    /// The velocity or acceleration limits of the wheels do not let them move.
+    InvalidWheelLimits { error: String },
+    #[error("PID gains not applied: {error}")]
+    /// The PID gains read back from the firmware differ from the ones set.
+    PidGainsNotApplied { error: String },
+    #[error("Polling failed: {error}")]
+    /// The latest poll of the background thread failed.
+    PollingFailed { error: String },
+    #[error("Emergency stopped: {reason}")]
+    /// The robot is emergency stopped, and does not move until it is reset.
+    EStopped { reason: String },
 }
 
 /// Configuration for the hardware abstraction layer (HAL).
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct HalConfig {
-    /// The serial device to connect to (e.g., "/dev/ttyUSB0").
-    pub serial_device: String,
+    /// The serial device to connect to, by path (e.g., "/dev/ttyUSB0") or by USB IDs.
+    /// It is looked up again on every reconnection, as the device may come back under another name.
+    pub serial_device: SerialDeviceSelector,
     /// The baud rate for the serial connection.
     pub baud_rate: u32,
     /// The timeout for the serial connection in milliseconds.
     pub timeout: u64,
     /// The number of ticks per revolution of the motor.
     pub motor_ticks_per_revolution: u64,
+    /// The calibration of the left wheel. When `None`, the motor drives the wheel directly, with
+    /// `motor_ticks_per_revolution` ticks per revolution.
+    pub left_wheel_calibration: Option<WheelCalibration>,
+    /// The calibration of the right wheel, like the left one.
+    pub right_wheel_calibration: Option<WheelCalibration>,
+    /// The maximum time to wait for the firmware to answer after connecting, in milliseconds.
+    pub ready_timeout: u64,
+    /// The named GPIO pins of the board.
+    pub gpio_pins: Vec<GpioPinConfig>,
+    /// Reconnect to the hardware when the connection is lost. When `None`, a lost connection is not recovered.
+    pub reconnect: Option<ReconnectPolicy>,
+    /// File to record the serial traffic to, to be replayed with a `ReplayTransport`.
+    pub record_file: Option<std::path::PathBuf>,
+    /// How each kind of command is retried after a transient error, e.g. to ride out a dropped encoder read.
+    pub retry_policies: HashMap<SerialCommandKind, RetryPolicy>,
+    /// The wire protocol, which has to match the one of the firmware. The stock firmware speaks ASCII.
+    pub protocol: Protocol,
+    /// The gains of the PID speed controllers, set when the HAL connects. When `None`, the firmware keeps its own.
+    pub pid_gains: Option<PidGains>,
+    /// Time in milliseconds after which the motors are stopped if no new motor command arrived, checked whenever
+    /// the HAL is polled. When `None`, a command applies until the next one.
+    pub command_timeout: Option<u64>,
+    /// The velocity and acceleration limits of the left wheel, applied to the speed commands. When `None`, the speed
+    /// of the wheel is not limited, and when neither wheel has limits it is sent as requested.
+    pub left_wheel_limits: Option<WheelLimits>,
+    /// The velocity and acceleration limits of the right wheel, like the left one.
+    pub right_wheel_limits: Option<WheelLimits>,
+}
+
+impl Default for HalConfig {
+    fn default() -> Self {
+        HalConfig {
+            serial_device: SerialDeviceSelector::default(),
+            baud_rate: 57600,
+            timeout: 3000,
+            motor_ticks_per_revolution: 700,
+            left_wheel_calibration: None,
+            right_wheel_calibration: None,
+            ready_timeout: 5000,
+            gpio_pins: Vec::new(),
+            reconnect: None,
+            record_file: None,
+            retry_policies: HashMap::new(),
+            protocol: Protocol::default(),
+            pid_gains: None,
+            command_timeout: None,
+            left_wheel_limits: None,
+            right_wheel_limits: None,
+        }
+    }
+}
+
+impl HalConfig {
+    /// Gets the calibration of the left and right wheels, falling back to `motor_ticks_per_revolution`.
+    pub fn wheel_calibrations(&self) -> (WheelCalibration, WheelCalibration) {
+        let default_calibration = WheelCalibration::new(self.motor_ticks_per_revolution);
+        (
+            self.left_wheel_calibration.unwrap_or(default_calibration),
+            self.right_wheel_calibration.unwrap_or(default_calibration),
+        )
+    }
+
+    /// Gets the limits of the left and right wheels, with no limits for a wheel without them, or `None` when
+    /// neither wheel has limits.
+    pub fn wheel_limits(&self) -> Option<(WheelLimits, WheelLimits)> {
+        (self.left_wheel_limits.is_some() || self.right_wheel_limits.is_some()).then(|| {
+            (
+                self.left_wheel_limits.unwrap_or_default(),
+                self.right_wheel_limits.unwrap_or_default(),
+            )
+        })
+    }
+
+    // Creates the left and right wheels as calibrated.
+    fn wheels(&self) -> (Wheel, Wheel) {
+        let (left_calibration, right_calibration) = self.wheel_calibrations();
+        (
+            Wheel::with_calibration(left_calibration),
+            Wheel::with_calibration(right_calibration),
+        )
+    }
+}
+
+/// Gains of the PID speed controllers of the firmware.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PidGains {
+    /// Proportional gain.
+    pub kp: f32,
+    /// Integral gain.
+    pub ki: f32,
+    /// Derivative gain.
+    pub kd: f32,
+    /// Output scaling factor.
+    pub ko: f32,
+}
+
+impl PidGains {
+    /// Creates gains for the PID speed controllers, checking they are finite and not negative.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PidGains)` - The gains.
+    /// * `Err(HalError)` - [`HalError::InvalidPidGains`] if any of them is not a finite, non-negative number.
+    pub fn new(kp: f32, ki: f32, kd: f32, ko: f32) -> Result<Self, HalError> {
+        let pid_gains = PidGains { kp, ki, kd, ko };
+        if [kp, ki, kd, ko].iter().all(|gain| gain.is_finite() && *gain >= 0.0) {
+            Ok(pid_gains)
+        } else {
+            Err(HalError::InvalidPidGains {
+                error: format!("The gains must be finite and not negative, got {}", pid_gains),
+            })
+        }
+    }
+}
+
+impl std::str::FromStr for PidGains {
+    type Err = HalError;
+
+    /// Parses the gains as `<kp>:<ki>:<kd>:<ko>`, like the firmware takes them.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || HalError::InvalidPidGains {
+            error: format!("Expected '<kp>:<ki>:<kd>:<ko>', got '{}'", s),
+        };
+        let gains = s
+            .trim()
+            .split(':')
+            .map(|gain| gain.trim().parse::<f32>().ok())
+            .collect::<Option<Vec<f32>>>()
+            .ok_or_else(invalid)?;
+        let [kp, ki, kd, ko] = gains[..] else {
+            return Err(invalid());
+        };
+        PidGains::new(kp, ki, kd, ko)
+    }
+}
+
+impl std::fmt::Display for PidGains {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}:{}", self.kp, self.ki, self.kd, self.ko)
+    }
+}
+
+impl std::str::FromStr for WheelCalibration {
+    type Err = HalError;
+
+    /// Parses the calibration as `<ticks_per_revolution>:<gear_ratio>:<motor_direction>:<encoder_direction>`, with
+    /// the directions being `1`, or `-1` when inverted.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || HalError::InvalidWheelCalibration {
+            error: format!(
+                "Expected '<ticks_per_revolution>:<gear_ratio>:<motor_direction>:<encoder_direction>', got '{}'",
+                s
+            ),
+        };
+        let fields = s.trim().split(':').map(str::trim).collect::<Vec<_>>();
+        let [ticks_per_revolution, gear_ratio, motor_direction, encoder_direction] = fields[..] else {
+            return Err(invalid());
+        };
+        let inverted = |direction: &str| match direction {
+            "1" => Some(false),
+            "-1" => Some(true),
+            _ => None,
+        };
+        Ok(WheelCalibration {
+            ticks_per_revolution: ticks_per_revolution
+                .parse::<u64>()
+                .ok()
+                .filter(|ticks| *ticks > 0)
+                .ok_or_else(invalid)?,
+            gear_ratio: gear_ratio
+                .parse::<f64>()
+                .ok()
+                .filter(|ratio| ratio.is_finite() && *ratio > 0.0)
+                .ok_or_else(invalid)?,
+            motor_inverted: inverted(motor_direction).ok_or_else(invalid)?,
+            encoder_inverted: inverted(encoder_direction).ok_or_else(invalid)?,
+        })
+    }
 }
 
 /// Hardware abstraction layer (HAL) for the robot.
@@ -31,16 +273,32 @@ pub struct HalConfig {
 /// the robot's motors and read sensor values.
 #[derive(Debug)]
 pub struct Hal {
-    /// The serial connection to the hardware.
-    hw_serial_connection: HwSerialConnection,
+    /// The serial connection to the hardware, `None` while it is lost.
+    hw_serial_connection: Option<HwSerialConnection>,
+    /// Reestablishes the connection when it is lost, if reconnecting is enabled.
+    reconnector: Option<Reconnector>,
+    /// The maximum time to wait for the firmware to answer after connecting, in milliseconds.
+    ready_timeout: u64,
+    /// The last PID gains set, to be restored after reconnecting.
+    pid_gains: Option<PidGains>,
+    /// The firmware on the board, read when the HAL is created.
+    firmware_info: FirmwareInfo,
+    /// Statistics of the communication over the connections lost so far.
+    lost_connections_stats: CommStats,
     /// Right wheel instance.
     right_wheel: Wheel,
     /// Left wheel instance.
     left_wheel: Wheel,
+    /// The named GPIO pins of the board.
+    gpio_pins: Vec<GpioPinConfig>,
+    /// Stops the motors when no new motor command arrived for the command timeout.
+    command_watchdog: CommandWatchdog,
+    /// Applies the limits of the wheels to the speed commands, if configured.
+    speed_limiter: Option<SpeedLimiter>,
 }
 
 /// The state of the hardware abstraction layer (HAL).
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct HalState {
     /// The state of the right wheel.
     pub right_wheel_state: WheelState,
@@ -51,6 +309,8 @@ pub struct HalState {
 impl Hal {
     /// Creates a new instance of the hardware abstraction layer (HAL).
     ///
+    /// It waits until the firmware answers, as the board resets when the serial port is opened.
+    ///
     /// # Arguments
     ///  - `hal_config` - The configuration for the HAL.
     ///
@@ -58,15 +318,129 @@ impl Hal {
     ///  - `Ok(Hal)` - A new instance of the HAL.
     /// - `Err(HalError)` - An error if the HAL fails to initialize.
     pub fn new(hal_config: &HalConfig) -> Result<Self, HalError> {
-        let hw_serial_connection =
-            HwSerialConnection::new(&hal_config.serial_device, hal_config.baud_rate, hal_config.timeout)?;
-        Ok(Hal {
-            hw_serial_connection,
-            right_wheel: Wheel::new(hal_config.motor_ticks_per_revolution),
-            left_wheel: Wheel::new(hal_config.motor_ticks_per_revolution),
+        let serial_device = hal_config.serial_device.clone();
+        let baud_rate = hal_config.baud_rate;
+        let timeout = hal_config.timeout;
+        Hal::with_connector(hal_config, move || {
+            HwSerialConnection::new(serial_device.resolve()?, baud_rate, timeout)
         })
     }
 
+    /// Creates a new instance of the HAL that opens its connections to the hardware with the given connector.
+    ///
+    /// The connector is called once to connect and, if `reconnect` is set in the configuration,
+    /// again every time the connection has to be reestablished. The first connection must succeed.
+    ///
+    /// # Arguments
+    ///  - `hal_config` - The configuration for the HAL.
+    ///  - `connector` - Opens a new connection to the hardware.
+    ///
+    /// # Returns
+    ///  - `Ok(Hal)` - A new instance of the HAL.
+    /// - `Err(HalError)` - An error if the HAL fails to initialize.
+    pub fn with_connector(
+        hal_config: &HalConfig,
+        mut connector: impl FnMut() -> Result<HwSerialConnection, HwSerialConnectionError> + Send + 'static,
+    ) -> Result<Self, HalError> {
+        let recorder = Hal::recorder(hal_config)?;
+        let retry_policies = hal_config.retry_policies.clone();
+        let protocol = hal_config.protocol;
+        let mut connector = move || {
+            let mut hw_serial_connection = connector()?;
+            Hal::configure_connection(&mut hw_serial_connection, recorder.as_ref(), &retry_policies, protocol);
+            Ok(hw_serial_connection)
+        };
+        let hw_serial_connection = connector()?;
+        let reconnector = hal_config
+            .reconnect
+            .clone()
+            .map(|policy| Reconnector::new(Box::new(connector), policy));
+        Hal::from_connection(hal_config, hw_serial_connection, reconnector)
+    }
+
+    /// Creates a new instance of the HAL that talks to the hardware over the given transport.
+    ///
+    /// The `serial_device` and `baud_rate` of the configuration are ignored, as the transport is already open.
+    /// The `timeout` still bounds the wait for every response.
+    ///
+    /// # Arguments
+    ///  - `hal_config` - The configuration for the HAL.
+    ///  - `transport` - The transport used to exchange bytes with the hardware.
+    ///
+    /// # Returns
+    ///  - `Ok(Hal)` - A new instance of the HAL.
+    /// - `Err(HalError)` - An error if the HAL fails to initialize.
+    pub fn with_transport(hal_config: &HalConfig, transport: impl Transport + 'static) -> Result<Self, HalError> {
+        let mut hw_serial_connection = HwSerialConnection::from_transport(transport, hal_config.timeout);
+        Hal::configure_connection(
+            &mut hw_serial_connection,
+            Hal::recorder(hal_config)?.as_ref(),
+            &hal_config.retry_policies,
+            hal_config.protocol,
+        );
+        Hal::from_connection(hal_config, hw_serial_connection, None)
+    }
+
+    // Creates the recorder of the serial traffic, if a file to record to is configured.
+    fn recorder(hal_config: &HalConfig) -> Result<Option<SerialRecorder>, HalError> {
+        Ok(hal_config
+            .record_file
+            .as_ref()
+            .map(SerialRecorder::create)
+            .transpose()?)
+    }
+
+    // Sets up a new serial connection with the recorder, the retry policies and the protocol of the configuration.
+    fn configure_connection(
+        hw_serial_connection: &mut HwSerialConnection,
+        recorder: Option<&SerialRecorder>,
+        retry_policies: &HashMap<SerialCommandKind, RetryPolicy>,
+        protocol: Protocol,
+    ) {
+        hw_serial_connection.set_protocol(protocol);
+        if let Some(recorder) = recorder {
+            hw_serial_connection.set_recorder(recorder.clone());
+        }
+        for (command_kind, retry_policy) in retry_policies {
+            hw_serial_connection.set_retry_policy(*command_kind, retry_policy.clone());
+        }
+    }
+
+    // Builds the HAL on top of an established serial connection, once the firmware is ready.
+    fn from_connection(
+        hal_config: &HalConfig,
+        mut hw_serial_connection: HwSerialConnection,
+        reconnector: Option<Reconnector>,
+    ) -> Result<Self, HalError> {
+        let speed_limiter = hal_config
+            .wheel_limits()
+            .map(|wheel_limits| SpeedLimiter::new(wheel_limits, Instant::now()))
+            .transpose()?;
+        hw_serial_connection.wait_until_ready(hal_config.ready_timeout)?;
+        let firmware_info = firmware_info(
+            hw_serial_connection.send_command(SerialCommands::ReadFirmwareInfo),
+            hal_config,
+        )?;
+        let (left_wheel, right_wheel) = hal_config.wheels();
+        let mut hal = Hal {
+            hw_serial_connection: Some(hw_serial_connection),
+            reconnector,
+            ready_timeout: hal_config.ready_timeout,
+            pid_gains: None,
+            firmware_info,
+            lost_connections_stats: CommStats::default(),
+            right_wheel,
+            left_wheel,
+            gpio_pins: hal_config.gpio_pins.clone(),
+            command_watchdog: CommandWatchdog::new(hal_config.command_timeout),
+            speed_limiter,
+        };
+        if let Some(pid_gains) = hal_config.pid_gains {
+            hal.set_pid_gains(pid_gains)?;
+        }
+        Ok(hal)
+    }
+
     /// Reads sensor values and updates the state of the sensors in the HAL.
     ///
     /// This method is called periodically to update the state of the sensors.
@@ -82,18 +456,87 @@ impl Hal {
     /// * `Ok(HalState)` - The state of the HAL after the update.
     /// * `Err(HalError)` - An error if the update fails.
     pub fn poll_state(&mut self, delta_time: f64) -> Result<HalState, HalError> {
+        if self.command_watchdog.expired() {
+            self.stop()?;
+            self.command_watchdog.timed_out();
+        } else if let Some((left_speed, right_speed)) = self.ramp_speed() {
+            let command = motor_speed_command(&self.left_wheel, &self.right_wheel, left_speed, right_speed);
+            self.send_command(command)?;
+        }
         // Poll the state of the wheels and update their state.
         let wheels_state = self.update_wheels_state(delta_time)?;
         // Compose the HAL state.
         let hal_state = HalState {
-            right_wheel_state: wheels_state.0,
-            left_wheel_state: wheels_state.1,
+            left_wheel_state: wheels_state.0,
+            right_wheel_state: wheels_state.1,
         };
         Ok(hal_state)
     }
 
+    /// Sets the speed of the motors, if given, and reads the sensor values, like [`Hal::set_motor_speed`] followed by
+    /// [`Hal::poll_state`] but in a single round trip to the hardware.
+    ///
+    /// Both commands are written at once and their responses read afterwards, which saves the latency of one
+    /// exchange per control cycle. A transient error sends both again if the retry policy of the failed command
+    /// allows it.
+    ///
+    /// # Arguments
+    ///
+    /// * `motor_speed` - The speed of the left and right motors in rads per second, `None` to leave it unchanged.
+    /// * `delta_time` - The time elapsed since the last update in seconds.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(HalState)` - The state of the HAL after the update.
+    /// * `Err(HalError)` - An error if any of the commands fails.
+    pub fn cycle(&mut self, motor_speed: Option<(f64, f64)>, delta_time: f64) -> Result<HalState, HalError> {
+        // A new command, a stop when the last one timed out, or the next step of a ramp.
+        let expired = motor_speed.is_none() && self.command_watchdog.expired();
+        let requested_speed = match motor_speed {
+            Some(_) => motor_speed,
+            None if expired => {
+                if let Some(speed_limiter) = &mut self.speed_limiter {
+                    speed_limiter.stopped(Instant::now());
+                }
+                Some((0.0, 0.0))
+            }
+            None => None,
+        };
+        let commanded_speed = match requested_speed {
+            Some((left_speed, right_speed)) => Some(self.limit_speed(left_speed, right_speed)),
+            None => self.ramp_speed(),
+        };
+        let mut commands = Vec::with_capacity(2);
+        if let Some((left_speed, right_speed)) = commanded_speed {
+            commands.push(motor_speed_command(
+                &self.left_wheel,
+                &self.right_wheel,
+                left_speed,
+                right_speed,
+            ));
+        }
+        commands.push(SerialCommands::ReadEncoderValues);
+        let mut responses = self.send_commands(commands)?;
+        if let Some((left_speed, right_speed)) = requested_speed {
+            self.command_watchdog.commanded(left_speed, right_speed);
+        }
+        if expired {
+            self.command_watchdog.timed_out();
+        }
+        // The encoder read goes last.
+        let (left, right) = encoder_values(responses.remove(responses.len() - 1))?;
+        Ok(HalState {
+            left_wheel_state: self.left_wheel.update(left, delta_time).clone(),
+            right_wheel_state: self.right_wheel.update(right, delta_time).clone(),
+        })
+    }
+
     /// Sets the speed of the motors in rads per second.
     ///
+    /// With [`HalConfig::wheel_limits`], the speed is clamped to the maximum velocity and ramped to within the
+    /// maximum acceleration: the ramp goes on whenever the HAL is polled. [`Hal::last_speed_clamp`] tells how the
+    /// request was limited.
+    ///
     /// # Arguments
     ///
     /// * `left_speed` - The speed of the left motor in rads per second.
@@ -104,24 +547,327 @@ impl Hal {
     /// * `Ok(())` - If the command was sent successfully.
     /// * `Err(HalError)` - An error if the command fails.
     pub fn set_motor_speed(&mut self, left_speed: f64, right_speed: f64) -> Result<(), HalError> {
-        // Convert the speed from rads/sec to ticks/sec using the rads per tick (rpt) of the motor:
-        // ticks/sec = rads/sec * ticks/rads
-        let left_value_target = (left_speed * self.left_wheel.ticks_per_rad() as f64).round() as i64;
-        let right_value_target = (right_speed * self.right_wheel.ticks_per_rad() as f64).round() as i64;
-        log::trace!(
-            "Sending command to set motor speed[ticks per second]: left: {} right: {}",
-            left_value_target,
-            right_value_target
-        );
-        self.hw_serial_connection
-            .send_command(super::comm::SerialCommands::SetMotorValues {
-                left: left_value_target,
-                right: right_value_target,
-            })?;
+        let (left_commanded, right_commanded) = self.limit_speed(left_speed, right_speed);
+        let command = motor_speed_command(&self.left_wheel, &self.right_wheel, left_commanded, right_commanded);
+        self.send_command(command)?;
+        self.command_watchdog.commanded(left_speed, right_speed);
+
+        Ok(())
+    }
 
+    /// Stops the motors at once, regardless of the acceleration limit.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the command was sent successfully.
+    /// * `Err(HalError)` - An error if the command fails.
+    pub fn stop(&mut self) -> Result<(), HalError> {
+        if let Some(speed_limiter) = &mut self.speed_limiter {
+            speed_limiter.stopped(Instant::now());
+        }
+        self.send_command(motor_speed_command(&self.left_wheel, &self.right_wheel, 0.0, 0.0))?;
+        self.command_watchdog.commanded(0.0, 0.0);
         Ok(())
     }
 
+    /// Whether the motors were stopped because no motor command arrived for [`HalConfig::command_timeout`].
+    ///
+    /// It holds until the next motor command, stop or encoder reset.
+    pub fn motors_stopped_by_timeout(&self) -> bool {
+        self.command_watchdog.stopped_by_timeout
+    }
+
+    /// Gets how the latest speed request was limited by [`HalConfig::wheel_limits`].
+    ///
+    /// # Returns
+    ///
+    /// * `Some(&SpeedClamp)` - If the request was clamped to the maximum velocity or is being ramped to.
+    /// * `None` - If it was sent as requested, or there are no limits.
+    pub fn last_speed_clamp(&self) -> Option<&SpeedClamp> {
+        self.speed_limiter.as_ref().and_then(SpeedLimiter::last_clamp)
+    }
+
+    /// Drives the motors in open loop, bypassing the PID speed controllers of the firmware.
+    ///
+    /// The PID controllers stay disabled until the next call to [`Hal::set_motor_speed`].
+    ///
+    /// # Arguments
+    ///
+    /// * `left_duty_cycle` - The duty cycle of the left motor, from -1.0 (full reverse) to 1.0 (full forward).
+    /// * `right_duty_cycle` - The duty cycle of the right motor, from -1.0 (full reverse) to 1.0 (full forward).
+    ///
+    /// Values out of range are clamped.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the command was sent successfully.
+    /// * `Err(HalError)` - An error if the command fails.
+    pub fn set_motor_pwm(&mut self, left_duty_cycle: f64, right_duty_cycle: f64) -> Result<(), HalError> {
+        let command = motor_pwm_command(&self.left_wheel, &self.right_wheel, left_duty_cycle, right_duty_cycle);
+        self.send_command(command)?;
+        self.command_watchdog.commanded(left_duty_cycle, right_duty_cycle);
+
+        Ok(())
+    }
+
+    /// Resets the encoder counts to zero, and the position and velocity of the wheels with them.
+    ///
+    /// The firmware also resets its PID controllers, which clears the speed target of the motors.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the encoders were reset.
+    /// * `Err(HalError)` - An error if the command fails.
+    pub fn reset_encoders(&mut self) -> Result<(), HalError> {
+        self.send_command(SerialCommands::ResetEncoders)?;
+        self.left_wheel.reset();
+        self.right_wheel.reset();
+        self.command_watchdog.reset();
+        if let Some(speed_limiter) = &mut self.speed_limiter {
+            speed_limiter.stopped(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Sets the gains of the PID speed controllers of the firmware.
+    ///
+    /// The firmware must acknowledge them and, if it supports reading them, they are read back to check
+    /// they were applied. The gains are restored when the HAL reconnects to the hardware.
+    ///
+    /// # Arguments
+    ///
+    /// * `pid_gains` - The gains to set.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the gains were applied.
+    /// * `Err(HalError)` - An error if the command fails or the gains read back differ.
+    pub fn set_pid_gains(&mut self, pid_gains: PidGains) -> Result<(), HalError> {
+        let pid_gains = PidGains::new(pid_gains.kp, pid_gains.ki, pid_gains.kd, pid_gains.ko)?;
+        self.send_command(pid_gains_command(&pid_gains))?;
+        if self.firmware_info.supports(SerialCommandKind::ReadPIDValues) {
+            if let Err(err) = check_pid_gains_applied(&pid_gains, &self.read_pid_gains()?) {
+                self.restore_pid_gains();
+                return Err(err);
+            }
+        }
+        self.pid_gains = Some(pid_gains);
+        Ok(())
+    }
+
+    /// Reads the gains of the PID speed controllers of the firmware.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PidGains)` - The gains in use.
+    /// * `Err(HalError)` - An error if the firmware does not support reading them or the command fails.
+    pub fn read_pid_gains(&mut self) -> Result<PidGains, HalError> {
+        Ok(pid_gains(self.send_command(SerialCommands::ReadPIDValues)?)?)
+    }
+
+    /// Gets the version and the capabilities of the firmware on the board.
+    ///
+    /// Commands the firmware does not support fail with [`HalError::UnsupportedCommand`] without being sent.
+    pub fn firmware_info(&self) -> &FirmwareInfo {
+        &self.firmware_info
+    }
+
+    /// Gets the statistics of the communication with the hardware, across reconnections,
+    /// since the HAL was created or the statistics were taken.
+    pub fn comm_stats(&self) -> CommStats {
+        let mut comm_stats = self.lost_connections_stats.clone();
+        if let Some(hw_serial_connection) = &self.hw_serial_connection {
+            comm_stats.merge(hw_serial_connection.stats());
+        }
+        comm_stats
+    }
+
+    /// Takes the statistics of the communication with the hardware, starting over from zero.
+    ///
+    /// Taking them periodically gives the statistics of each period, e.g. to publish them as diagnostics.
+    pub fn take_comm_stats(&mut self) -> CommStats {
+        let mut comm_stats = std::mem::take(&mut self.lost_connections_stats);
+        if let Some(hw_serial_connection) = self.hw_serial_connection.as_mut() {
+            comm_stats.merge(&hw_serial_connection.take_stats());
+        }
+        comm_stats
+    }
+
+    /// Gets the status of the connection to the hardware.
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.reconnector
+            .as_ref()
+            .map_or(ConnectionStatus::Connected, |reconnector| reconnector.status().clone())
+    }
+
+    /// Reads the value of a configured input pin.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the pin in the configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(GpioValue)` - The value of the pin.
+    /// * `Err(HalError)` - An error if the pin is unknown, is not an input or the command fails.
+    pub fn read_gpio(&mut self, name: &str) -> Result<GpioValue, HalError> {
+        let pin_config = find_gpio_pin(&self.gpio_pins, name)?.clone();
+        self.read_gpio_pin(&pin_config)
+    }
+
+    /// Reads the values of all the configured input pins.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(String, GpioValue)>)` - The name and value of every input pin, in configuration order.
+    /// * `Err(HalError)` - An error if any of the commands fails.
+    pub fn read_gpio_inputs(&mut self) -> Result<Vec<(String, GpioValue)>, HalError> {
+        gpio_input_pins(&self.gpio_pins)
+            .into_iter()
+            .map(|pin_config| Ok((pin_config.name.clone(), self.read_gpio_pin(&pin_config)?)))
+            .collect()
+    }
+
+    /// Writes the value of a configured output pin.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the pin in the configuration.
+    /// * `value` - The value to write. It must be digital for digital outputs and analog,
+    ///   from 0 to 255, for analog outputs.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the command was sent successfully.
+    /// * `Err(HalError)` - An error if the pin is unknown, the value does not suit the pin or the command fails.
+    pub fn write_gpio(&mut self, name: &str, value: GpioValue) -> Result<(), HalError> {
+        let command = write_gpio_command(find_gpio_pin(&self.gpio_pins, name)?, value)?;
+        self.send_command(command)?;
+        Ok(())
+    }
+
+    // Sends a command to the hardware, reconnecting first if the connection was lost.
+    fn send_command(&mut self, command: SerialCommands) -> Result<SerialResponse, HalError> {
+        let mut responses = self.send_commands(vec![command])?;
+        Ok(responses.remove(0))
+    }
+
+    // Sends several commands to the hardware at once, reconnecting first if the connection was lost.
+    //
+    // When reconnecting is enabled, a connection that fails is dropped right away, which releases the device
+    // so it can be reopened. Commands sent while the reconnection backoff runs fail fast.
+    fn send_commands(&mut self, commands: Vec<SerialCommands>) -> Result<Vec<SerialResponse>, HalError> {
+        for command in &commands {
+            check_supported(&self.firmware_info, command)?;
+        }
+        if self.hw_serial_connection.is_none() {
+            self.reconnect()?;
+        }
+        let Some(hw_serial_connection) = self.hw_serial_connection.as_mut() else {
+            return Err(HalError::Disconnected {
+                error: "No connection to the hardware".to_string(),
+            });
+        };
+        let result = hw_serial_connection.send_commands(&commands);
+        if let (Err(error), Some(reconnector)) = (&result, self.reconnector.as_mut()) {
+            if error.is_connection_lost() {
+                log::error!("Lost the connection to the hardware: {}", error);
+                if let Some(hw_serial_connection) = self.hw_serial_connection.take() {
+                    self.lost_connections_stats.merge(hw_serial_connection.stats());
+                }
+                reconnector.disconnected(error);
+            }
+        }
+        Ok(result?)
+    }
+
+    // Tries to reestablish the connection to the hardware, if the backoff allows it.
+    fn reconnect(&mut self) -> Result<(), HalError> {
+        let Some(reconnector) = self.reconnector.as_mut() else {
+            return Err(HalError::Disconnected {
+                error: "No connection to the hardware".to_string(),
+            });
+        };
+        if !reconnector.attempt_due() {
+            return Err(HalError::Disconnected {
+                error: format!("Waiting to reconnect ({:?})", reconnector.status()),
+            });
+        }
+        log::info!("Reconnecting to the hardware");
+        let result = reconnector.connect().and_then(|mut hw_serial_connection| {
+            hw_serial_connection.wait_until_ready(self.ready_timeout)?;
+            Hal::restore_state(
+                &mut hw_serial_connection,
+                self.pid_gains.as_ref(),
+                &mut self.left_wheel,
+                &mut self.right_wheel,
+            )?;
+            Ok(hw_serial_connection)
+        });
+        match result {
+            Ok(hw_serial_connection) => {
+                log::info!("Reconnected to the hardware");
+                reconnector.connected();
+                self.hw_serial_connection = Some(hw_serial_connection);
+                Ok(())
+            }
+            Err(error) => {
+                reconnector.attempt_failed(&error);
+                Err(HalError::Disconnected {
+                    error: format!("Reconnection failed: {}", error),
+                })
+            }
+        }
+    }
+
+    // Restores the PID gains on a firmware that restarted and resynchronizes the wheels with its encoder counts.
+    fn restore_state(
+        hw_serial_connection: &mut HwSerialConnection,
+        pid_gains: Option<&PidGains>,
+        left_wheel: &mut Wheel,
+        right_wheel: &mut Wheel,
+    ) -> Result<(), HwSerialConnectionError> {
+        if let Some(pid_gains) = pid_gains {
+            hw_serial_connection.send_command(pid_gains_command(pid_gains))?;
+        }
+        let (left, right) = encoder_values(hw_serial_connection.send_command(SerialCommands::ReadEncoderValues)?)?;
+        left_wheel.resync(left);
+        right_wheel.resync(right);
+        Ok(())
+    }
+
+    // Reads the value of an input pin from the hardware.
+    fn read_gpio_pin(&mut self, pin_config: &GpioPinConfig) -> Result<GpioValue, HalError> {
+        let response = self.send_command(read_gpio_command(pin_config)?)?;
+        gpio_value(pin_config, response)
+    }
+
+    // Sets the gains set before again after the firmware did not apply new ones, so that it and a reconnection keep
+    // using them. If they cannot be set, they are forgotten so that a reconnection does not apply them.
+    fn restore_pid_gains(&mut self) {
+        if let Some(pid_gains) = self.pid_gains {
+            if let Err(err) = self.send_command(pid_gains_command(&pid_gains)) {
+                log::warn!("Failed to restore the PID gains {}: {}", pid_gains, err);
+                self.pid_gains = None;
+            }
+        }
+    }
+
+    // Applies the limits of the wheels to a speed request, returning the speed to command now.
+    fn limit_speed(&mut self, left_speed: f64, right_speed: f64) -> (f64, f64) {
+        match &mut self.speed_limiter {
+            Some(speed_limiter) => speed_limiter.request(left_speed, right_speed, Instant::now()),
+            None => (left_speed, right_speed),
+        }
+    }
+
+    // Gets the next speed to command while ramping to the one requested.
+    fn ramp_speed(&mut self) -> Option<(f64, f64)> {
+        self.speed_limiter
+            .as_mut()
+            .and_then(|speed_limiter| speed_limiter.ramp(Instant::now()))
+    }
+
     /// Updates the state of the wheels by reading the encoder values from the hardware.
     ///
     /// # Arguments
@@ -134,24 +880,282 @@ impl Hal {
         &mut self,
         delta_time: f64,
     ) -> Result<(super::sensors::WheelState, super::sensors::WheelState), HalError> {
-        let response = self
-            .hw_serial_connection
-            .send_command(super::comm::SerialCommands::ReadEncoderValues)?;
-        if let super::comm::SerialResponse::EncoderValues { left, right } = response {
-            Ok((
-                self.left_wheel.update(left, delta_time).clone(),
-                self.right_wheel.update(right, delta_time).clone(),
-            ))
-        } else {
-            Err(HalError::HardwareCommunicationError(
-                HwSerialConnectionError::WrongResponseError {
-                    error: "Invalid response to a ReadEncoderValues from hardware".to_string(),
-                },
-            ))
+        let (left, right) = encoder_values(self.send_command(SerialCommands::ReadEncoderValues)?)?;
+        Ok((
+            self.left_wheel.update(left, delta_time).clone(),
+            self.right_wheel.update(right, delta_time).clone(),
+        ))
+    }
+}
+
+impl Drop for Hal {
+    // Leaves the motors stopped, whatever the application was doing. The response is not waited for.
+    fn drop(&mut self) {
+        if let Some(hw_serial_connection) = &mut self.hw_serial_connection {
+            let command = motor_speed_command(&self.left_wheel, &self.right_wheel, 0.0, 0.0);
+            if let Err(e) = hw_serial_connection.post_command(command) {
+                log::warn!("Failed to stop the motors: {}", e);
+            }
         }
     }
 }
 
+// The commands and the interpretation of the responses below are shared with the async HAL.
+
+// Tells when the motors have to be stopped because no new motor command arrived for the command timeout.
+#[derive(Debug)]
+struct CommandWatchdog {
+    /// Time after which the motors are stopped if no new motor command arrived.
+    command_timeout: Option<Duration>,
+    /// When the motors were last commanded to move, `None` while they are commanded to stand still.
+    motion_commanded_at: Option<Instant>,
+    /// Whether the motors were stopped because the last command expired, until the next command.
+    stopped_by_timeout: bool,
+}
+
+impl CommandWatchdog {
+    fn new(command_timeout: Option<u64>) -> Self {
+        CommandWatchdog {
+            command_timeout: command_timeout.map(Duration::from_millis),
+            motion_commanded_at: None,
+            stopped_by_timeout: false,
+        }
+    }
+
+    // Keeps track of the last command that set the motors in motion.
+    fn commanded(&mut self, left: f64, right: f64) {
+        self.motion_commanded_at = (left != 0.0 || right != 0.0).then(Instant::now);
+        self.stopped_by_timeout = false;
+    }
+
+    // Forgets the last command, once the motors were stopped otherwise.
+    fn reset(&mut self) {
+        self.motion_commanded_at = None;
+        self.stopped_by_timeout = false;
+    }
+
+    // Records that the motors were stopped because the last command expired.
+    fn timed_out(&mut self) {
+        self.motion_commanded_at = None;
+        self.stopped_by_timeout = true;
+    }
+
+    // Whether the motors are in motion after a command older than the command timeout.
+    fn expired(&self) -> bool {
+        match (self.command_timeout, self.motion_commanded_at) {
+            (Some(command_timeout), Some(motion_commanded_at)) if motion_commanded_at.elapsed() > command_timeout => {
+                log::warn!(
+                    "No motor command in {:?}, stopping the motors",
+                    motion_commanded_at.elapsed()
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// Interprets the response to the firmware info query sent when connecting, checking the HAL can work with the firmware.
+//
+// Firmware that does not know the query is assumed to be the legacy one. Transient errors fall back to it too,
+// so a garbled answer does not keep the HAL from starting.
+fn firmware_info(
+    response: Result<SerialResponse, HwSerialConnectionError>,
+    hal_config: &HalConfig,
+) -> Result<FirmwareInfo, HalError> {
+    let firmware_info = match response {
+        Ok(SerialResponse::FirmwareInfo(firmware_info)) => firmware_info,
+        Ok(response) => {
+            log::info!(
+                "The firmware does not report its version ({:?}), assuming a legacy one",
+                response
+            );
+            FirmwareInfo::legacy()
+        }
+        Err(error) if error.is_transient() => {
+            log::warn!("Cannot read the firmware version ({}), assuming a legacy one", error);
+            FirmwareInfo::legacy()
+        }
+        Err(error) => return Err(error.into()),
+    };
+    log::info!("Firmware: {}", firmware_info);
+    if let Some(missing) = REQUIRED_COMMANDS
+        .iter()
+        .find(|command_kind| !firmware_info.supports(**command_kind))
+    {
+        return Err(HalError::IncompatibleFirmware {
+            error: format!("The firmware ({}) does not support {:?}", firmware_info, missing),
+        });
+    }
+    if let Some(encoder_ticks_per_revolution) = firmware_info.encoder_ticks_per_revolution {
+        let (left_calibration, right_calibration) = hal_config.wheel_calibrations();
+        for (side, calibration) in [("left", left_calibration), ("right", right_calibration)] {
+            if encoder_ticks_per_revolution != calibration.ticks_per_revolution {
+                log::warn!(
+                    "The firmware is configured with {} encoder ticks per revolution, but the HAL with {} for the {} wheel",
+                    encoder_ticks_per_revolution,
+                    calibration.ticks_per_revolution,
+                    side
+                );
+            }
+        }
+    }
+    Ok(firmware_info)
+}
+
+// Fails if the firmware does not support the command.
+fn check_supported(firmware_info: &FirmwareInfo, command: &SerialCommands) -> Result<(), HalError> {
+    if firmware_info.supports(command.kind()) {
+        Ok(())
+    } else {
+        Err(HalError::UnsupportedCommand {
+            error: format!("The firmware ({}) does not support {:?}", firmware_info, command.kind()),
+        })
+    }
+}
+
+// Builds the command that sets the speed of the motors, given in rads per second.
+fn motor_speed_command(left_wheel: &Wheel, right_wheel: &Wheel, left_speed: f64, right_speed: f64) -> SerialCommands {
+    // Convert the speed from rads/sec to ticks/sec, rounding only the result sent to the firmware.
+    let left_value_target = left_wheel.motor_speed(RadiansPerSecond(left_speed)).0.round() as i64;
+    let right_value_target = right_wheel.motor_speed(RadiansPerSecond(right_speed)).0.round() as i64;
+    log::trace!(
+        "Sending command to set motor speed[ticks per second]: left: {} right: {}",
+        left_value_target,
+        right_value_target
+    );
+    SerialCommands::SetMotorValues {
+        left: left_value_target,
+        right: right_value_target,
+    }
+}
+
+// Builds the command that sets the PWM of the motors, given as duty cycles clamped to [-1.0, 1.0].
+fn motor_pwm_command(
+    left_wheel: &Wheel,
+    right_wheel: &Wheel,
+    left_duty_cycle: f64,
+    right_duty_cycle: f64,
+) -> SerialCommands {
+    let left_pwm = (left_wheel.motor_direction() * left_duty_cycle.clamp(-1.0, 1.0) * MAX_PWM).round() as i64;
+    let right_pwm = (right_wheel.motor_direction() * right_duty_cycle.clamp(-1.0, 1.0) * MAX_PWM).round() as i64;
+    log::trace!(
+        "Sending command to set motor PWM: left: {} right: {}",
+        left_pwm,
+        right_pwm
+    );
+    SerialCommands::SetMotorPWMValues {
+        left: left_pwm,
+        right: right_pwm,
+    }
+}
+
+// Gets the encoder counts from the response to a ReadEncoderValues.
+fn encoder_values(response: SerialResponse) -> Result<(i64, i64), HwSerialConnectionError> {
+    match response {
+        SerialResponse::EncoderValues { left, right } => Ok((left, right)),
+        response => Err(HwSerialConnectionError::UnexpectedResponseError {
+            error: "Invalid response to a ReadEncoderValues from hardware".to_string(),
+            response,
+        }),
+    }
+}
+
+// Looks up a configured GPIO pin by name.
+fn find_gpio_pin<'a>(gpio_pins: &'a [GpioPinConfig], name: &str) -> Result<&'a GpioPinConfig, HalError> {
+    gpio_pins
+        .iter()
+        .find(|pin_config| pin_config.name == name)
+        .ok_or_else(|| HalError::UnknownGpioPin { name: name.to_string() })
+}
+
+// The configured input pins, in configuration order.
+fn gpio_input_pins(gpio_pins: &[GpioPinConfig]) -> Vec<GpioPinConfig> {
+    gpio_pins
+        .iter()
+        .filter(|pin_config| pin_config.kind.is_input())
+        .cloned()
+        .collect()
+}
+
+// Builds the command that reads an input pin.
+fn read_gpio_command(pin_config: &GpioPinConfig) -> Result<SerialCommands, HalError> {
+    match pin_config.kind {
+        GpioPinKind::DigitalInput => Ok(SerialCommands::ReadDigitalPin { pin: pin_config.pin }),
+        GpioPinKind::AnalogInput => Ok(SerialCommands::ReadAnalogPin { pin: pin_config.pin }),
+        kind => Err(HalError::InvalidGpioOperation {
+            error: format!("Cannot read pin '{}' configured as {:?}", pin_config.name, kind),
+        }),
+    }
+}
+
+// Gets the value of a pin from the response to its read.
+fn gpio_value(pin_config: &GpioPinConfig, response: SerialResponse) -> Result<GpioValue, HalError> {
+    match response {
+        SerialResponse::DigitalValue { value } => Ok(GpioValue::Digital(value)),
+        SerialResponse::AnalogValue { value } => Ok(GpioValue::Analog(value)),
+        response => Err(HalError::HardwareCommunicationError(
+            HwSerialConnectionError::UnexpectedResponseError {
+                error: format!("Invalid response to a read of pin '{}' from hardware", pin_config.name),
+                response,
+            },
+        )),
+    }
+}
+
+// Builds the command that writes an output pin, checking the value suits it.
+fn write_gpio_command(pin_config: &GpioPinConfig, value: GpioValue) -> Result<SerialCommands, HalError> {
+    let name = &pin_config.name;
+    match (pin_config.kind, value) {
+        (GpioPinKind::DigitalOutput, GpioValue::Digital(value)) => Ok(SerialCommands::WriteDigitalPin {
+            pin: pin_config.pin,
+            value,
+        }),
+        (GpioPinKind::AnalogOutput, GpioValue::Analog(value)) => Ok(SerialCommands::WriteAnalogPin {
+            pin: pin_config.pin,
+            value: u8::try_from(value).map_err(|_| HalError::InvalidGpioOperation {
+                error: format!("Value {} out of range [0, 255] for analog output '{}'", value, name),
+            })?,
+        }),
+        (kind, value) => Err(HalError::InvalidGpioOperation {
+            error: format!("Cannot write {:?} to pin '{}' configured as {:?}", value, name, kind),
+        }),
+    }
+}
+
+// Interprets the response to a read of the PID gains.
+fn pid_gains(response: SerialResponse) -> Result<PidGains, HwSerialConnectionError> {
+    match response {
+        SerialResponse::PIDValues { kp, ki, kd, ko } => Ok(PidGains { kp, ki, kd, ko }),
+        response => Err(HwSerialConnectionError::UnexpectedResponseError {
+            error: "Invalid response to a ReadPIDValues from hardware".to_string(),
+            response,
+        }),
+    }
+}
+
+// Checks that the PID gains read back from the firmware match the ones set, up to rounding.
+fn check_pid_gains_applied(set: &PidGains, read: &PidGains) -> Result<(), HalError> {
+    let matches = |set: f32, read: f32| (set - read).abs() <= PID_GAINS_TOLERANCE * set.abs().max(1.0);
+    if matches(set.kp, read.kp) && matches(set.ki, read.ki) && matches(set.kd, read.kd) && matches(set.ko, read.ko) {
+        Ok(())
+    } else {
+        Err(HalError::PidGainsNotApplied {
+            error: format!("Set {}, but the firmware uses {}", set, read),
+        })
+    }
+}
+
+// Builds the command that sets the given PID gains.
+fn pid_gains_command(pid_gains: &PidGains) -> SerialCommands {
+    SerialCommands::SetPIDValues {
+        kp: pid_gains.kp,
+        ki: pid_gains.ki,
+        kd: pid_gains.kd,
+        ko: pid_gains.ko,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -159,10 +1163,11 @@ mod tests {
     #[test]
     fn test_hal_new_failing() {
         let hal_config = HalConfig {
-            serial_device: String::from("/hope/invalid/path"),
+            serial_device: SerialDeviceSelector::Path(String::from("/hope/invalid/path")),
             baud_rate: 57600,
             timeout: 3000,
             motor_ticks_per_revolution: 360,
+            ..Default::default()
         };
         let hal = Hal::new(&hal_config);
         assert!(hal.is_err());
@@ -170,4 +1175,800 @@ mod tests {
             assert!(matches!(err, HalError::HardwareCommunicationError(_)));
         }
     }
+
+    #[test]
+    fn test_hal_poll_state_over_memory_pipe() {
+        use crate::core::comm::transport::MemoryPipe;
+        use std::io::{Read, Write};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            motor_ticks_per_revolution: 1000,
+            ..Default::default()
+        };
+        let (hal_end, mut firmware_end) = MemoryPipe::pair(1000);
+        let firmware = std::thread::spawn(move || {
+            // Readiness probe, firmware info query of a legacy firmware and encoder read.
+            for (command, response) in [
+                (b"e\r", b"0 0\r\n".as_slice()),
+                (b"v\r", b"Invalid Command\r\n".as_slice()),
+                (b"e\r", b"500 -500\r\n".as_slice()),
+            ] {
+                let mut buffer = [0; 32];
+                let n = firmware_end.read(&mut buffer).unwrap();
+                assert_eq!(&buffer[..n], command);
+                firmware_end.write_all(response).unwrap();
+            }
+        });
+        let mut hal = Hal::with_transport(&hal_config, hal_end).unwrap();
+        let state = hal.poll_state(1.0).unwrap();
+        firmware.join().unwrap();
+        assert_eq!(hal.firmware_info(), &FirmwareInfo::legacy());
+        assert_eq!(state.left_wheel_state.position, std::f64::consts::PI);
+        assert_eq!(state.right_wheel_state.position, -std::f64::consts::PI);
+    }
+
+    #[test]
+    fn test_hal_firmware_info() {
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            ..Default::default()
+        };
+        let hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(EmulatorConfig::default())).unwrap();
+        assert_eq!(hal.firmware_info().version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+        assert!(hal.firmware_info().supports(SerialCommandKind::SetMotorPWMValues));
+    }
+
+    #[test]
+    fn test_hal_pid_gains() {
+        use crate::core::comm::transport::MemoryPipe;
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+        use std::io::{Read, Write};
+
+        let configured = "30:0.5:10:10".parse::<PidGains>().unwrap();
+        assert_eq!(configured.to_string(), "30:0.5:10:10");
+        assert!(matches!(
+            "30:0:10".parse::<PidGains>(),
+            Err(HalError::InvalidPidGains { .. })
+        ));
+        assert!(matches!(
+            "30:zero:10:10".parse::<PidGains>(),
+            Err(HalError::InvalidPidGains { .. })
+        ));
+
+        // The configured gains are applied when the HAL is created.
+        let hal_config = HalConfig {
+            timeout: 1000,
+            pid_gains: Some(configured),
+            ..Default::default()
+        };
+        let mut hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(EmulatorConfig::default())).unwrap();
+        assert_eq!(hal.read_pid_gains().unwrap(), configured);
+        let tuned = PidGains {
+            kp: 25.0,
+            ki: 0.25,
+            kd: 12.0,
+            ko: 10.0,
+        };
+        hal.set_pid_gains(tuned).unwrap();
+        assert_eq!(hal.read_pid_gains().unwrap(), tuned);
+
+        // A legacy firmware cannot read them, but they can still be set.
+        let emulator_config = EmulatorConfig {
+            firmware_version: None,
+            ..Default::default()
+        };
+        let mut hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(emulator_config)).unwrap();
+        assert!(matches!(hal.read_pid_gains(), Err(HalError::UnsupportedCommand { .. })));
+        hal.set_pid_gains(tuned).unwrap();
+
+        // A firmware that acknowledges the gains without applying them.
+        let (hal_end, mut firmware_end) = MemoryPipe::pair(1000);
+        let firmware = std::thread::spawn(move || {
+            for response in [
+                b"0 0\r\n".as_slice(),
+                b"0.1.0 emui\r\n".as_slice(),
+                b"OK\r\n".as_slice(),
+                b"20:0:12:10\r\n".as_slice(),
+            ] {
+                let mut buffer = [0; 32];
+                let _ = firmware_end.read(&mut buffer).unwrap();
+                firmware_end.write_all(response).unwrap();
+            }
+        });
+        assert!(matches!(
+            Hal::with_transport(&hal_config, hal_end),
+            Err(HalError::PidGainsNotApplied { .. })
+        ));
+        firmware.join().unwrap();
+    }
+
+    #[test]
+    fn test_hal_restores_pid_gains_not_applied() {
+        use crate::core::comm::transport::MemoryPipe;
+        use std::io::{Read, Write};
+
+        assert!(matches!(
+            PidGains::new(30.0, f32::NAN, 10.0, 10.0),
+            Err(HalError::InvalidPidGains { .. })
+        ));
+        assert!(matches!(
+            "30:0:inf:10".parse::<PidGains>(),
+            Err(HalError::InvalidPidGains { .. })
+        ));
+        assert!(matches!(
+            "30:0:-10:10".parse::<PidGains>(),
+            Err(HalError::InvalidPidGains { .. })
+        ));
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            ..Default::default()
+        };
+        let (hal_end, mut firmware_end) = MemoryPipe::pair(1000);
+        let firmware = std::thread::spawn(move || {
+            let mut commands = Vec::new();
+            for response in [
+                b"0 0\r\n".as_slice(),
+                b"0.1.0 emui\r\n".as_slice(),
+                // The first gains are applied.
+                b"OK\r\n".as_slice(),
+                b"30:0:10:10\r\n".as_slice(),
+                // The second ones are not, and the first ones are set again.
+                b"OK\r\n".as_slice(),
+                b"30:0:10:10\r\n".as_slice(),
+                b"OK\r\n".as_slice(),
+            ] {
+                let mut buffer = [0; 32];
+                let n = firmware_end.read(&mut buffer).unwrap();
+                commands.push(String::from_utf8_lossy(&buffer[..n]).to_string());
+                firmware_end.write_all(response).unwrap();
+            }
+            commands
+        });
+        let mut hal = Hal::with_transport(&hal_config, hal_end).unwrap();
+        let applied = "30:0:10:10".parse::<PidGains>().unwrap();
+        hal.set_pid_gains(applied).unwrap();
+        assert!(matches!(
+            hal.set_pid_gains("20:0:12:10".parse::<PidGains>().unwrap()),
+            Err(HalError::PidGainsNotApplied { .. })
+        ));
+        // A reconnection applies the gains in use.
+        assert_eq!(hal.pid_gains, Some(applied));
+        // Gains built by hand are checked too, without being sent.
+        let invalid = PidGains {
+            kp: f32::INFINITY,
+            ..applied
+        };
+        assert!(matches!(
+            hal.set_pid_gains(invalid),
+            Err(HalError::InvalidPidGains { .. })
+        ));
+        assert_eq!(
+            firmware.join().unwrap()[4..],
+            ["u 20:0:12:10\r", "i\r", "u 30:0:10:10\r"]
+        );
+    }
+
+    #[test]
+    fn test_hal_checks_firmware_commands() {
+        use crate::core::comm::transport::MemoryPipe;
+        use std::io::{Read, Write};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            ..Default::default()
+        };
+        let firmware = |firmware_info: &'static [u8]| {
+            let (hal_end, mut firmware_end) = MemoryPipe::pair(1000);
+            std::thread::spawn(move || {
+                for response in [b"0 0\r\n".as_slice(), firmware_info] {
+                    firmware_end.read_exact(&mut [0; 2]).unwrap();
+                    firmware_end.write_all(response).unwrap();
+                }
+                firmware_end
+            });
+            hal_end
+        };
+        // No speed command, which the HAL cannot do without.
+        assert!(matches!(
+            Hal::with_transport(&hal_config, firmware(b"0.1.0 eo\r\n")),
+            Err(HalError::IncompatibleFirmware { .. })
+        ));
+        // No PWM command, which is refused without being sent.
+        let mut hal = Hal::with_transport(&hal_config, firmware(b"0.1.0 em 700\r\n")).unwrap();
+        assert_eq!(hal.firmware_info().encoder_ticks_per_revolution, Some(700));
+        assert!(matches!(
+            hal.set_motor_pwm(0.5, 0.5),
+            Err(HalError::UnsupportedCommand { .. })
+        ));
+    }
+
+    #[test]
+    fn test_hal_drives_emulated_firmware() {
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            motor_ticks_per_revolution: 700,
+            ..Default::default()
+        };
+        let mut hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(EmulatorConfig::default())).unwrap();
+        let state = hal.poll_state(0.1).unwrap();
+        assert_eq!(state.left_wheel_state.position, 0.0);
+        assert_eq!(state.right_wheel_state.position, 0.0);
+
+        // Half a revolution per second forward on the left wheel and backwards on the right one.
+        let target_speed = std::f64::consts::PI;
+        hal.set_motor_speed(target_speed, -target_speed).unwrap();
+        // Let the PID settle.
+        std::thread::sleep(std::time::Duration::from_millis(1500));
+        let last_time = std::time::Instant::now();
+        hal.poll_state(1.0).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        let state = hal.poll_state(last_time.elapsed().as_secs_f64()).unwrap();
+        assert!((state.left_wheel_state.velocity - target_speed).abs() < 0.1 * target_speed);
+        assert!((state.right_wheel_state.velocity + target_speed).abs() < 0.1 * target_speed);
+        assert!(state.left_wheel_state.position > 0.0);
+        assert!(state.right_wheel_state.position < 0.0);
+
+        hal.set_motor_speed(0.0, 0.0).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+        hal.poll_state(1.0).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let state = hal.poll_state(0.2).unwrap();
+        assert_eq!(state.left_wheel_state.velocity, 0.0);
+        assert_eq!(state.right_wheel_state.velocity, 0.0);
+    }
+
+    #[test]
+    fn test_hal_cycle() {
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            motor_ticks_per_revolution: 700,
+            ..Default::default()
+        };
+        let mut hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(EmulatorConfig::default())).unwrap();
+        hal.take_comm_stats();
+
+        let target_speed = std::f64::consts::PI;
+        let state = hal.cycle(Some((target_speed, -target_speed)), 0.1).unwrap();
+        assert_eq!(state.left_wheel_state.position, 0.0);
+
+        // Without a speed, only the encoders are read.
+        hal.cycle(None, 0.1).unwrap();
+        let comm_stats = hal.take_comm_stats();
+        assert_eq!(comm_stats.commands[&SerialCommandKind::SetMotorValues].successes, 1);
+        assert_eq!(comm_stats.commands[&SerialCommandKind::ReadEncoderValues].successes, 2);
+    }
+
+    #[test]
+    fn test_hal_command_timeout() {
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            command_timeout: Some(100),
+            ..Default::default()
+        };
+        let mut hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(EmulatorConfig::default())).unwrap();
+        let motor_commands = |hal: &Hal| hal.comm_stats().commands[&SerialCommandKind::SetMotorValues].exchanges;
+        hal.set_motor_speed(5.0, 5.0).unwrap();
+        hal.poll_state(0.01).unwrap();
+        assert_eq!(motor_commands(&hal), 1);
+        // Without a fresh command, the motors are stopped once, on the next poll.
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        hal.poll_state(0.15).unwrap();
+        assert_eq!(motor_commands(&hal), 2);
+        hal.cycle(None, 0.01).unwrap();
+        assert_eq!(motor_commands(&hal), 2);
+        // Same along with the encoder read of a cycle.
+        hal.cycle(Some((1.0, -1.0)), 0.01).unwrap();
+        assert_eq!(motor_commands(&hal), 3);
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        hal.cycle(None, 0.15).unwrap();
+        assert_eq!(motor_commands(&hal), 4);
+        hal.poll_state(0.01).unwrap();
+        assert_eq!(motor_commands(&hal), 4);
+    }
+
+    #[test]
+    fn test_hal_wheel_limits() {
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+
+        let wheel_limits = WheelLimits {
+            max_velocity: 4.0,
+            max_acceleration: 20.0,
+        };
+        let hal_config = HalConfig {
+            timeout: 1000,
+            left_wheel_limits: Some(wheel_limits),
+            right_wheel_limits: Some(wheel_limits),
+            ..Default::default()
+        };
+        let invalid_config = HalConfig {
+            right_wheel_limits: Some(WheelLimits {
+                max_velocity: 4.0,
+                max_acceleration: 0.0,
+            }),
+            ..hal_config.clone()
+        };
+        assert!(matches!(
+            Hal::with_transport(&invalid_config, FirmwareEmulator::new(EmulatorConfig::default())),
+            Err(HalError::InvalidWheelLimits { .. })
+        ));
+        let mut hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(EmulatorConfig::default())).unwrap();
+        let motor_commands = |hal: &Hal| hal.comm_stats().commands[&SerialCommandKind::SetMotorValues].exchanges;
+        hal.set_motor_speed(10.0, -1.0).unwrap();
+        let clamp = hal.last_speed_clamp().unwrap();
+        assert_eq!(clamp.requested, (10.0, -1.0));
+        assert_eq!(clamp.limited, (4.0, -1.0));
+        assert!(clamp.velocity_clamped);
+        assert!(clamp.ramping);
+
+        // The speed ramps up over the following cycles, then is left alone.
+        for _ in 0..30 {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            hal.cycle(None, 0.02).unwrap();
+        }
+        let ramp_commands = motor_commands(&hal);
+        assert!(ramp_commands > 5);
+        hal.cycle(None, 0.02).unwrap();
+        assert_eq!(motor_commands(&hal), ramp_commands);
+
+        // Stopping skips the ramp.
+        hal.stop().unwrap();
+        assert_eq!(motor_commands(&hal), ramp_commands + 1);
+        hal.poll_state(0.01).unwrap();
+        assert_eq!(motor_commands(&hal), ramp_commands + 1);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        hal.cycle(Some((1.0, 1.0)), 0.1).unwrap();
+        assert!(!hal.last_speed_clamp().unwrap().velocity_clamped);
+    }
+
+    #[test]
+    fn test_hal_stops_motors_on_drop() {
+        use crate::core::comm::transport::MemoryPipe;
+        use std::io::{Read, Write};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            ..Default::default()
+        };
+        let (hal_end, mut firmware_end) = MemoryPipe::pair(1000);
+        let firmware = std::thread::spawn(move || {
+            // Readiness probe, firmware info query of a legacy firmware and speed command.
+            for response in [
+                b"0 0\r\n".as_slice(),
+                b"Invalid Command\r\n".as_slice(),
+                b"OK\r\n".as_slice(),
+            ] {
+                let mut buffer = [0; 32];
+                let _ = firmware_end.read(&mut buffer).unwrap();
+                firmware_end.write_all(response).unwrap();
+            }
+            firmware_end
+        });
+        let mut hal = Hal::with_transport(&hal_config, hal_end).unwrap();
+        hal.set_motor_speed(5.0, 5.0).unwrap();
+        let mut firmware_end = firmware.join().unwrap();
+        drop(hal);
+        let mut buffer = [0; 32];
+        let n = firmware_end.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"m 0 0\r");
+    }
+
+    #[test]
+    fn test_hal_binary_protocol() {
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            motor_ticks_per_revolution: 700,
+            protocol: Protocol::Binary,
+            ..Default::default()
+        };
+        let emulator = FirmwareEmulator::new(EmulatorConfig {
+            protocol: Protocol::Binary,
+            ..Default::default()
+        });
+        let mut hal = Hal::with_transport(&hal_config, emulator).unwrap();
+        assert!(hal.firmware_info().version.is_some());
+        hal.set_motor_pwm(0.5, -0.5).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let state = hal.poll_state(0.2).unwrap();
+        assert!(state.left_wheel_state.position > 0.0);
+        assert!(state.right_wheel_state.position < 0.0);
+    }
+
+    #[test]
+    fn test_hal_reset_encoders_and_pwm() {
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            motor_ticks_per_revolution: 700,
+            ..Default::default()
+        };
+        let mut hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(EmulatorConfig::default())).unwrap();
+        hal.set_motor_pwm(0.5, -0.5).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        let state = hal.poll_state(0.3).unwrap();
+        assert!(state.left_wheel_state.position > 0.0);
+        assert!(state.right_wheel_state.position < 0.0);
+
+        hal.set_motor_pwm(0.0, 0.0).unwrap();
+        hal.reset_encoders().unwrap();
+        let state = hal.poll_state(0.1).unwrap();
+        // Only the ticks counted while the wheels spin down remain, at most ~70 ticks (0.63 rad).
+        assert!(state.left_wheel_state.position >= 0.0 && state.left_wheel_state.position < 0.7);
+        assert!(state.right_wheel_state.position <= 0.0 && state.right_wheel_state.position > -0.7);
+    }
+
+    #[test]
+    fn test_hal_wheel_calibration() {
+        let mirrored = "700:1:-1:-1".parse::<WheelCalibration>().unwrap();
+        assert!(mirrored.motor_inverted && mirrored.encoder_inverted);
+        assert!(matches!(
+            "700:0:1:1".parse::<WheelCalibration>(),
+            Err(HalError::InvalidWheelCalibration { .. })
+        ));
+        assert!(matches!(
+            "700:1:0:1".parse::<WheelCalibration>(),
+            Err(HalError::InvalidWheelCalibration { .. })
+        ));
+
+        // A mirrored left wheel, and a right one geared down by two.
+        let hal_config = HalConfig {
+            left_wheel_calibration: Some(mirrored),
+            right_wheel_calibration: Some(WheelCalibration {
+                gear_ratio: 2.0,
+                ..WheelCalibration::new(350)
+            }),
+            ..Default::default()
+        };
+        let (mut left_wheel, mut right_wheel) = hal_config.wheels();
+        // The mirrored motor is commanded backwards to turn its wheel forwards, the geared one twice as fast.
+        assert!(matches!(
+            motor_speed_command(&left_wheel, &right_wheel, 3.0, 3.0),
+            SerialCommands::SetMotorValues { left: -334, right: 334 }
+        ));
+        assert!(matches!(
+            motor_pwm_command(&left_wheel, &right_wheel, 0.5, 0.5),
+            SerialCommands::SetMotorPWMValues { left: -128, right: 128 }
+        ));
+        // A revolution forwards of each wheel, counted down by the mirrored encoder and over two motor
+        // revolutions by the geared one.
+        let full_turn = 2.0 * std::f64::consts::PI;
+        let state = left_wheel.update(-700, 1.0);
+        assert!((state.position - full_turn).abs() < 1e-9);
+        assert!((state.velocity - full_turn).abs() < 1e-9);
+        let state = right_wheel.update(700, 1.0);
+        assert!((state.position - full_turn).abs() < 1e-9);
+        assert!((state.velocity - full_turn).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_hal_gpio() {
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            gpio_pins: vec![
+                "left_bumper:digital_input:2".parse().unwrap(),
+                "battery:analog_input:0".parse().unwrap(),
+                "status_led:digital_output:13".parse().unwrap(),
+                "buzzer:analog_output:5".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+        let mut emulator = FirmwareEmulator::new(EmulatorConfig::default());
+        emulator.set_pin_value(2, 1);
+        emulator.set_pin_value(0, 800);
+        let mut hal = Hal::with_transport(&hal_config, emulator).unwrap();
+
+        assert_eq!(hal.read_gpio("left_bumper").unwrap(), GpioValue::Digital(true));
+        assert_eq!(
+            hal.read_gpio_inputs().unwrap(),
+            vec![
+                ("left_bumper".to_string(), GpioValue::Digital(true)),
+                ("battery".to_string(), GpioValue::Analog(800)),
+            ]
+        );
+        hal.write_gpio("status_led", GpioValue::Digital(true)).unwrap();
+        hal.write_gpio("buzzer", GpioValue::Analog(128)).unwrap();
+
+        assert!(matches!(hal.read_gpio("missing"), Err(HalError::UnknownGpioPin { .. })));
+        assert!(matches!(
+            hal.read_gpio("status_led"),
+            Err(HalError::InvalidGpioOperation { .. })
+        ));
+        assert!(matches!(
+            hal.write_gpio("status_led", GpioValue::Analog(1)),
+            Err(HalError::InvalidGpioOperation { .. })
+        ));
+        assert!(matches!(
+            hal.write_gpio("buzzer", GpioValue::Analog(256)),
+            Err(HalError::InvalidGpioOperation { .. })
+        ));
+    }
+
+    /// Emulated firmware whose USB cable can be unplugged.
+    #[derive(Debug)]
+    struct UnpluggableFirmware {
+        emulator: crate::core::emulator::FirmwareEmulator,
+        plugged: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl UnpluggableFirmware {
+        fn check_plugged(&self) -> std::io::Result<()> {
+            if self.plugged.load(std::sync::atomic::Ordering::SeqCst) {
+                Ok(())
+            } else {
+                Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Device unplugged"))
+            }
+        }
+    }
+
+    impl std::io::Read for UnpluggableFirmware {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.check_plugged()?;
+            self.emulator.read(buf)
+        }
+    }
+
+    impl std::io::Write for UnpluggableFirmware {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.check_plugged()?;
+            self.emulator.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.emulator.flush()
+        }
+    }
+
+    impl Transport for UnpluggableFirmware {
+        fn clear_input_buffer(&mut self) -> std::io::Result<()> {
+            self.check_plugged()?;
+            self.emulator.clear_input_buffer()
+        }
+    }
+
+    #[test]
+    fn test_hal_reconnects() {
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            motor_ticks_per_revolution: 700,
+            reconnect: Some(ReconnectPolicy {
+                initial_backoff: 50,
+                max_backoff: 200,
+            }),
+            ..Default::default()
+        };
+        let plugged = Arc::new(AtomicBool::new(true));
+        let connector_plugged = Arc::clone(&plugged);
+        // Every connection reaches a freshly booted firmware, as opening the port resets the board.
+        let mut hal = Hal::with_connector(&hal_config, move || {
+            if !connector_plugged.load(Ordering::SeqCst) {
+                return Err(HwSerialConnectionError::SerialPortConnectionError {
+                    error: "No such device".to_string(),
+                });
+            }
+            let firmware = UnpluggableFirmware {
+                emulator: FirmwareEmulator::new(EmulatorConfig::default()),
+                plugged: Arc::clone(&connector_plugged),
+            };
+            Ok(HwSerialConnection::from_transport(firmware, 1000))
+        })
+        .unwrap();
+        hal.set_pid_gains(PidGains {
+            kp: 20.0,
+            ki: 0.0,
+            kd: 12.0,
+            ko: 10.0,
+        })
+        .unwrap();
+        hal.set_motor_pwm(0.5, -0.5).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        let state = hal.poll_state(0.3).unwrap();
+        let (left_position, right_position) = (state.left_wheel_state.position, state.right_wheel_state.position);
+        assert!(left_position > 0.0);
+        assert!(right_position < 0.0);
+        assert_eq!(hal.connection_status(), ConnectionStatus::Connected);
+
+        plugged.store(false, Ordering::SeqCst);
+        assert!(matches!(
+            hal.poll_state(0.1),
+            Err(HalError::HardwareCommunicationError(
+                HwSerialConnectionError::DisconnectedError { .. }
+            ))
+        ));
+        assert!(matches!(
+            hal.connection_status(),
+            ConnectionStatus::Reconnecting { attempts: 0, .. }
+        ));
+        // The backoff has not elapsed yet.
+        assert!(matches!(hal.poll_state(0.1), Err(HalError::Disconnected { .. })));
+        // The device is still missing.
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        assert!(matches!(hal.poll_state(0.1), Err(HalError::Disconnected { .. })));
+        assert!(matches!(
+            hal.connection_status(),
+            ConnectionStatus::Reconnecting { attempts: 1, .. }
+        ));
+
+        plugged.store(true, Ordering::SeqCst);
+        std::thread::sleep(std::time::Duration::from_millis(110));
+        let state = hal.poll_state(0.1).unwrap();
+        assert_eq!(hal.connection_status(), ConnectionStatus::Connected);
+        // The position continues from where it was and the restarted encoder count causes no velocity spike.
+        assert_eq!(state.left_wheel_state.position, left_position);
+        assert_eq!(state.right_wheel_state.position, right_position);
+        assert_eq!(state.left_wheel_state.velocity, 0.0);
+        assert_eq!(state.right_wheel_state.velocity, 0.0);
+        // The statistics of the lost connection are kept.
+        assert_eq!(hal.comm_stats().total().other_errors, 1);
+    }
+
+    #[test]
+    fn test_hal_without_reconnect_stays_disconnected() {
+        use crate::core::comm::transport::MemoryPipe;
+        use std::io::{Read, Write};
+
+        let hal_config = HalConfig {
+            timeout: 1000,
+            ..Default::default()
+        };
+        let (hal_end, mut firmware_end) = MemoryPipe::pair(1000);
+        let firmware = std::thread::spawn(move || {
+            for response in [b"0 0\r\n".as_slice(), b"Invalid Command\r\n".as_slice()] {
+                firmware_end.read_exact(&mut [0; 2]).unwrap();
+                firmware_end.write_all(response).unwrap();
+            }
+        });
+        let mut hal = Hal::with_transport(&hal_config, hal_end).unwrap();
+        firmware.join().unwrap();
+        // The firmware end is gone, so reads time out but the connection is kept.
+        assert!(matches!(
+            hal.poll_state(0.1),
+            Err(HalError::HardwareCommunicationError(
+                HwSerialConnectionError::TimeoutError { .. }
+            ))
+        ));
+        assert_eq!(hal.connection_status(), ConnectionStatus::Connected);
+    }
+
+    #[test]
+    fn test_hal_record_and_replay() {
+        use crate::core::comm::recording::{ReplayTransport, load_recording};
+        use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+
+        let record_file = std::env::temp_dir().join(format!("andino_hal_recording_{}.tsv", std::process::id()));
+        let hal_config = HalConfig {
+            timeout: 1000,
+            motor_ticks_per_revolution: 700,
+            record_file: Some(record_file.clone()),
+            ..Default::default()
+        };
+        let mut hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(EmulatorConfig::default())).unwrap();
+        hal.set_motor_pwm(0.5, -0.5).unwrap();
+        let mut recorded_states = Vec::new();
+        for _ in 0..3 {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            let state = hal.poll_state(0.05).unwrap();
+            recorded_states.push((state.left_wheel_state, state.right_wheel_state));
+        }
+        drop(hal);
+
+        let exchanges = load_recording(&record_file).unwrap();
+        std::fs::remove_file(&record_file).unwrap();
+        // The readiness probe, the firmware info query, the PWM command and the encoder reads.
+        assert_eq!(exchanges.len(), 6);
+        assert_eq!(
+            exchanges.iter().filter(|exchange| exchange.is_encoder_read()).count(),
+            4
+        );
+
+        let replay_config = HalConfig {
+            record_file: None,
+            ..hal_config
+        };
+        let mut hal = Hal::with_transport(&replay_config, ReplayTransport::new(exchanges, 1)).unwrap();
+        hal.set_motor_pwm(0.5, -0.5).unwrap();
+        for (left_wheel_state, right_wheel_state) in recorded_states {
+            let state = hal.poll_state(0.05).unwrap();
+            assert_eq!(state.left_wheel_state.position, left_wheel_state.position);
+            assert_eq!(state.left_wheel_state.velocity, left_wheel_state.velocity);
+            assert_eq!(state.right_wheel_state.position, right_wheel_state.position);
+            assert_eq!(state.right_wheel_state.velocity, right_wheel_state.velocity);
+        }
+        // The recording is over.
+        assert!(hal.poll_state(0.05).is_err());
+    }
+
+    #[test]
+    fn test_hal_retries_dropped_encoder_read() {
+        use crate::core::comm::transport::MemoryPipe;
+        use std::io::{Read, Write};
+
+        let hal_config = HalConfig {
+            timeout: 100,
+            motor_ticks_per_revolution: 1000,
+            retry_policies: HashMap::from([(
+                SerialCommandKind::ReadEncoderValues,
+                RetryPolicy {
+                    max_retries: 1,
+                    retry_delay: 0,
+                },
+            )]),
+            ..Default::default()
+        };
+        // Short reads, so the HAL notices the dropped response in time.
+        let (hal_end, mut firmware_end) = MemoryPipe::pair(10);
+        let firmware = std::thread::spawn(move || {
+            // Readiness probe, firmware info query, a dropped encoder read and its retry.
+            for (command, response) in [
+                (b"e\r", b"0 0\r\n".as_slice()),
+                (b"v\r", b"Invalid Command\r\n".as_slice()),
+                (b"e\r", b"".as_slice()),
+                (b"e\r", b"500 -500\r\n".as_slice()),
+            ] {
+                let mut buffer = [0; 2];
+                let mut received = 0;
+                while received < buffer.len() {
+                    match firmware_end.read(&mut buffer[received..]) {
+                        Ok(n) => received += n,
+                        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+                assert_eq!(&buffer, command);
+                firmware_end.write_all(response).unwrap();
+            }
+        });
+        let mut hal = Hal::with_transport(&hal_config, hal_end).unwrap();
+        let state = hal.poll_state(1.0).unwrap();
+        firmware.join().unwrap();
+        assert_eq!(state.left_wheel_state.position, std::f64::consts::PI);
+        assert_eq!(state.right_wheel_state.position, -std::f64::consts::PI);
+
+        // The readiness probe is not accounted, the dropped read and its retry are.
+        let comm_stats = hal.take_comm_stats();
+        let encoder_stats = &comm_stats.commands[&SerialCommandKind::ReadEncoderValues];
+        assert_eq!(encoder_stats.exchanges, 2);
+        assert_eq!(encoder_stats.timeouts, 1);
+        assert_eq!(encoder_stats.retries, 1);
+        assert_eq!(encoder_stats.successes, 1);
+        assert_eq!(comm_stats.commands[&SerialCommandKind::ReadFirmwareInfo].successes, 1);
+        assert_eq!(comm_stats.bytes_sent, 8);
+        assert_eq!(hal.comm_stats(), CommStats::default());
+    }
+
+    #[test]
+    fn test_hal_new_firmware_not_ready() {
+        use crate::core::comm::transport::MemoryPipe;
+
+        let hal_config = HalConfig {
+            ready_timeout: 200,
+            ..Default::default()
+        };
+        // Nothing answers on the other end.
+        let (hal_end, _firmware_end) = MemoryPipe::pair(10);
+        let hal = Hal::with_transport(&hal_config, hal_end);
+        assert!(matches!(
+            hal,
+            Err(HalError::HardwareCommunicationError(
+                HwSerialConnectionError::NotReadyError { .. }
+            ))
+        ));
+    }
 }
@@ -0,0 +1,587 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Async counterpart of [`Hal`], on tokio.
+//!
+//! [`AsyncHal`] sends the same commands as [`Hal`] over an [`AsyncHwSerialConnection`], so it can be
+//! driven from async code without blocking the runtime. Its futures are cancellation safe: the state of
+//! the wheels and the speed ramp are only updated once a response was received, and the connection recovers
+//! from dropped exchanges.
+//!
+//! It does not reconnect: the `reconnect` setting of the configuration is ignored. The `command_timeout` and the
+//! wheel limits apply as in [`Hal`], checked and ramped whenever the HAL is polled.
+//!
+//! Dropping an [`AsyncHal`] does not stop the motors, as it cannot write to the connection without awaiting:
+//! call [`AsyncHal::stop`] before dropping it.
+
+use std::time::Instant;
+
+use super::{
+    CommandWatchdog, Hal, HalConfig, HalError, HalState, PidGains, SpeedClamp, SpeedLimiter, check_pid_gains_applied,
+    check_supported, encoder_values, find_gpio_pin, firmware_info, gpio_input_pins, gpio_value, motor_pwm_command,
+    motor_speed_command, pid_gains, pid_gains_command, read_gpio_command, write_gpio_command,
+};
+use crate::core::comm::asynchronous::{AsyncHwSerialConnection, AsyncTransport};
+use crate::core::comm::{FirmwareInfo, SerialCommandKind, SerialCommands, SerialResponse};
+use crate::core::gpio::{GpioPinConfig, GpioValue};
+use crate::core::sensors::Wheel;
+
+/// Async hardware abstraction layer (HAL) for the robot.
+///
+/// See [`Hal`] for the blocking version.
+#[derive(Debug)]
+pub struct AsyncHal {
+    /// The serial connection to the hardware.
+    hw_serial_connection: AsyncHwSerialConnection,
+    /// The gains of the PID speed controllers applied last, to set them again when new ones are not applied.
+    pid_gains: Option<PidGains>,
+    /// The firmware on the board, read when the HAL is created.
+    firmware_info: FirmwareInfo,
+    /// Right wheel instance.
+    right_wheel: Wheel,
+    /// Left wheel instance.
+    left_wheel: Wheel,
+    /// The named GPIO pins of the board.
+    gpio_pins: Vec<GpioPinConfig>,
+    /// Stops the motors when no new motor command arrived for the command timeout.
+    command_watchdog: CommandWatchdog,
+    /// Applies the limits of the wheels to the speed commands, if configured.
+    speed_limiter: Option<SpeedLimiter>,
+}
+
+impl AsyncHal {
+    /// Creates a new instance of the async HAL.
+    ///
+    /// It waits until the firmware answers, as the board resets when the serial port is opened.
+    /// It must be called from within a tokio runtime.
+    ///
+    /// # Arguments
+    ///  - `hal_config` - The configuration for the HAL.
+    ///
+    /// # Returns
+    ///  - `Ok(AsyncHal)` - A new instance of the HAL.
+    /// - `Err(HalError)` - An error if the HAL fails to initialize.
+    pub async fn new(hal_config: &HalConfig) -> Result<Self, HalError> {
+        let hw_serial_connection = AsyncHwSerialConnection::new(
+            hal_config.serial_device.resolve()?,
+            hal_config.baud_rate,
+            hal_config.timeout,
+        )?;
+        AsyncHal::from_connection(hal_config, hw_serial_connection).await
+    }
+
+    /// Creates a new instance of the async HAL that talks to the hardware over the given transport.
+    ///
+    /// The `serial_device` and `baud_rate` of the configuration are ignored, as the transport is already open.
+    ///
+    /// # Arguments
+    ///  - `hal_config` - The configuration for the HAL.
+    ///  - `transport` - The transport used to exchange bytes with the hardware.
+    ///
+    /// # Returns
+    ///  - `Ok(AsyncHal)` - A new instance of the HAL.
+    /// - `Err(HalError)` - An error if the HAL fails to initialize.
+    pub async fn with_transport(
+        hal_config: &HalConfig,
+        transport: impl AsyncTransport + 'static,
+    ) -> Result<Self, HalError> {
+        let hw_serial_connection = AsyncHwSerialConnection::from_transport(transport, hal_config.timeout);
+        AsyncHal::from_connection(hal_config, hw_serial_connection).await
+    }
+
+    // Sets up the connection as configured and builds the HAL on top of it, once the firmware is ready.
+    async fn from_connection(
+        hal_config: &HalConfig,
+        mut hw_serial_connection: AsyncHwSerialConnection,
+    ) -> Result<Self, HalError> {
+        let speed_limiter = hal_config
+            .wheel_limits()
+            .map(|wheel_limits| SpeedLimiter::new(wheel_limits, Instant::now()))
+            .transpose()?;
+        if let Some(recorder) = Hal::recorder(hal_config)? {
+            hw_serial_connection.set_recorder(recorder);
+        }
+        hw_serial_connection.set_protocol(hal_config.protocol);
+        for (command_kind, retry_policy) in &hal_config.retry_policies {
+            hw_serial_connection.set_retry_policy(*command_kind, retry_policy.clone());
+        }
+        hw_serial_connection.wait_until_ready(hal_config.ready_timeout).await?;
+        let firmware_info = firmware_info(
+            hw_serial_connection
+                .send_command(SerialCommands::ReadFirmwareInfo)
+                .await,
+            hal_config,
+        )?;
+        let (left_wheel, right_wheel) = hal_config.wheels();
+        let mut hal = AsyncHal {
+            hw_serial_connection,
+            pid_gains: None,
+            firmware_info,
+            right_wheel,
+            left_wheel,
+            gpio_pins: hal_config.gpio_pins.clone(),
+            command_watchdog: CommandWatchdog::new(hal_config.command_timeout),
+            speed_limiter,
+        };
+        if let Some(pid_gains) = hal_config.pid_gains {
+            hal.set_pid_gains(pid_gains).await?;
+        }
+        Ok(hal)
+    }
+
+    /// Gets the version and the capabilities of the firmware on the board.
+    ///
+    /// See [`Hal::firmware_info`].
+    pub fn firmware_info(&self) -> &FirmwareInfo {
+        &self.firmware_info
+    }
+
+    /// Reads sensor values and updates the state of the sensors in the HAL.
+    ///
+    /// See [`Hal::poll_state`].
+    ///
+    /// # Arguments
+    ///
+    /// * `delta_time` - The time elapsed since the last update in seconds.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(HalState)` - The state of the HAL after the update.
+    /// * `Err(HalError)` - An error if the update fails.
+    pub async fn poll_state(&mut self, delta_time: f64) -> Result<HalState, HalError> {
+        if self.command_watchdog.expired() {
+            self.stop().await?;
+            self.command_watchdog.timed_out();
+        } else if let (speed_limiter, Some((left_speed, right_speed))) = self.ramp_speed() {
+            let command = motor_speed_command(&self.left_wheel, &self.right_wheel, left_speed, right_speed);
+            self.send_command(command).await?;
+            self.speed_limiter = speed_limiter;
+        }
+        let (left, right) = encoder_values(self.send_command(SerialCommands::ReadEncoderValues).await?)?;
+        Ok(HalState {
+            left_wheel_state: self.left_wheel.update(left, delta_time).clone(),
+            right_wheel_state: self.right_wheel.update(right, delta_time).clone(),
+        })
+    }
+
+    /// Sets the speed of the motors in rads per second.
+    ///
+    /// See [`Hal::set_motor_speed`] for the limits of the wheels.
+    ///
+    /// # Arguments
+    ///
+    /// * `left_speed` - The speed of the left motor in rads per second.
+    /// * `right_speed` - The speed of the right motor in rads per second.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the command was sent successfully.
+    /// * `Err(HalError)` - An error if the command fails.
+    pub async fn set_motor_speed(&mut self, left_speed: f64, right_speed: f64) -> Result<(), HalError> {
+        let (speed_limiter, (left_commanded, right_commanded)) = self.limit_speed(left_speed, right_speed);
+        let command = motor_speed_command(&self.left_wheel, &self.right_wheel, left_commanded, right_commanded);
+        self.send_command(command).await?;
+        self.speed_limiter = speed_limiter;
+        self.command_watchdog.commanded(left_speed, right_speed);
+        Ok(())
+    }
+
+    /// Stops the motors at once, regardless of the acceleration limit.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the command was sent successfully.
+    /// * `Err(HalError)` - An error if the command fails.
+    pub async fn stop(&mut self) -> Result<(), HalError> {
+        let mut speed_limiter = self.speed_limiter.clone();
+        if let Some(speed_limiter) = &mut speed_limiter {
+            speed_limiter.stopped(Instant::now());
+        }
+        self.send_command(motor_speed_command(&self.left_wheel, &self.right_wheel, 0.0, 0.0))
+            .await?;
+        self.speed_limiter = speed_limiter;
+        self.command_watchdog.commanded(0.0, 0.0);
+        Ok(())
+    }
+
+    /// Gets how the latest speed request was limited by [`HalConfig::wheel_limits`].
+    ///
+    /// See [`Hal::last_speed_clamp`].
+    pub fn last_speed_clamp(&self) -> Option<&SpeedClamp> {
+        self.speed_limiter.as_ref().and_then(SpeedLimiter::last_clamp)
+    }
+
+    /// Whether the motors were stopped because no motor command arrived for the command timeout.
+    ///
+    /// See [`Hal::motors_stopped_by_timeout`].
+    pub fn motors_stopped_by_timeout(&self) -> bool {
+        self.command_watchdog.stopped_by_timeout
+    }
+
+    /// Drives the motors in open loop, bypassing the PID speed controllers of the firmware.
+    ///
+    /// See [`Hal::set_motor_pwm`].
+    ///
+    /// # Arguments
+    ///
+    /// * `left_duty_cycle` - The duty cycle of the left motor, from -1.0 (full reverse) to 1.0 (full forward).
+    /// * `right_duty_cycle` - The duty cycle of the right motor, from -1.0 (full reverse) to 1.0 (full forward).
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the command was sent successfully.
+    /// * `Err(HalError)` - An error if the command fails.
+    pub async fn set_motor_pwm(&mut self, left_duty_cycle: f64, right_duty_cycle: f64) -> Result<(), HalError> {
+        let command = motor_pwm_command(&self.left_wheel, &self.right_wheel, left_duty_cycle, right_duty_cycle);
+        self.send_command(command).await?;
+        self.command_watchdog.commanded(left_duty_cycle, right_duty_cycle);
+        Ok(())
+    }
+
+    /// Resets the encoder counts to zero, and the position and velocity of the wheels with them.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the encoders were reset.
+    /// * `Err(HalError)` - An error if the command fails.
+    pub async fn reset_encoders(&mut self) -> Result<(), HalError> {
+        self.send_command(SerialCommands::ResetEncoders).await?;
+        self.left_wheel.reset();
+        self.right_wheel.reset();
+        self.command_watchdog.reset();
+        if let Some(speed_limiter) = &mut self.speed_limiter {
+            speed_limiter.stopped(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Sets the gains of the PID speed controllers of the firmware.
+    ///
+    /// See [`Hal::set_pid_gains`].
+    ///
+    /// # Arguments
+    ///
+    /// * `pid_gains` - The gains to set.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the gains were applied.
+    /// * `Err(HalError)` - An error if the command fails or the gains read back differ.
+    pub async fn set_pid_gains(&mut self, pid_gains: PidGains) -> Result<(), HalError> {
+        let pid_gains = PidGains::new(pid_gains.kp, pid_gains.ki, pid_gains.kd, pid_gains.ko)?;
+        self.send_command(pid_gains_command(&pid_gains)).await?;
+        if self.firmware_info.supports(SerialCommandKind::ReadPIDValues) {
+            if let Err(err) = check_pid_gains_applied(&pid_gains, &self.read_pid_gains().await?) {
+                self.restore_pid_gains().await;
+                return Err(err);
+            }
+        }
+        self.pid_gains = Some(pid_gains);
+        Ok(())
+    }
+
+    /// Reads the gains of the PID speed controllers of the firmware.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PidGains)` - The gains in use.
+    /// * `Err(HalError)` - An error if the firmware does not support reading them or the command fails.
+    pub async fn read_pid_gains(&mut self) -> Result<PidGains, HalError> {
+        Ok(pid_gains(self.send_command(SerialCommands::ReadPIDValues).await?)?)
+    }
+
+    /// Reads the value of a configured input pin.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the pin in the configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(GpioValue)` - The value of the pin.
+    /// * `Err(HalError)` - An error if the pin is unknown, is not an input or the command fails.
+    pub async fn read_gpio(&mut self, name: &str) -> Result<GpioValue, HalError> {
+        let pin_config = find_gpio_pin(&self.gpio_pins, name)?.clone();
+        self.read_gpio_pin(&pin_config).await
+    }
+
+    /// Reads the values of all the configured input pins.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(String, GpioValue)>)` - The name and value of every input pin, in configuration order.
+    /// * `Err(HalError)` - An error if any of the commands fails.
+    pub async fn read_gpio_inputs(&mut self) -> Result<Vec<(String, GpioValue)>, HalError> {
+        let mut values = Vec::new();
+        for pin_config in gpio_input_pins(&self.gpio_pins) {
+            let value = self.read_gpio_pin(&pin_config).await?;
+            values.push((pin_config.name, value));
+        }
+        Ok(values)
+    }
+
+    /// Writes the value of a configured output pin.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the pin in the configuration.
+    /// * `value` - The value to write. It must be digital for digital outputs and analog,
+    ///   from 0 to 255, for analog outputs.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the command was sent successfully.
+    /// * `Err(HalError)` - An error if the pin is unknown, the value does not suit the pin or the command fails.
+    pub async fn write_gpio(&mut self, name: &str, value: GpioValue) -> Result<(), HalError> {
+        let command = write_gpio_command(find_gpio_pin(&self.gpio_pins, name)?, value)?;
+        self.send_command(command).await?;
+        Ok(())
+    }
+
+    // Applies the limits of the wheels to a speed request, returning the speed to command now along with the
+    // limiter that commanded it. The limiter is a copy, to be kept once the command was sent, so that a cancelled
+    // command does not advance the ramp.
+    fn limit_speed(&self, left_speed: f64, right_speed: f64) -> (Option<SpeedLimiter>, (f64, f64)) {
+        let mut speed_limiter = self.speed_limiter.clone();
+        let speed = match &mut speed_limiter {
+            Some(speed_limiter) => speed_limiter.request(left_speed, right_speed, Instant::now()),
+            None => (left_speed, right_speed),
+        };
+        (speed_limiter, speed)
+    }
+
+    // Gets the next speed to command while ramping to the one requested, along with the limiter that commanded it,
+    // to be kept once the command was sent like in `limit_speed`.
+    fn ramp_speed(&self) -> (Option<SpeedLimiter>, Option<(f64, f64)>) {
+        let mut speed_limiter = self.speed_limiter.clone();
+        let speed = speed_limiter
+            .as_mut()
+            .and_then(|speed_limiter| speed_limiter.ramp(Instant::now()));
+        (speed_limiter, speed)
+    }
+
+    // Sets the gains applied before again after the firmware did not apply new ones, so that it keeps using them.
+    // If they cannot be set, they are forgotten.
+    async fn restore_pid_gains(&mut self) {
+        if let Some(pid_gains) = self.pid_gains {
+            if let Err(err) = self.send_command(pid_gains_command(&pid_gains)).await {
+                log::warn!("Failed to restore the PID gains {}: {}", pid_gains, err);
+                self.pid_gains = None;
+            }
+        }
+    }
+
+    // Reads the value of an input pin from the hardware.
+    async fn read_gpio_pin(&mut self, pin_config: &GpioPinConfig) -> Result<GpioValue, HalError> {
+        let response = self.send_command(read_gpio_command(pin_config)?).await?;
+        gpio_value(pin_config, response)
+    }
+
+    // Sends a command to the hardware.
+    async fn send_command(&mut self, command: SerialCommands) -> Result<SerialResponse, HalError> {
+        check_supported(&self.firmware_info, &command)?;
+        Ok(self.hw_serial_connection.send_command(command).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::comm::asynchronous::spawn_scripted_firmware;
+    use crate::core::gpio::GpioPinKind;
+    use crate::core::hal::WheelLimits;
+
+    #[tokio::test]
+    async fn test_async_hal() {
+        let (hal_end, firmware_end) = tokio::io::duplex(64);
+        let firmware = spawn_scripted_firmware(
+            firmware_end,
+            vec![
+                // Readiness probe.
+                ("e\r", "0 0\r\n"),
+                ("v\r", "1.2.0 emrdxui\r\n"),
+                ("m 700 -700\r", "OK\r\n"),
+                ("e\r", "350 -350\r\n"),
+                ("d 2\r", "1\r\n"),
+                ("x 3 128\r", "OK\r\n"),
+                ("u 30:0:10:10\r", "OK\r\n"),
+                // The gains are read back to check they were applied.
+                ("i\r", "30:0:10:10\r\n"),
+            ],
+        );
+        let hal_config = HalConfig {
+            motor_ticks_per_revolution: 700,
+            gpio_pins: vec![
+                GpioPinConfig {
+                    name: "bumper".to_string(),
+                    pin: 2,
+                    kind: GpioPinKind::DigitalInput,
+                },
+                GpioPinConfig {
+                    name: "led".to_string(),
+                    pin: 3,
+                    kind: GpioPinKind::AnalogOutput,
+                },
+            ],
+            ..Default::default()
+        };
+        let mut hal = AsyncHal::with_transport(&hal_config, hal_end).await.unwrap();
+        // A revolution per second is 700 ticks per second.
+        hal.set_motor_speed(2.0 * std::f64::consts::PI, -2.0 * std::f64::consts::PI)
+            .await
+            .unwrap();
+        let hal_state = hal.poll_state(0.5).await.unwrap();
+        assert!((hal_state.left_wheel_state.position - std::f64::consts::PI).abs() < 1e-9);
+        assert!((hal_state.right_wheel_state.position + std::f64::consts::PI).abs() < 1e-9);
+        assert_eq!(
+            hal.read_gpio_inputs().await.unwrap(),
+            vec![("bumper".to_string(), GpioValue::Digital(true))]
+        );
+        hal.write_gpio("led", GpioValue::Analog(128)).await.unwrap();
+        assert!(matches!(
+            hal.write_gpio("bumper", GpioValue::Digital(true)).await,
+            Err(HalError::InvalidGpioOperation { .. })
+        ));
+        // Not in the commands reported by the firmware.
+        assert!(matches!(
+            hal.set_motor_pwm(0.5, 0.5).await,
+            Err(HalError::UnsupportedCommand { .. })
+        ));
+        hal.set_pid_gains(PidGains {
+            kp: 30.0,
+            ki: 0.0,
+            kd: 10.0,
+            ko: 10.0,
+        })
+        .await
+        .unwrap();
+        firmware.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_hal_restores_pid_gains_not_applied() {
+        let (hal_end, firmware_end) = tokio::io::duplex(64);
+        let firmware = spawn_scripted_firmware(
+            firmware_end,
+            vec![
+                ("e\r", "0 0\r\n"),
+                ("v\r", "0.1.0 emui\r\n"),
+                // The first gains are applied.
+                ("u 30:0:10:10\r", "OK\r\n"),
+                ("i\r", "30:0:10:10\r\n"),
+                // The second ones are not, and the first ones are set again.
+                ("u 20:0:12:10\r", "OK\r\n"),
+                ("i\r", "30:0:10:10\r\n"),
+                ("u 30:0:10:10\r", "OK\r\n"),
+            ],
+        );
+        let mut hal = AsyncHal::with_transport(&HalConfig::default(), hal_end).await.unwrap();
+        let applied = "30:0:10:10".parse::<PidGains>().unwrap();
+        hal.set_pid_gains(applied).await.unwrap();
+        assert!(matches!(
+            hal.set_pid_gains("20:0:12:10".parse::<PidGains>().unwrap()).await,
+            Err(HalError::PidGainsNotApplied { .. })
+        ));
+        assert_eq!(hal.pid_gains, Some(applied));
+        firmware.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_hal_wheel_limits() {
+        let (hal_end, firmware_end) = tokio::io::duplex(64);
+        let firmware = spawn_scripted_firmware(
+            firmware_end,
+            vec![
+                ("e\r", "0 0\r\n"),
+                ("v\r", "1.2.0 emrdxui\r\n"),
+                // Half a revolution per second at most for the left wheel, a quarter for the right one.
+                ("m 350 -175\r", "OK\r\n"),
+                ("e\r", "0 0\r\n"),
+            ],
+        );
+        let hal_config = HalConfig {
+            motor_ticks_per_revolution: 700,
+            left_wheel_limits: Some(WheelLimits {
+                max_velocity: std::f64::consts::PI,
+                ..Default::default()
+            }),
+            right_wheel_limits: Some(WheelLimits {
+                max_velocity: std::f64::consts::FRAC_PI_2,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut hal = AsyncHal::with_transport(&hal_config, hal_end).await.unwrap();
+        hal.set_motor_speed(2.0 * std::f64::consts::PI, -2.0 * std::f64::consts::PI)
+            .await
+            .unwrap();
+        assert!(hal.last_speed_clamp().unwrap().velocity_clamped);
+        hal.poll_state(0.01).await.unwrap();
+        firmware.await.unwrap();
+
+        let (hal_end, _firmware_end) = tokio::io::duplex(64);
+        let hal_config = HalConfig {
+            right_wheel_limits: Some(WheelLimits {
+                max_velocity: -1.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            AsyncHal::with_transport(&hal_config, hal_end).await,
+            Err(HalError::InvalidWheelLimits { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_async_hal_cancelled_speed_command_keeps_the_ramp() {
+        let (hal_end, firmware_end) = tokio::io::duplex(64);
+        let firmware = spawn_scripted_firmware(firmware_end, vec![("e\r", "0 0\r\n"), ("v\r", "1.2.0 emrdxui\r\n")]);
+        let hal_config = HalConfig {
+            left_wheel_limits: Some(WheelLimits {
+                max_velocity: 1.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut hal = AsyncHal::with_transport(&hal_config, hal_end).await.unwrap();
+        // The firmware does not answer the speed command, which is cancelled.
+        let _firmware_end = firmware.await.unwrap();
+        let cancelled = tokio::time::timeout(std::time::Duration::from_millis(20), hal.set_motor_speed(5.0, 5.0)).await;
+        assert!(cancelled.is_err());
+        assert!(hal.last_speed_clamp().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_async_hal_command_timeout() {
+        let (hal_end, firmware_end) = tokio::io::duplex(64);
+        let firmware = spawn_scripted_firmware(
+            firmware_end,
+            vec![
+                ("e\r", "0 0\r\n"),
+                ("v\r", "1.2.0 emrdxui\r\n"),
+                ("m 700 700\r", "OK\r\n"),
+                ("e\r", "10 10\r\n"),
+                // Without a fresh command, the motors are stopped once, on the next poll.
+                ("m 0 0\r", "OK\r\n"),
+                ("e\r", "20 20\r\n"),
+                ("e\r", "20 20\r\n"),
+            ],
+        );
+        let hal_config = HalConfig {
+            motor_ticks_per_revolution: 700,
+            command_timeout: Some(100),
+            ..Default::default()
+        };
+        let mut hal = AsyncHal::with_transport(&hal_config, hal_end).await.unwrap();
+        hal.set_motor_speed(2.0 * std::f64::consts::PI, 2.0 * std::f64::consts::PI)
+            .await
+            .unwrap();
+        hal.poll_state(0.01).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+        hal.poll_state(0.15).await.unwrap();
+        hal.poll_state(0.01).await.unwrap();
+        firmware.await.unwrap();
+    }
+}
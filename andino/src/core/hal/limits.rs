@@ -0,0 +1,337 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Velocity and acceleration limits of the wheels.
+//!
+//! The requested speed of each wheel is clamped to the maximum velocity, and the speed commanded to the firmware
+//! ramps towards it within the maximum acceleration, over the time elapsed between updates.
+
+use std::time::Instant;
+
+use super::HalError;
+
+/// Limits of the motion of each wheel. Infinite values do not limit it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WheelLimits {
+    /// Maximum speed of a wheel in rads per second. Faster requests are clamped.
+    pub max_velocity: f64,
+    /// Maximum acceleration of a wheel in rads per second squared. Speed changes are ramped within it.
+    pub max_acceleration: f64,
+}
+
+impl Default for WheelLimits {
+    // No limits.
+    fn default() -> Self {
+        WheelLimits {
+            max_velocity: f64::INFINITY,
+            max_acceleration: f64::INFINITY,
+        }
+    }
+}
+
+/// How a speed request was limited.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpeedClamp {
+    /// The speed of the left and right wheels requested, in rads per second.
+    pub requested: (f64, f64),
+    /// The speed of the left and right wheels within the velocity limit, which the wheels ramp to.
+    pub limited: (f64, f64),
+    /// Whether the requested speed exceeded the velocity limit, or was not a number.
+    pub velocity_clamped: bool,
+    /// Whether the speed is ramped to, as it changes faster than the acceleration limit allows.
+    pub ramping: bool,
+}
+
+/// Applies the [`WheelLimits`] of the left and right wheels to the speed requests.
+#[derive(Clone, Debug)]
+pub(crate) struct SpeedLimiter {
+    /// The limits of the left and right wheels.
+    limits: (WheelLimits, WheelLimits),
+    /// The speed of the left and right wheels requested, within the velocity limit.
+    target: (f64, f64),
+    /// The speed of the left and right wheels commanded last, on its way to the target.
+    commanded: (f64, f64),
+    /// When the commanded speed was last updated.
+    last_update: Instant,
+    /// How the latest request was limited, `None` if it was within the limits.
+    last_clamp: Option<SpeedClamp>,
+}
+
+impl SpeedLimiter {
+    /// Creates a limiter for wheels standing still at `now`.
+    ///
+    /// It fails with [`HalError::InvalidWheelLimits`] if the maximum velocity of a wheel is negative or not a number,
+    /// or its maximum acceleration is not positive, as the wheel could not move.
+    pub(crate) fn new(limits: (WheelLimits, WheelLimits), now: Instant) -> Result<Self, HalError> {
+        for (wheel, wheel_limits) in [("left", limits.0), ("right", limits.1)] {
+            if wheel_limits.max_velocity.is_nan() || wheel_limits.max_velocity < 0.0 {
+                return Err(HalError::InvalidWheelLimits {
+                    error: format!(
+                        "The maximum velocity of the {} wheel must not be negative, got {}",
+                        wheel, wheel_limits.max_velocity
+                    ),
+                });
+            }
+            if wheel_limits.max_acceleration.is_nan() || wheel_limits.max_acceleration <= 0.0 {
+                return Err(HalError::InvalidWheelLimits {
+                    error: format!(
+                        "The maximum acceleration of the {} wheel must be positive, got {}",
+                        wheel, wheel_limits.max_acceleration
+                    ),
+                });
+            }
+        }
+        Ok(SpeedLimiter {
+            limits,
+            target: (0.0, 0.0),
+            commanded: (0.0, 0.0),
+            last_update: now,
+            last_clamp: None,
+        })
+    }
+
+    /// Gets how the latest request was limited, `None` if it was within the limits.
+    pub(crate) fn last_clamp(&self) -> Option<&SpeedClamp> {
+        self.last_clamp.as_ref()
+    }
+
+    /// Takes a new speed request at `now` and returns the speed to command.
+    pub(crate) fn request(&mut self, left_speed: f64, right_speed: f64, now: Instant) -> (f64, f64) {
+        let limit = |speed: f64, limits: WheelLimits| {
+            if speed.is_nan() {
+                0.0
+            } else {
+                speed.clamp(-limits.max_velocity, limits.max_velocity)
+            }
+        };
+        if self.commanded == self.target {
+            // The wheels kept their speed while idle, so the ramp to the new target starts now.
+            self.last_update = now;
+        }
+        self.target = (limit(left_speed, self.limits.0), limit(right_speed, self.limits.1));
+        let commanded = self.step(now);
+        let velocity_clamped = self.target.0 != left_speed || self.target.1 != right_speed;
+        let ramping = commanded != self.target;
+        self.last_clamp = (velocity_clamped || ramping).then_some(SpeedClamp {
+            requested: (left_speed, right_speed),
+            limited: self.target,
+            velocity_clamped,
+            ramping,
+        });
+        if velocity_clamped {
+            log::warn!(
+                "Speed request ({}, {}) rad/s clamped to ({}, {}) rad/s",
+                left_speed,
+                right_speed,
+                self.target.0,
+                self.target.1
+            );
+        }
+        commanded
+    }
+
+    /// Gets the next speed to command at `now` on the way to the target, `None` once it is reached.
+    pub(crate) fn ramp(&mut self, now: Instant) -> Option<(f64, f64)> {
+        if self.commanded == self.target {
+            // Keep the time of the update, so that the next request ramps from now on.
+            self.last_update = now;
+            None
+        } else {
+            Some(self.step(now))
+        }
+    }
+
+    /// Takes the wheels as stopped at once at `now`, e.g. when the motors were stopped regardless of the limits.
+    pub(crate) fn stopped(&mut self, now: Instant) {
+        self.target = (0.0, 0.0);
+        self.commanded = (0.0, 0.0);
+        self.last_update = now;
+    }
+
+    // Moves the commanded speed towards the target, as far as the acceleration limit allows since the last update.
+    //
+    // Both wheels cover the same fraction of the way, the one the wheel furthest from its limit allows, so the robot
+    // keeps the curvature of the target while ramping.
+    fn step(&mut self, now: Instant) -> (f64, f64) {
+        let elapsed = now.saturating_duration_since(self.last_update).as_secs_f64();
+        self.last_update = now;
+        let change = (self.target.0 - self.commanded.0, self.target.1 - self.commanded.1);
+        // Infinity times no elapsed time is not a number, so a wheel without acceleration limit or without a change
+        // does not hold the other one back.
+        let allowed_fraction = |change: f64, limits: WheelLimits| {
+            if limits.max_acceleration.is_infinite() || change == 0.0 {
+                1.0
+            } else {
+                limits.max_acceleration * elapsed / change.abs()
+            }
+        };
+        let fraction = allowed_fraction(change.0, self.limits.0)
+            .min(allowed_fraction(change.1, self.limits.1))
+            .min(1.0);
+        self.commanded = if fraction >= 1.0 {
+            self.target
+        } else {
+            (
+                self.commanded.0 + change.0 * fraction,
+                self.commanded.1 + change.1 * fraction,
+            )
+        };
+        self.commanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_speed_limiter() {
+        let limits = WheelLimits {
+            max_velocity: 10.0,
+            max_acceleration: 20.0,
+        };
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let mut limiter = SpeedLimiter::new((limits, limits), start).unwrap();
+        let commanded = limiter.request(15.0, -1.0, at(0));
+        assert_eq!(commanded, (0.0, 0.0));
+        let clamp = limiter.last_clamp().unwrap();
+        assert_eq!(clamp.limited, (10.0, -1.0));
+        assert!(clamp.velocity_clamped);
+        assert!(clamp.ramping);
+
+        // The ramp goes on every 50 ms, by 1 rad/s for the left wheel up to the velocity limit, and in proportion
+        // for the right one, so both reach the target together.
+        let mut millis = 0;
+        let mut steps = 0;
+        while let Some(commanded) = limiter.ramp(at(millis + 50)) {
+            millis += 50;
+            steps += 1;
+            assert!((commanded.0 - steps as f64).abs() < 1e-9);
+            assert!((commanded.1 + 0.1 * steps as f64).abs() < 1e-9);
+        }
+        assert_eq!(steps, 10);
+        assert_eq!(limiter.commanded, (10.0, -1.0));
+
+        // Requests within the velocity limit are ramped to from the time they are taken.
+        assert_eq!(limiter.request(9.0, 0.0, at(millis + 100)), (10.0, -1.0));
+        assert!(!limiter.last_clamp().unwrap().velocity_clamped);
+        assert_eq!(limiter.ramp(at(millis + 200)), Some((9.0, 0.0)));
+        // Those keeping the speed are sent as they are.
+        assert_eq!(limiter.request(9.0, 0.0, at(millis + 300)), (9.0, 0.0));
+        assert_eq!(limiter.last_clamp(), None);
+        assert_eq!(limiter.request(f64::NAN, 0.0, at(millis + 100)).1, 0.0);
+        assert!(limiter.last_clamp().unwrap().velocity_clamped);
+
+        limiter.stopped(at(millis + 100));
+        assert_eq!(limiter.ramp(at(millis + 100)), None);
+    }
+
+    #[test]
+    fn test_speed_limiter_ramps_from_the_request_after_idling() {
+        let limits = WheelLimits {
+            max_velocity: 10.0,
+            max_acceleration: 5.0,
+        };
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        // Long after the limiter was created, the idle time does not count as time spent ramping.
+        let mut limiter = SpeedLimiter::new((limits, limits), start).unwrap();
+        assert_eq!(limiter.request(10.0, 10.0, at(2000)), (0.0, 0.0));
+        let commanded = limiter.ramp(at(2100)).unwrap();
+        assert!((commanded.0 - 0.5).abs() < 1e-9);
+        // Nor long after the wheels were stopped.
+        limiter.stopped(at(2100));
+        assert_eq!(limiter.request(-10.0, 10.0, at(5000)), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_speed_limiter_keeps_the_curvature() {
+        let limits = WheelLimits {
+            max_velocity: 10.0,
+            max_acceleration: 5.0,
+        };
+        let start = Instant::now();
+        let mut limiter = SpeedLimiter::new((limits, limits), start).unwrap();
+        limiter.request(10.0, 2.0, start);
+        // The left wheel speeds up by 2 rad/s in 0.4 s, and the right one by a fifth of it.
+        let commanded = limiter.ramp(start + Duration::from_millis(400)).unwrap();
+        assert!((commanded.0 - 2.0).abs() < 1e-9);
+        assert!((commanded.1 - 0.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_speed_limiter_per_wheel() {
+        let left_limits = WheelLimits {
+            max_velocity: 10.0,
+            max_acceleration: 5.0,
+        };
+        let right_limits = WheelLimits {
+            max_velocity: 4.0,
+            max_acceleration: 20.0,
+        };
+        let start = Instant::now();
+        let mut limiter = SpeedLimiter::new((left_limits, right_limits), start).unwrap();
+        limiter.request(10.0, 10.0, start);
+        assert_eq!(limiter.last_clamp().unwrap().limited, (10.0, 4.0));
+        // The left wheel holds the right one back, which could reach its target within 0.4 s.
+        let commanded = limiter.ramp(start + Duration::from_millis(400)).unwrap();
+        assert!((commanded.0 - 2.0).abs() < 1e-9);
+        assert!((commanded.1 - 0.8).abs() < 1e-9);
+
+        // The limits of each wheel are checked.
+        let invalid_limits = WheelLimits {
+            max_acceleration: 0.0,
+            ..right_limits
+        };
+        assert!(matches!(
+            SpeedLimiter::new((left_limits, invalid_limits), start),
+            Err(HalError::InvalidWheelLimits { .. })
+        ));
+    }
+
+    #[test]
+    fn test_speed_limiter_without_limits() {
+        let mut limiter = SpeedLimiter::new((WheelLimits::default(), WheelLimits::default()), Instant::now()).unwrap();
+        assert_eq!(limiter.request(100.0, -100.0, Instant::now()), (100.0, -100.0));
+        assert_eq!(limiter.last_clamp(), None);
+        assert_eq!(limiter.ramp(Instant::now()), None);
+    }
+
+    #[test]
+    fn test_speed_limiter_without_acceleration_limit() {
+        let limits = WheelLimits {
+            max_velocity: 4.0,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let mut limiter = SpeedLimiter::new((limits, limits), now).unwrap();
+        // No time elapsed since the stop, as when a command times out.
+        limiter.stopped(now);
+        assert_eq!(limiter.request(10.0, -1.0, now), (4.0, -1.0));
+        assert!(!limiter.last_clamp().unwrap().ramping);
+        assert_eq!(limiter.ramp(now), None);
+    }
+
+    #[test]
+    fn test_speed_limiter_rejects_invalid_limits() {
+        let invalid_limits = [(-1.0, 1.0), (f64::NAN, 1.0), (1.0, 0.0), (1.0, -1.0), (1.0, f64::NAN)];
+        for (max_velocity, max_acceleration) in invalid_limits {
+            let limits = WheelLimits {
+                max_velocity,
+                max_acceleration,
+            };
+            assert!(matches!(
+                SpeedLimiter::new((limits, limits), Instant::now()),
+                Err(HalError::InvalidWheelLimits { .. })
+            ));
+        }
+        let limits = WheelLimits {
+            max_velocity: 0.0,
+            max_acceleration: f64::INFINITY,
+        };
+        assert!(SpeedLimiter::new((limits, limits), Instant::now()).is_ok());
+    }
+}
@@ -0,0 +1,398 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Polling of a robot at a fixed rate, in a background thread.
+//!
+//! When the state of the wheels is polled from a control loop, the velocity is computed over the `delta_time`
+//! the caller measured, which jitters with the scheduling of the loop. [`PollingHal`] owns a thread that polls
+//! the robot on its own schedule and measures the time between samples with the monotonic clock. The latest
+//! sample is shared through a [`PollingHandle`], which also takes speed commands from any thread; they are sent
+//! along with the next poll.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::{Capabilities, ConnectionStatus, HalError, HalState, PidGains, RobotHal, SpeedClamp};
+use crate::core::comm::CommStats;
+use crate::core::gpio::GpioValue;
+
+/// A state of the wheels polled in the background.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    /// The state of the wheels.
+    pub state: HalState,
+    /// When the state was polled.
+    pub timestamp: Instant,
+    /// Number of the sample, starting at 0 with the one polled when spawning the thread.
+    pub sequence: u64,
+}
+
+/// What the polling thread shares with the handles.
+#[derive(Debug)]
+struct PollingStatus {
+    /// The latest sample.
+    sample: Sample,
+    /// The error of the latest poll, if it failed.
+    error: Option<String>,
+    /// The status of the connection after the latest poll.
+    connection_status: ConnectionStatus,
+    /// The speed command to send with the next poll.
+    motor_speed: Option<(f64, f64)>,
+    /// Number of stops so far. A speed command taken by the polling thread before a stop is dropped.
+    stop_generation: u64,
+    /// Whether the polling thread has to finish.
+    stopping: bool,
+}
+
+/// The status and the condition signalled when it changes.
+#[derive(Debug)]
+struct Shared {
+    status: Mutex<PollingStatus>,
+    changed: Condvar,
+}
+
+/// Shared handle to the samples of a [`PollingHal`]. It can be cloned and sent to other threads.
+#[derive(Clone, Debug)]
+pub struct PollingHandle {
+    shared: Arc<Shared>,
+}
+
+impl PollingHandle {
+    /// Gets the latest sample.
+    pub fn latest_sample(&self) -> Sample {
+        self.shared.status.lock().unwrap().sample.clone()
+    }
+
+    /// Waits for a sample newer than the given one.
+    ///
+    /// # Arguments
+    ///
+    /// * `sequence` - The number of the last sample seen.
+    /// * `timeout` - The maximum time to wait.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(Sample)` - The latest sample, if newer than `sequence`.
+    /// * `None` - If no newer sample was polled in time.
+    pub fn wait_for_sample(&self, sequence: u64, timeout: Duration) -> Option<Sample> {
+        let status = self.shared.status.lock().unwrap();
+        let (status, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(status, timeout, |status| status.sample.sequence <= sequence)
+            .unwrap();
+        (status.sample.sequence > sequence).then(|| status.sample.clone())
+    }
+
+    /// Gets the error of the latest poll, `None` if it succeeded.
+    pub fn last_error(&self) -> Option<String> {
+        self.shared.status.lock().unwrap().error.clone()
+    }
+
+    /// Sets the speed of the wheels, sent along with the next poll. It replaces a previous command not sent yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `left_speed` - The speed of the left wheel in rads per second.
+    /// * `right_speed` - The speed of the right wheel in rads per second.
+    pub fn set_motor_speed(&self, left_speed: f64, right_speed: f64) {
+        self.shared.status.lock().unwrap().motor_speed = Some((left_speed, right_speed));
+    }
+}
+
+/// A robot polled at a fixed rate in a background thread, see the [module documentation](self).
+///
+/// It is a [`RobotHal`] too: [`RobotHal::poll_state`] returns the latest sample, ignoring the `delta_time` given,
+/// and speed commands are sent along with the next poll. The other operations lock the robot in between polls.
+/// Dropping it stops the thread.
+#[derive(Debug)]
+pub struct PollingHal<R: RobotHal + Send + 'static> {
+    /// The robot, shared with the polling thread.
+    robot: Arc<Mutex<R>>,
+    /// The capabilities of the robot, read when spawning the thread.
+    capabilities: Capabilities,
+    /// The handle to the samples.
+    handle: PollingHandle,
+    /// The polling thread.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<R: RobotHal + Send + 'static> PollingHal<R> {
+    /// Polls the robot once and spawns the thread that keeps polling it.
+    ///
+    /// # Arguments
+    ///
+    /// * `robot` - The robot to poll.
+    /// * `period` - The time between polls. When a poll takes longer, the next one starts right after it.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PollingHal)` - The robot being polled.
+    /// * `Err(HalError)` - An error if the first poll fails.
+    pub fn spawn(mut robot: R, period: Duration) -> Result<Self, HalError> {
+        let timestamp = Instant::now();
+        let state = robot.poll_state(period.as_secs_f64())?;
+        let shared = Arc::new(Shared {
+            status: Mutex::new(PollingStatus {
+                sample: Sample {
+                    state,
+                    timestamp,
+                    sequence: 0,
+                },
+                error: None,
+                connection_status: robot.connection_status(),
+                motor_speed: None,
+                stop_generation: 0,
+                stopping: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let capabilities = robot.capabilities();
+        let robot = Arc::new(Mutex::new(robot));
+        let thread = {
+            let robot = Arc::clone(&robot);
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || poll_loop(&robot, &shared, period, timestamp))
+        };
+        Ok(PollingHal {
+            robot,
+            capabilities,
+            handle: PollingHandle { shared },
+            thread: Some(thread),
+        })
+    }
+
+    /// Gets a shared handle to the samples.
+    pub fn handle(&self) -> PollingHandle {
+        self.handle.clone()
+    }
+}
+
+impl<R: RobotHal + Send + 'static> Drop for PollingHal<R> {
+    fn drop(&mut self) {
+        self.handle.shared.status.lock().unwrap().stopping = true;
+        self.handle.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::error!("The polling thread panicked");
+            }
+        }
+    }
+}
+
+impl<R: RobotHal + Send + 'static> RobotHal for PollingHal<R> {
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities.clone()
+    }
+
+    fn poll_state(&mut self, _delta_time: f64) -> Result<HalState, HalError> {
+        let status = self.handle.shared.status.lock().unwrap();
+        match &status.error {
+            Some(error) => Err(HalError::PollingFailed { error: error.clone() }),
+            None => Ok(status.sample.state.clone()),
+        }
+    }
+
+    fn set_motor_speed(&mut self, left_speed: f64, right_speed: f64) -> Result<(), HalError> {
+        self.handle.set_motor_speed(left_speed, right_speed);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), HalError> {
+        // Drop a pending command, and the one the polling thread may have taken but not sent yet, so that they do
+        // not restart the wheels. The stop is counted before waiting for the robot, so that the polling thread
+        // drops its command whether it gets the robot first or not.
+        {
+            let mut status = self.handle.shared.status.lock().unwrap();
+            status.motor_speed = None;
+            status.stop_generation += 1;
+        }
+        self.robot.lock().unwrap().stop()
+    }
+
+    fn connection_status(&self) -> ConnectionStatus {
+        self.handle.shared.status.lock().unwrap().connection_status.clone()
+    }
+
+    fn read_gpio_inputs(&mut self) -> Result<Vec<(String, GpioValue)>, HalError> {
+        self.robot.lock().unwrap().read_gpio_inputs()
+    }
+
+    fn set_pid_gains(&mut self, pid_gains: PidGains) -> Result<(), HalError> {
+        self.robot.lock().unwrap().set_pid_gains(pid_gains)
+    }
+
+    fn read_pid_gains(&mut self) -> Result<PidGains, HalError> {
+        self.robot.lock().unwrap().read_pid_gains()
+    }
+
+    fn take_comm_stats(&mut self) -> CommStats {
+        self.robot.lock().unwrap().take_comm_stats()
+    }
+
+    fn last_speed_clamp(&self) -> Option<SpeedClamp> {
+        self.robot.lock().unwrap().last_speed_clamp()
+    }
+
+    fn motors_stopped_by_timeout(&self) -> bool {
+        self.robot.lock().unwrap().motors_stopped_by_timeout()
+    }
+}
+
+// Polls the robot every `period` until asked to stop, measuring the time between samples with the monotonic clock.
+fn poll_loop<R: RobotHal>(robot: &Mutex<R>, shared: &Shared, period: Duration, mut last_timestamp: Instant) {
+    let mut deadline = last_timestamp + period;
+    loop {
+        let (motor_speed, stop_generation) = {
+            let status = shared.status.lock().unwrap();
+            let (mut status, _) = shared
+                .changed
+                .wait_timeout_while(status, deadline.saturating_duration_since(Instant::now()), |status| {
+                    !status.stopping
+                })
+                .unwrap();
+            if status.stopping {
+                return;
+            }
+            (status.motor_speed.take(), status.stop_generation)
+        };
+        let timestamp = Instant::now();
+        let (result, motor_speed, connection_status) = {
+            let mut robot = robot.lock().unwrap();
+            // A stop that came in since the speed was taken drops it.
+            let motor_speed = motor_speed.filter(|_| shared.status.lock().unwrap().stop_generation == stop_generation);
+            let result = robot.cycle(motor_speed, (timestamp - last_timestamp).as_secs_f64());
+            (result, motor_speed, robot.connection_status())
+        };
+        let mut status = shared.status.lock().unwrap();
+        match result {
+            Ok(state) => {
+                status.sample = Sample {
+                    state,
+                    timestamp,
+                    sequence: status.sample.sequence + 1,
+                };
+                status.error = None;
+                last_timestamp = timestamp;
+            }
+            Err(err) => {
+                status.error = Some(err.to_string());
+                // Send the speed again with the next poll, unless a newer one arrives or the robot was stopped.
+                if status.stop_generation == stop_generation {
+                    status.motor_speed = status.motor_speed.or(motor_speed);
+                }
+            }
+        }
+        status.connection_status = connection_status;
+        shared.changed.notify_all();
+        // Keep the schedule, unless the poll overran it.
+        deadline = (deadline + period).max(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+    use crate::core::hal::robot::mock::MockRobot;
+    use crate::core::hal::{Hal, HalConfig};
+    use crate::core::sim::{SimConfig, SimHal};
+
+    #[test]
+    fn test_polling_hal() {
+        let hal_config = HalConfig {
+            timeout: 1000,
+            ..Default::default()
+        };
+        let hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(EmulatorConfig::default())).unwrap();
+        let period = Duration::from_millis(20);
+        let mut polling_hal = PollingHal::spawn(hal, period).unwrap();
+        let handle = polling_hal.handle();
+        assert_eq!(handle.latest_sample().sequence, 0);
+
+        // Commands can come from any thread.
+        std::thread::spawn({
+            let handle = handle.clone();
+            move || handle.set_motor_speed(5.0, -5.0)
+        })
+        .join()
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        let first = handle.latest_sample();
+        let mut second = first.clone();
+        for _ in 0..10 {
+            let next = handle.wait_for_sample(second.sequence, Duration::from_secs(1)).unwrap();
+            assert_eq!(next.sequence, second.sequence + 1);
+            assert!(next.timestamp > second.timestamp);
+            second = next;
+        }
+        // On average, the samples follow the schedule, as the emulated exchanges take a few milliseconds.
+        let mean_interval = (second.timestamp - first.timestamp) / 10;
+        assert!(mean_interval >= period / 2 && mean_interval <= period * 5);
+        // The emulated encoders only change at the rate of its PID loop, so check the distance travelled.
+        assert!(second.state.left_wheel_state.position > first.state.left_wheel_state.position);
+        assert!(second.state.right_wheel_state.position < first.state.right_wheel_state.position);
+        assert!(handle.last_error().is_none());
+
+        // Through the RobotHal interface, the latest sample is returned whatever the delta time.
+        let state = polling_hal.poll_state(123.0).unwrap();
+        assert!(state.left_wheel_state.position >= second.state.left_wheel_state.position);
+        assert!(polling_hal.take_comm_stats().total().exchanges > 20);
+        polling_hal.stop().unwrap();
+        drop(polling_hal);
+        // The thread is gone, no more samples are polled.
+        let last = handle.latest_sample();
+        assert!(handle.wait_for_sample(last.sequence, period * 3).is_none());
+    }
+
+    #[test]
+    fn test_polling_hal_measures_delta_time() {
+        let start = Instant::now();
+        let polling_hal = PollingHal::spawn(SimHal::new(SimConfig::default()), Duration::from_millis(10)).unwrap();
+        let handle = polling_hal.handle();
+        handle.set_motor_speed(4.0, 4.0);
+        std::thread::sleep(Duration::from_millis(1000));
+        let sample = handle.latest_sample();
+        // The simulation advances by the measured time between samples, so its time follows the wall clock.
+        let elapsed = (sample.timestamp - start).as_secs_f64();
+        let expected_position = 4.0 * (elapsed - 0.1 * (1.0 - (-elapsed / 0.1).exp()));
+        assert!((sample.state.left_wheel_state.position - expected_position).abs() < 0.3);
+    }
+
+    #[test]
+    fn test_polling_hal_stop_drops_taken_command() {
+        let mut polling_hal = PollingHal::spawn(MockRobot::default(), Duration::from_millis(100)).unwrap();
+        let handle = polling_hal.handle();
+        let robot = Arc::clone(&polling_hal.robot);
+        // Wait for a poll, so that the thread waits for the next one.
+        handle.wait_for_sample(0, Duration::from_secs(5)).unwrap();
+
+        // Keep the polling thread waiting for the robot once it takes the command.
+        let robot_guard = robot.lock().unwrap();
+        handle.set_motor_speed(5.0, 5.0);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.shared.status.lock().unwrap().motor_speed.is_some() {
+            assert!(Instant::now() < deadline, "The polling thread did not take the command");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let stopping = std::thread::spawn(move || {
+            polling_hal.stop().unwrap();
+            polling_hal
+        });
+        while handle.shared.status.lock().unwrap().stop_generation == 0 {
+            assert!(Instant::now() < deadline, "The stop did not start");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        drop(robot_guard);
+        let polling_hal = stopping.join().unwrap();
+
+        // Whoever got the robot first, the command taken before the stop never reaches it.
+        let sequence = handle.latest_sample().sequence;
+        handle.wait_for_sample(sequence, Duration::from_secs(1)).unwrap();
+        handle.wait_for_sample(sequence + 1, Duration::from_secs(1)).unwrap();
+        assert_eq!(robot.lock().unwrap().commands, vec![(0.0, 0.0)]);
+        drop(polling_hal);
+    }
+}
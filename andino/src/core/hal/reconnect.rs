@@ -0,0 +1,169 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Reconnection to the hardware when the serial link fails, e.g. when the USB cable glitches.
+
+use std::time::{Duration, Instant};
+
+use crate::core::comm::{HwSerialConnection, HwSerialConnectionError};
+
+/// Opens a new connection to the hardware.
+pub type Connector = Box<dyn FnMut() -> Result<HwSerialConnection, HwSerialConnectionError> + Send>;
+
+/// Configuration of the reconnecting mode of the HAL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Time to wait before the first reconnection attempt in milliseconds.
+    pub initial_backoff: u64,
+    /// Maximum time between reconnection attempts in milliseconds. The wait doubles after each failure.
+    pub max_backoff: u64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: 100,
+            max_backoff: 5000,
+        }
+    }
+}
+
+/// Status of the connection to the hardware.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionStatus {
+    /// Commands are being exchanged with the hardware.
+    Connected,
+    /// The connection was lost and is being reestablished.
+    Reconnecting {
+        /// Failed reconnection attempts so far.
+        attempts: u32,
+        /// The error that caused the disconnection or made the last attempt fail.
+        last_error: String,
+    },
+}
+
+/// Keeps track of the reconnection attempts and their backoff.
+pub(crate) struct Reconnector {
+    connector: Connector,
+    policy: ReconnectPolicy,
+    status: ConnectionStatus,
+    /// Time to wait after the next failed attempt.
+    backoff: Duration,
+    /// Instant from which the next attempt can be made.
+    next_attempt: Instant,
+}
+
+impl std::fmt::Debug for Reconnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reconnector")
+            .field("policy", &self.policy)
+            .field("status", &self.status)
+            .field("backoff", &self.backoff)
+            .field("next_attempt", &self.next_attempt)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Reconnector {
+    pub(crate) fn new(connector: Connector, policy: ReconnectPolicy) -> Self {
+        let backoff = Duration::from_millis(policy.initial_backoff);
+        Reconnector {
+            connector,
+            policy,
+            status: ConnectionStatus::Connected,
+            backoff,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    pub(crate) fn status(&self) -> &ConnectionStatus {
+        &self.status
+    }
+
+    /// Opens a connection, without waiting for the firmware.
+    pub(crate) fn connect(&mut self) -> Result<HwSerialConnection, HwSerialConnectionError> {
+        (self.connector)()
+    }
+
+    /// Whether a reconnection attempt can be made now.
+    pub(crate) fn attempt_due(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+
+    /// Records that the connection was lost, scheduling the first reconnection attempt.
+    pub(crate) fn disconnected(&mut self, error: &HwSerialConnectionError) {
+        self.backoff = Duration::from_millis(self.policy.initial_backoff);
+        self.next_attempt = Instant::now() + self.backoff;
+        self.status = ConnectionStatus::Reconnecting {
+            attempts: 0,
+            last_error: error.to_string(),
+        };
+    }
+
+    /// Records a failed reconnection attempt, doubling the backoff.
+    pub(crate) fn attempt_failed(&mut self, error: &HwSerialConnectionError) {
+        self.backoff = (self.backoff * 2).min(Duration::from_millis(self.policy.max_backoff));
+        self.next_attempt = Instant::now() + self.backoff;
+        let attempts = match &self.status {
+            ConnectionStatus::Reconnecting { attempts, .. } => attempts + 1,
+            ConnectionStatus::Connected => 1,
+        };
+        log::warn!(
+            "Reconnection attempt {} failed, retrying in {} ms: {}",
+            attempts,
+            self.backoff.as_millis(),
+            error
+        );
+        self.status = ConnectionStatus::Reconnecting {
+            attempts,
+            last_error: error.to_string(),
+        };
+    }
+
+    /// Records that the connection was reestablished.
+    pub(crate) fn connected(&mut self) {
+        self.status = ConnectionStatus::Connected;
+        self.backoff = Duration::from_millis(self.policy.initial_backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failing_connector() -> Connector {
+        Box::new(|| {
+            Err(HwSerialConnectionError::SerialPortConnectionError {
+                error: "No such device".to_string(),
+            })
+        })
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let policy = ReconnectPolicy {
+            initial_backoff: 100,
+            max_backoff: 300,
+        };
+        let mut reconnector = Reconnector::new(failing_connector(), policy);
+        let error = reconnector.connect().unwrap_err();
+        reconnector.disconnected(&error);
+        assert!(!reconnector.attempt_due());
+        assert_eq!(reconnector.backoff, Duration::from_millis(100));
+        reconnector.attempt_failed(&error);
+        assert_eq!(reconnector.backoff, Duration::from_millis(200));
+        reconnector.attempt_failed(&error);
+        assert_eq!(reconnector.backoff, Duration::from_millis(300));
+        reconnector.attempt_failed(&error);
+        assert_eq!(reconnector.backoff, Duration::from_millis(300));
+        assert!(matches!(
+            reconnector.status(),
+            ConnectionStatus::Reconnecting { attempts: 3, .. }
+        ));
+
+        reconnector.connected();
+        assert_eq!(reconnector.status(), &ConnectionStatus::Connected);
+        assert_eq!(reconnector.backoff, Duration::from_millis(100));
+    }
+}
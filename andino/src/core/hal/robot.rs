@@ -0,0 +1,334 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Interface to a differential drive robot, whatever drives its wheels.
+//!
+//! [`RobotHal`] is what a control loop needs from the robot: drive the wheels, poll their state and stop.
+//! [`Hal`] implements it over the serial link to the firmware; a simulator, a replay or a mock can implement it
+//! too and be driven by the same code.
+
+use super::{ConnectionStatus, Hal, HalError, HalState, PidGains, SpeedClamp, gpio_input_pins};
+use crate::core::comm::{CommStats, SerialCommandKind};
+use crate::core::gpio::GpioValue;
+
+/// The optional features of a robot.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    /// Whether the gains of the PID speed controllers can be set.
+    pub set_pid_gains: bool,
+    /// Whether the gains of the PID speed controllers can be read.
+    pub read_pid_gains: bool,
+    /// The names of the GPIO inputs [`RobotHal::read_gpio_inputs`] reads.
+    pub gpio_inputs: Vec<String>,
+    /// Whether the robot is reached over a link whose statistics [`RobotHal::take_comm_stats`] returns.
+    pub comm_stats: bool,
+}
+
+/// A differential drive robot.
+///
+/// Only driving the wheels and polling their state are required. The optional features have defaults that do
+/// nothing or fail with [`HalError::UnsupportedCommand`], and are listed by [`RobotHal::capabilities`].
+pub trait RobotHal {
+    /// Gets the optional features of the robot.
+    fn capabilities(&self) -> Capabilities;
+
+    /// Reads the state of the wheels.
+    ///
+    /// # Arguments
+    ///
+    /// * `delta_time` - The time elapsed since the last update in seconds.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(HalState)` - The state of the wheels.
+    /// * `Err(HalError)` - An error if the state cannot be read.
+    fn poll_state(&mut self, delta_time: f64) -> Result<HalState, HalError>;
+
+    /// Sets the speed of the wheels.
+    ///
+    /// # Arguments
+    ///
+    /// * `left_speed` - The speed of the left wheel in rads per second.
+    /// * `right_speed` - The speed of the right wheel in rads per second.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the speed was set.
+    /// * `Err(HalError)` - An error if the speed cannot be set.
+    fn set_motor_speed(&mut self, left_speed: f64, right_speed: f64) -> Result<(), HalError>;
+
+    /// Sets the speed of the wheels, if given, and reads their state.
+    ///
+    /// The default calls [`RobotHal::set_motor_speed`] and [`RobotHal::poll_state`]; [`Hal`] does both in a single
+    /// round trip.
+    ///
+    /// # Arguments
+    ///
+    /// * `motor_speed` - The speed of the left and right wheels in rads per second, `None` to leave it unchanged.
+    /// * `delta_time` - The time elapsed since the last update in seconds.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(HalState)` - The state of the wheels.
+    /// * `Err(HalError)` - An error if the speed cannot be set or the state cannot be read.
+    fn cycle(&mut self, motor_speed: Option<(f64, f64)>, delta_time: f64) -> Result<HalState, HalError> {
+        if let Some((left_speed, right_speed)) = motor_speed {
+            self.set_motor_speed(left_speed, right_speed)?;
+        }
+        self.poll_state(delta_time)
+    }
+
+    /// Stops the wheels.
+    fn stop(&mut self) -> Result<(), HalError> {
+        self.set_motor_speed(0.0, 0.0)
+    }
+
+    /// Gets the status of the connection to the robot. The default is always connected.
+    fn connection_status(&self) -> ConnectionStatus {
+        ConnectionStatus::Connected
+    }
+
+    /// Reads the GPIO inputs listed in the capabilities. The default has none.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(String, GpioValue)>)` - The name and value of every input.
+    /// * `Err(HalError)` - An error if any of the inputs cannot be read.
+    fn read_gpio_inputs(&mut self) -> Result<Vec<(String, GpioValue)>, HalError> {
+        Ok(Vec::new())
+    }
+
+    /// Sets the gains of the PID speed controllers. The default is unsupported.
+    fn set_pid_gains(&mut self, _pid_gains: PidGains) -> Result<(), HalError> {
+        Err(HalError::UnsupportedCommand {
+            error: "The robot does not support setting the PID gains".to_string(),
+        })
+    }
+
+    /// Reads the gains of the PID speed controllers. The default is unsupported.
+    fn read_pid_gains(&mut self) -> Result<PidGains, HalError> {
+        Err(HalError::UnsupportedCommand {
+            error: "The robot does not support reading the PID gains".to_string(),
+        })
+    }
+
+    /// Gets the statistics of the link to the robot since the last call and starts over.
+    /// The default has no link, so the statistics are empty.
+    fn take_comm_stats(&mut self) -> CommStats {
+        CommStats::default()
+    }
+
+    /// Gets how the latest speed request was limited. The default has no limits.
+    fn last_speed_clamp(&self) -> Option<SpeedClamp> {
+        None
+    }
+
+    /// Whether the wheels were stopped because speed commands stopped arriving, until the next command.
+    /// The default has no command timeout.
+    fn motors_stopped_by_timeout(&self) -> bool {
+        false
+    }
+}
+
+impl RobotHal for Hal {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            set_pid_gains: self.firmware_info().supports(SerialCommandKind::SetPIDValues),
+            read_pid_gains: self.firmware_info().supports(SerialCommandKind::ReadPIDValues),
+            gpio_inputs: gpio_input_pins(&self.gpio_pins)
+                .into_iter()
+                .map(|pin_config| pin_config.name)
+                .collect(),
+            comm_stats: true,
+        }
+    }
+
+    fn poll_state(&mut self, delta_time: f64) -> Result<HalState, HalError> {
+        Hal::poll_state(self, delta_time)
+    }
+
+    fn set_motor_speed(&mut self, left_speed: f64, right_speed: f64) -> Result<(), HalError> {
+        Hal::set_motor_speed(self, left_speed, right_speed)
+    }
+
+    fn cycle(&mut self, motor_speed: Option<(f64, f64)>, delta_time: f64) -> Result<HalState, HalError> {
+        Hal::cycle(self, motor_speed, delta_time)
+    }
+
+    fn stop(&mut self) -> Result<(), HalError> {
+        Hal::stop(self)
+    }
+
+    fn connection_status(&self) -> ConnectionStatus {
+        Hal::connection_status(self)
+    }
+
+    fn read_gpio_inputs(&mut self) -> Result<Vec<(String, GpioValue)>, HalError> {
+        Hal::read_gpio_inputs(self)
+    }
+
+    fn set_pid_gains(&mut self, pid_gains: PidGains) -> Result<(), HalError> {
+        Hal::set_pid_gains(self, pid_gains)
+    }
+
+    fn read_pid_gains(&mut self) -> Result<PidGains, HalError> {
+        Hal::read_pid_gains(self)
+    }
+
+    fn take_comm_stats(&mut self) -> CommStats {
+        Hal::take_comm_stats(self)
+    }
+
+    fn last_speed_clamp(&self) -> Option<SpeedClamp> {
+        Hal::last_speed_clamp(self).cloned()
+    }
+
+    fn motors_stopped_by_timeout(&self) -> bool {
+        Hal::motors_stopped_by_timeout(self)
+    }
+}
+
+impl<R: RobotHal + ?Sized> RobotHal for Box<R> {
+    fn capabilities(&self) -> Capabilities {
+        (**self).capabilities()
+    }
+
+    fn poll_state(&mut self, delta_time: f64) -> Result<HalState, HalError> {
+        (**self).poll_state(delta_time)
+    }
+
+    fn set_motor_speed(&mut self, left_speed: f64, right_speed: f64) -> Result<(), HalError> {
+        (**self).set_motor_speed(left_speed, right_speed)
+    }
+
+    fn cycle(&mut self, motor_speed: Option<(f64, f64)>, delta_time: f64) -> Result<HalState, HalError> {
+        (**self).cycle(motor_speed, delta_time)
+    }
+
+    fn stop(&mut self) -> Result<(), HalError> {
+        (**self).stop()
+    }
+
+    fn connection_status(&self) -> ConnectionStatus {
+        (**self).connection_status()
+    }
+
+    fn read_gpio_inputs(&mut self) -> Result<Vec<(String, GpioValue)>, HalError> {
+        (**self).read_gpio_inputs()
+    }
+
+    fn set_pid_gains(&mut self, pid_gains: PidGains) -> Result<(), HalError> {
+        (**self).set_pid_gains(pid_gains)
+    }
+
+    fn read_pid_gains(&mut self) -> Result<PidGains, HalError> {
+        (**self).read_pid_gains()
+    }
+
+    fn take_comm_stats(&mut self) -> CommStats {
+        (**self).take_comm_stats()
+    }
+
+    fn last_speed_clamp(&self) -> Option<SpeedClamp> {
+        (**self).last_speed_clamp()
+    }
+
+    fn motors_stopped_by_timeout(&self) -> bool {
+        (**self).motors_stopped_by_timeout()
+    }
+}
+
+/// A robot for the tests of the wrappers of [`RobotHal`].
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use crate::core::sensors::WheelState;
+
+    // A robot whose wheels reach the commanded speed at once, recording the speed commands.
+    #[derive(Debug, Default)]
+    pub(crate) struct MockRobot {
+        pub(crate) speed: (f64, f64),
+        pub(crate) position: (f64, f64),
+        pub(crate) commands: Vec<(f64, f64)>,
+    }
+
+    impl RobotHal for MockRobot {
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::default()
+        }
+
+        fn poll_state(&mut self, delta_time: f64) -> Result<HalState, HalError> {
+            self.position.0 += self.speed.0 * delta_time;
+            self.position.1 += self.speed.1 * delta_time;
+            Ok(HalState {
+                left_wheel_state: WheelState {
+                    velocity: self.speed.0,
+                    position: self.position.0,
+                },
+                right_wheel_state: WheelState {
+                    velocity: self.speed.1,
+                    position: self.position.1,
+                },
+            })
+        }
+
+        fn set_motor_speed(&mut self, left_speed: f64, right_speed: f64) -> Result<(), HalError> {
+            self.speed = (left_speed, right_speed);
+            self.commands.push(self.speed);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+    use crate::core::hal::HalConfig;
+    use mock::MockRobot;
+
+    // Drives a robot forward for 0.3 s and stops it, returning the distance travelled by the left wheel.
+    // `wait` lets the time pass for robots that do not integrate the `delta_time` they are given.
+    fn drive(robot: &mut dyn RobotHal, wait: impl FnOnce()) -> f64 {
+        let start = robot.poll_state(0.0).unwrap().left_wheel_state.position;
+        robot.cycle(Some((5.0, 5.0)), 0.0).unwrap();
+        wait();
+        let state = robot.cycle(None, 0.3).unwrap();
+        robot.stop().unwrap();
+        state.left_wheel_state.position - start
+    }
+
+    #[test]
+    fn test_mock_robot() {
+        let mut robot = MockRobot::default();
+        assert!((drive(&mut robot, || {}) - 1.5).abs() < 1e-9);
+        assert_eq!(robot.speed, (0.0, 0.0));
+        assert_eq!(robot.commands, vec![(5.0, 5.0), (0.0, 0.0)]);
+        assert_eq!(robot.connection_status(), ConnectionStatus::Connected);
+        assert!(robot.read_gpio_inputs().unwrap().is_empty());
+        assert!(matches!(
+            robot.read_pid_gains(),
+            Err(HalError::UnsupportedCommand { .. })
+        ));
+        assert_eq!(robot.take_comm_stats(), CommStats::default());
+    }
+
+    #[test]
+    fn test_hal_as_robot() {
+        let hal_config = HalConfig {
+            timeout: 1000,
+            ..Default::default()
+        };
+        let hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(EmulatorConfig::default())).unwrap();
+        let mut robot: Box<dyn RobotHal> = Box::new(hal);
+        let capabilities = robot.capabilities();
+        assert!(capabilities.set_pid_gains);
+        assert!(capabilities.read_pid_gains);
+        assert!(capabilities.comm_stats);
+        assert!(capabilities.gpio_inputs.is_empty());
+        // The emulated wheels move in real time.
+        assert!(drive(&mut robot, || std::thread::sleep(std::time::Duration::from_millis(300))) > 0.0);
+        assert!(robot.take_comm_stats().total().exchanges > 0);
+    }
+}
@@ -0,0 +1,388 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! State machine of a robot, with a latched emergency stop.
+//!
+//! [`SupervisedHal`] wraps a [`RobotHal`] and tracks whether it is initializing, ready, driving, emergency
+//! stopped or faulted. Once emergency stopped, speed commands are rejected until the stop is explicitly reset,
+//! so that whoever triggered it decides when the robot may move again.
+
+use super::{Capabilities, ConnectionStatus, HalError, HalState, PidGains, RobotHal, SpeedClamp};
+use crate::core::comm::CommStats;
+use crate::core::gpio::GpioValue;
+
+/// The state of a robot.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RobotState {
+    /// The wheels were not polled successfully yet.
+    Initializing,
+    /// The wheels are commanded to stand still, or were stopped because speed commands stopped arriving.
+    Ready,
+    /// The wheels are commanded to move.
+    Driving,
+    /// Emergency stopped: the wheels were stopped and speed commands are rejected until reset.
+    EStopped {
+        /// Why the emergency stop was triggered.
+        reason: String,
+    },
+    /// The latest poll failed. It recovers, ready, with the next successful poll.
+    Faulted {
+        /// The error of the latest poll.
+        error: String,
+    },
+}
+
+impl RobotState {
+    /// Gets the name of the state, e.g. `e_stopped`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RobotState::Initializing => "initializing",
+            RobotState::Ready => "ready",
+            RobotState::Driving => "driving",
+            RobotState::EStopped { .. } => "e_stopped",
+            RobotState::Faulted { .. } => "faulted",
+        }
+    }
+
+    /// Gets why the robot is emergency stopped or faulted, `None` in the other states.
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            RobotState::EStopped { reason } => Some(reason),
+            RobotState::Faulted { error } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for RobotState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reason() {
+            Some(reason) => write!(f, "{} ({})", self.name(), reason),
+            None => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// A robot with a state machine and a latched emergency stop, see the [module documentation](self).
+///
+/// It is a [`RobotHal`] too. While emergency stopped, [`RobotHal::set_motor_speed`] fails with
+/// [`HalError::EStopped`] and [`RobotHal::cycle`] ignores the speed, so the wheels are still polled.
+#[derive(Debug)]
+pub struct SupervisedHal<R: RobotHal> {
+    /// The supervised robot.
+    robot: R,
+    /// The current state.
+    state: RobotState,
+}
+
+impl<R: RobotHal> SupervisedHal<R> {
+    /// Starts supervising a robot, initializing until its wheels are polled.
+    ///
+    /// # Arguments
+    ///
+    /// * `robot` - The robot to supervise.
+    pub fn new(robot: R) -> Self {
+        SupervisedHal {
+            robot,
+            state: RobotState::Initializing,
+        }
+    }
+
+    /// Gets the current state.
+    pub fn state(&self) -> &RobotState {
+        &self.state
+    }
+
+    /// Triggers the emergency stop: stops the wheels and rejects speed commands until [`SupervisedHal::reset`].
+    ///
+    /// The robot is emergency stopped even if stopping the wheels fails. Triggering it again updates the reason.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Why the emergency stop is triggered, reported by the state.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the wheels were stopped.
+    /// * `Err(HalError)` - An error if the stop command fails.
+    pub fn estop(&mut self, reason: impl Into<String>) -> Result<(), HalError> {
+        let reason = reason.into();
+        log::warn!("Emergency stop: {}", reason);
+        self.state = RobotState::EStopped { reason };
+        self.robot.stop()
+    }
+
+    /// Resets the emergency stop, leaving the robot ready with the wheels stopped. It does nothing otherwise.
+    pub fn reset(&mut self) {
+        if let RobotState::EStopped { reason } = &self.state {
+            log::info!("Emergency stop reset, it was triggered by: {}", reason);
+            self.state = RobotState::Ready;
+        }
+    }
+
+    // Moves to the state that follows a poll, unless emergency stopped.
+    fn polled(&mut self, result: &Result<HalState, HalError>) {
+        if matches!(self.state, RobotState::EStopped { .. }) {
+            return;
+        }
+        match result {
+            Err(err) => {
+                self.state = RobotState::Faulted { error: err.to_string() };
+            }
+            Ok(_) if matches!(self.state, RobotState::Initializing | RobotState::Faulted { .. }) => {
+                self.state = RobotState::Ready;
+            }
+            Ok(_) if self.state == RobotState::Driving && self.robot.motors_stopped_by_timeout() => {
+                log::debug!("The wheels were stopped as speed commands stopped arriving");
+                self.state = RobotState::Ready;
+            }
+            Ok(_) => {}
+        }
+    }
+
+    // Moves to the state that follows a speed command sent to the robot, unless emergency stopped.
+    // The wheels move even before the first poll or while faulted, so they are driving then too.
+    fn commanded(&mut self, left_speed: f64, right_speed: f64) {
+        let moving = left_speed != 0.0 || right_speed != 0.0;
+        match self.state {
+            RobotState::EStopped { .. } => {}
+            RobotState::Ready | RobotState::Driving if !moving => self.state = RobotState::Ready,
+            RobotState::Initializing | RobotState::Faulted { .. } if !moving => {}
+            _ => self.state = RobotState::Driving,
+        }
+    }
+}
+
+impl<R: RobotHal> RobotHal for SupervisedHal<R> {
+    fn capabilities(&self) -> Capabilities {
+        self.robot.capabilities()
+    }
+
+    fn poll_state(&mut self, delta_time: f64) -> Result<HalState, HalError> {
+        let result = self.robot.poll_state(delta_time);
+        self.polled(&result);
+        result
+    }
+
+    fn set_motor_speed(&mut self, left_speed: f64, right_speed: f64) -> Result<(), HalError> {
+        if let RobotState::EStopped { reason } = &self.state {
+            return Err(HalError::EStopped { reason: reason.clone() });
+        }
+        self.robot.set_motor_speed(left_speed, right_speed)?;
+        self.commanded(left_speed, right_speed);
+        Ok(())
+    }
+
+    fn cycle(&mut self, motor_speed: Option<(f64, f64)>, delta_time: f64) -> Result<HalState, HalError> {
+        let motor_speed = match (&self.state, motor_speed) {
+            (RobotState::EStopped { reason }, Some(_)) => {
+                log::debug!("Emergency stopped ({}), ignoring the speed command", reason);
+                None
+            }
+            (_, motor_speed) => motor_speed,
+        };
+        let result = self.robot.cycle(motor_speed, delta_time);
+        self.polled(&result);
+        if let (Ok(_), Some((left_speed, right_speed))) = (&result, motor_speed) {
+            self.commanded(left_speed, right_speed);
+        }
+        result
+    }
+
+    fn stop(&mut self) -> Result<(), HalError> {
+        self.robot.stop()?;
+        self.commanded(0.0, 0.0);
+        Ok(())
+    }
+
+    fn connection_status(&self) -> ConnectionStatus {
+        self.robot.connection_status()
+    }
+
+    fn read_gpio_inputs(&mut self) -> Result<Vec<(String, GpioValue)>, HalError> {
+        self.robot.read_gpio_inputs()
+    }
+
+    fn set_pid_gains(&mut self, pid_gains: PidGains) -> Result<(), HalError> {
+        self.robot.set_pid_gains(pid_gains)
+    }
+
+    fn read_pid_gains(&mut self) -> Result<PidGains, HalError> {
+        self.robot.read_pid_gains()
+    }
+
+    fn take_comm_stats(&mut self) -> CommStats {
+        self.robot.take_comm_stats()
+    }
+
+    fn last_speed_clamp(&self) -> Option<SpeedClamp> {
+        self.robot.last_speed_clamp()
+    }
+
+    fn motors_stopped_by_timeout(&self) -> bool {
+        self.robot.motors_stopped_by_timeout()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::emulator::{EmulatorConfig, FirmwareEmulator};
+    use crate::core::hal::robot::mock::MockRobot;
+    use crate::core::hal::{Hal, HalConfig, PollingHal};
+    use crate::core::sim::{SimConfig, SimHal};
+    use std::time::Duration;
+
+    // A simulated robot whose polls fail while `failing` is set.
+    struct FlakyRobot {
+        sim: SimHal,
+        failing: bool,
+    }
+
+    impl RobotHal for FlakyRobot {
+        fn capabilities(&self) -> Capabilities {
+            self.sim.capabilities()
+        }
+
+        fn poll_state(&mut self, delta_time: f64) -> Result<HalState, HalError> {
+            if self.failing {
+                return Err(HalError::Disconnected {
+                    error: "unplugged".to_string(),
+                });
+            }
+            self.sim.poll_state(delta_time)
+        }
+
+        fn set_motor_speed(&mut self, left_speed: f64, right_speed: f64) -> Result<(), HalError> {
+            self.sim.set_motor_speed(left_speed, right_speed)
+        }
+    }
+
+    #[test]
+    fn test_supervised_hal_states() {
+        let mut robot = SupervisedHal::new(SimHal::new(SimConfig::default()));
+        assert_eq!(robot.state(), &RobotState::Initializing);
+        robot.cycle(None, 0.01).unwrap();
+        assert_eq!(robot.state(), &RobotState::Ready);
+        robot.cycle(Some((3.0, 3.0)), 0.01).unwrap();
+        assert_eq!(robot.state(), &RobotState::Driving);
+        robot.set_motor_speed(0.0, 0.0).unwrap();
+        assert_eq!(robot.state(), &RobotState::Ready);
+        robot.set_motor_speed(3.0, -3.0).unwrap();
+        assert_eq!(robot.state(), &RobotState::Driving);
+        robot.stop().unwrap();
+        assert_eq!(robot.state(), &RobotState::Ready);
+        assert_eq!(RobotState::Driving.to_string(), "driving");
+    }
+
+    #[test]
+    fn test_supervised_hal_driving_before_the_first_poll() {
+        let mut robot = SupervisedHal::new(SimHal::new(SimConfig::default()));
+        robot.set_motor_speed(0.0, 0.0).unwrap();
+        assert_eq!(robot.state(), &RobotState::Initializing);
+        robot.set_motor_speed(3.0, 3.0).unwrap();
+        assert_eq!(robot.state(), &RobotState::Driving);
+        robot.poll_state(0.01).unwrap();
+        assert_eq!(robot.state(), &RobotState::Driving);
+    }
+
+    #[test]
+    fn test_supervised_hal_command_timeout() {
+        let hal_config = HalConfig {
+            timeout: 1000,
+            command_timeout: Some(100),
+            ..Default::default()
+        };
+        let hal = Hal::with_transport(&hal_config, FirmwareEmulator::new(EmulatorConfig::default())).unwrap();
+        let mut robot = SupervisedHal::new(hal);
+        robot.cycle(Some((5.0, 5.0)), 0.01).unwrap();
+        assert_eq!(robot.state(), &RobotState::Driving);
+        robot.cycle(None, 0.01).unwrap();
+        assert_eq!(robot.state(), &RobotState::Driving);
+        // Without a fresh command, the motors are stopped on the next poll.
+        std::thread::sleep(Duration::from_millis(150));
+        robot.cycle(None, 0.15).unwrap();
+        assert!(robot.motors_stopped_by_timeout());
+        assert_eq!(robot.state(), &RobotState::Ready);
+        robot.set_motor_speed(5.0, 5.0).unwrap();
+        assert!(!robot.motors_stopped_by_timeout());
+        assert_eq!(robot.state(), &RobotState::Driving);
+        std::thread::sleep(Duration::from_millis(150));
+        robot.poll_state(0.15).unwrap();
+        assert_eq!(robot.state(), &RobotState::Ready);
+    }
+
+    #[test]
+    fn test_supervised_hal_estop_is_latched() {
+        let mut robot = SupervisedHal::new(SimHal::new(SimConfig::default()));
+        robot.set_motor_speed(5.0, 5.0).unwrap();
+        for _ in 0..50 {
+            robot.poll_state(0.01).unwrap();
+        }
+        robot.estop("bumper pressed").unwrap();
+        assert_eq!(robot.state().to_string(), "e_stopped (bumper pressed)");
+        assert!(matches!(
+            robot.set_motor_speed(5.0, 5.0),
+            Err(HalError::EStopped { .. })
+        ));
+        // Speed commands along with a poll are ignored, and the wheels come to a halt.
+        let mut state = robot.cycle(Some((5.0, 5.0)), 0.01).unwrap();
+        for _ in 0..100 {
+            state = robot.cycle(Some((5.0, 5.0)), 0.01).unwrap();
+        }
+        assert_eq!(state.left_wheel_state.velocity, 0.0);
+        assert_eq!(robot.state().name(), "e_stopped");
+
+        // Only an explicit reset lets the robot move again.
+        robot.reset();
+        assert_eq!(robot.state(), &RobotState::Ready);
+        robot.set_motor_speed(5.0, 5.0).unwrap();
+        assert_eq!(robot.state(), &RobotState::Driving);
+    }
+
+    #[test]
+    fn test_supervised_hal_faults() {
+        let mut robot = SupervisedHal::new(FlakyRobot {
+            sim: SimHal::new(SimConfig::default()),
+            failing: true,
+        });
+        assert!(robot.poll_state(0.01).is_err());
+        assert_eq!(robot.state().name(), "faulted");
+        assert_eq!(robot.state().reason(), Some("Hardware disconnected: unplugged"));
+        // A speed command moves the wheels all the same.
+        robot.set_motor_speed(1.0, 1.0).unwrap();
+        assert_eq!(robot.state(), &RobotState::Driving);
+        assert!(robot.poll_state(0.01).is_err());
+        assert_eq!(robot.state().name(), "faulted");
+
+        // Recovers with the next successful poll, but not from an emergency stop.
+        robot.estop("operator").unwrap();
+        assert!(robot.poll_state(0.01).is_err());
+        assert_eq!(robot.state().name(), "e_stopped");
+        robot.reset();
+        assert_eq!(robot.state(), &RobotState::Ready);
+        assert!(robot.poll_state(0.01).is_err());
+        robot.robot.failing = false;
+        robot.poll_state(0.01).unwrap();
+        assert_eq!(robot.state(), &RobotState::Ready);
+    }
+
+    #[test]
+    fn test_supervised_polling_hal_estop_drops_pending_command() {
+        let polling_hal = PollingHal::spawn(MockRobot::default(), Duration::from_millis(50)).unwrap();
+        let handle = polling_hal.handle();
+        let mut robot = SupervisedHal::new(polling_hal);
+        let sample = handle.wait_for_sample(0, Duration::from_secs(5)).unwrap();
+        // The command waits for the next poll when the emergency stop fires.
+        robot.set_motor_speed(5.0, 5.0).unwrap();
+        robot.estop("operator").unwrap();
+        let mut sequence = sample.sequence;
+        for _ in 0..3 {
+            let sample = handle.wait_for_sample(sequence, Duration::from_secs(5)).unwrap();
+            assert_eq!(sample.state.left_wheel_state.velocity, 0.0);
+            sequence = sample.sequence;
+        }
+        assert_eq!(robot.state().name(), "e_stopped");
+    }
+}
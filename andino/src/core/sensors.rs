@@ -1,15 +1,65 @@
+use std::f64::consts::PI;
+
+/// An angle in rads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Radians(pub f64);
+
+/// An angular speed in rads per second.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct RadiansPerSecond(pub f64);
+
+/// A count of encoder ticks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ticks(pub i64);
+
+/// A speed in encoder ticks per second, fractional as the firmware only rounds it when commanded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct TicksPerSecond(pub f64);
+
+/// Calibration of a wheel: the resolution of its encoder, its gearbox and the direction of its motor and encoder.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WheelCalibration {
+    /// The number of encoder ticks per revolution of the motor.
+    pub ticks_per_revolution: u64,
+    /// Revolutions of the motor per revolution of the wheel, 1.0 when the encoder turns with the wheel.
+    pub gear_ratio: f64,
+    /// Whether the motor turns the wheel backwards for a positive command, e.g. when mounted mirrored.
+    pub motor_inverted: bool,
+    /// Whether the encoder counts down when the wheel turns forwards.
+    pub encoder_inverted: bool,
+}
+
+impl WheelCalibration {
+    /// Creates the calibration of a wheel driven directly by a motor with the given encoder resolution.
+    pub fn new(ticks_per_revolution: u64) -> Self {
+        WheelCalibration {
+            ticks_per_revolution,
+            gear_ratio: 1.0,
+            motor_inverted: false,
+            encoder_inverted: false,
+        }
+    }
+
+    /// The number of encoder ticks per revolution of the wheel.
+    pub fn ticks_per_wheel_revolution(&self) -> f64 {
+        self.ticks_per_revolution as f64 * self.gear_ratio
+    }
+}
+
 /// Abstract representation of the wheels.
 ///
 /// It provides a way to calculate the angular velocity(rad/s) and position(rad) of the wheels
-/// based on the encoder ticks.
+/// based on the encoder ticks, and to convert speeds of the wheels to speeds of the motors.
 #[derive(Debug)]
 pub struct Wheel {
-    /// The number of ticks per revolution of the wheel.
-    ticks_per_revolution: u64,
-    /// The number of encoder ticks per radian.
-    ticks_per_rad: u64,
-    /// The last ticks count from the encoder.
+    /// The calibration of the wheel.
+    calibration: WheelCalibration,
+    /// The number of encoder ticks per revolution of the wheel, kept exact for the conversions.
+    ticks_per_revolution: f64,
+    /// The last ticks count from the encoder, including the offset.
     last_ticks_count: i64,
+    /// Ticks added to the encoder count to keep it continuous across firmware restarts.
+    ticks_offset: i64,
     /// The current wheel state.
     state: WheelState,
 }
@@ -26,12 +76,16 @@ pub struct WheelState {
 impl Wheel {
     /// Creates a new wheel with the given ticks per revolution.
     pub fn new(ticks_per_revolution: u64) -> Self {
-        // Formula: ticks_per_rad = ticks_per_revolution / (2 * PI)
-        let ticks_per_rad = (ticks_per_revolution as f64 / (2.0 * std::f64::consts::PI)).round() as u64;
+        Self::with_calibration(WheelCalibration::new(ticks_per_revolution))
+    }
+
+    /// Creates a new wheel with the given calibration.
+    pub fn with_calibration(calibration: WheelCalibration) -> Self {
         Self {
-            ticks_per_rad,
-            ticks_per_revolution,
+            calibration,
+            ticks_per_revolution: calibration.ticks_per_wheel_revolution(),
             last_ticks_count: 0,
+            ticks_offset: 0,
             state: WheelState {
                 velocity: 0.0,
                 position: 0.0,
@@ -44,9 +98,40 @@ impl Wheel {
         &self.state
     }
 
-    /// Encoder ticks per radian unit.
-    pub fn ticks_per_rad(&self) -> u64 {
-        self.ticks_per_rad
+    /// Gets the calibration of the wheel.
+    pub fn calibration(&self) -> &WheelCalibration {
+        &self.calibration
+    }
+
+    /// Encoder ticks per radian unit, not rounded.
+    pub fn ticks_per_rad(&self) -> f64 {
+        self.ticks_per_revolution / (2.0 * PI)
+    }
+
+    /// Converts an encoder count of the wheel to its angle.
+    pub fn to_radians(&self, ticks: Ticks) -> Radians {
+        Radians(ticks.0 as f64 / self.ticks_per_revolution * (2.0 * PI))
+    }
+
+    /// Converts a speed of the wheel in encoder ticks per second to its angular speed.
+    pub fn to_radians_per_second(&self, speed: TicksPerSecond) -> RadiansPerSecond {
+        RadiansPerSecond(speed.0 / self.ticks_per_revolution * (2.0 * PI))
+    }
+
+    /// Converts an angular speed of the wheel to encoder ticks per second, the inverse of
+    /// [`Wheel::to_radians_per_second`].
+    pub fn to_ticks_per_second(&self, speed: RadiansPerSecond) -> TicksPerSecond {
+        TicksPerSecond(speed.0 / (2.0 * PI) * self.ticks_per_revolution)
+    }
+
+    /// Converts a speed of the wheel to the speed to command to its motor, in the direction of the motor.
+    pub fn motor_speed(&self, speed: RadiansPerSecond) -> TicksPerSecond {
+        TicksPerSecond(self.motor_direction() * self.to_ticks_per_second(speed).0)
+    }
+
+    /// Gets the sign to apply to the commands of the motor, -1.0 when it is inverted.
+    pub fn motor_direction(&self) -> f64 {
+        if self.calibration.motor_inverted { -1.0 } else { 1.0 }
     }
 
     /// Updates the wheel state based on the given ticks and delta time.
@@ -54,22 +139,50 @@ impl Wheel {
     /// This method should be called periodically to update the state of the wheel.
     /// Consider using a timer or a loop to call this method at regular intervals as
     /// there are calculations that depend on the time elapsed since the last update.
+    /// The ticks are counted as the encoder reports them, and negated when it is inverted.
     pub fn update(&mut self, ticks: i64, delta_time: f64) -> &WheelState {
+        let ticks = self.encoder_direction(ticks) + self.ticks_offset;
         self.update_velocity(ticks, delta_time);
         self.update_position(ticks);
         &self.state
     }
 
+    /// Resets the wheel state, to be called when the encoder count is zeroed.
+    pub fn reset(&mut self) {
+        self.last_ticks_count = 0;
+        self.ticks_offset = 0;
+        self.state = WheelState {
+            velocity: 0.0,
+            position: 0.0,
+        };
+    }
+
+    /// Resynchronizes the wheel with an encoder count that restarted, e.g. after the board rebooted.
+    ///
+    /// The given count is taken as the current position of the wheel, so the position stays continuous
+    /// and the next velocity is measured from it.
+    pub fn resync(&mut self, ticks: i64) {
+        self.ticks_offset = self.last_ticks_count - self.encoder_direction(ticks);
+    }
+
+    // Applies the direction of the encoder to a count it reported.
+    fn encoder_direction(&self, ticks: i64) -> i64 {
+        if self.calibration.encoder_inverted {
+            -ticks
+        } else {
+            ticks
+        }
+    }
+
     // Update the position of the wheel based on the ticks count.
     fn update_position(&mut self, ticks: i64) {
-        self.state.position = (ticks as f64 / self.ticks_per_revolution as f64) * (2.0 * std::f64::consts::PI);
+        self.state.position = self.to_radians(Ticks(ticks)).0;
     }
 
     // Update the angular velocity of the wheel based on the ticks count and delta time.
     fn update_velocity(&mut self, ticks: i64, delta_time: f64) {
         let delta_ticks = ticks - self.last_ticks_count;
-        self.state.velocity =
-            (delta_ticks as f64 / self.ticks_per_revolution as f64) * (2.0 * std::f64::consts::PI / delta_time);
+        self.state.velocity = self.to_radians(Ticks(delta_ticks)).0 / delta_time;
         self.last_ticks_count = ticks;
     }
 }
@@ -96,8 +209,116 @@ mod tests {
     }
 
     #[test]
-    fn test_wheel_ticks_per_rad() {
+    fn test_wheel_reset() {
+        let mut wheel = Wheel::new(1000);
+        wheel.update(500, 1.0);
+        wheel.reset();
+        assert_eq!(wheel.get_state().position, 0.0);
+        assert_eq!(wheel.get_state().velocity, 0.0);
+
+        // The next update measures from the zeroed count.
+        let state = wheel.update(500, 1.0);
+        assert_eq!(state.position, std::f64::consts::PI);
+        assert_eq!(state.velocity, std::f64::consts::PI);
+    }
+
+    #[test]
+    fn test_wheel_resync() {
+        let mut wheel = Wheel::new(1000);
+        wheel.update(500, 1.0);
+        // The encoder count restarts from zero.
+        wheel.resync(0);
+        let state = wheel.update(0, 1.0);
+        assert_eq!(state.position, std::f64::consts::PI);
+        assert_eq!(state.velocity, 0.0);
+        let state = wheel.update(500, 1.0);
+        assert_eq!(state.position, 2. * std::f64::consts::PI);
+        assert_eq!(state.velocity, std::f64::consts::PI);
+
+        wheel.reset();
+        let state = wheel.update(500, 1.0);
+        assert_eq!(state.position, std::f64::consts::PI);
+    }
+
+    #[test]
+    fn test_wheel_calibration() {
+        let calibration = WheelCalibration {
+            gear_ratio: 2.0,
+            motor_inverted: true,
+            encoder_inverted: true,
+            ..WheelCalibration::new(500)
+        };
+        let mut wheel = Wheel::with_calibration(calibration);
+        assert_eq!(wheel.calibration(), &calibration);
+        // A revolution of the wheel is two of the motor, counted down by the encoder.
+        let state = wheel.update(-500, 1.0);
+        assert_eq!(state.position, std::f64::consts::PI);
+        assert_eq!(state.velocity, std::f64::consts::PI);
+        assert_eq!(wheel.motor_speed(RadiansPerSecond(PI)), TicksPerSecond(-500.0));
+        assert_eq!(wheel.motor_direction(), -1.0);
+
+        wheel.resync(0);
+        let state = wheel.update(-500, 1.0);
+        assert_eq!(state.position, 2. * std::f64::consts::PI);
+
         let wheel = Wheel::new(1000);
-        assert_eq!(wheel.ticks_per_rad(), 159);
+        assert_eq!(wheel.motor_speed(RadiansPerSecond(-PI)), TicksPerSecond(-500.0));
+        assert_eq!(wheel.motor_direction(), 1.0);
+    }
+
+    #[test]
+    fn test_wheel_ticks_per_rad() {
+        let wheel = Wheel::new(700);
+        // Not rounded to 111.
+        assert!((wheel.ticks_per_rad() - 111.408_460_164_521_9).abs() < 1e-9);
+        assert_eq!(wheel.to_radians(Ticks(700)), Radians(2.0 * PI));
+    }
+
+    #[test]
+    fn test_wheel_command_measure_round_trip() {
+        let calibrations = [
+            WheelCalibration::new(700),
+            WheelCalibration::new(585),
+            WheelCalibration::new(1000),
+            WheelCalibration {
+                gear_ratio: 34.014,
+                motor_inverted: true,
+                encoder_inverted: true,
+                ..WheelCalibration::new(12)
+            },
+        ];
+        for calibration in calibrations {
+            let wheel = Wheel::with_calibration(calibration);
+            for speed in [-12.5, -PI, -0.01, 0.0, 0.3, 1.0, 7.77] {
+                // The speed measured at the speed commanded is the one requested.
+                let ticks_per_second = wheel.to_ticks_per_second(RadiansPerSecond(speed));
+                let measured = wheel.to_radians_per_second(ticks_per_second).0;
+                assert!(
+                    (measured - speed).abs() <= 1e-12 * speed.abs(),
+                    "{:?} {}",
+                    calibration,
+                    speed
+                );
+
+                // Same through the encoder, counting the ticks of the motor over ten seconds.
+                let mut wheel = Wheel::with_calibration(calibration);
+                let motor_ticks = wheel.motor_speed(RadiansPerSecond(speed)).0 * 10.0;
+                let encoder_ticks = if calibration.motor_inverted == calibration.encoder_inverted {
+                    motor_ticks
+                } else {
+                    -motor_ticks
+                };
+                // Only the rounding of the count to whole ticks is left.
+                let tolerance = wheel.to_radians(Ticks(1)).0 / 10.0;
+                let state = wheel.update(encoder_ticks.round() as i64, 10.0);
+                assert!(
+                    (state.velocity - speed).abs() <= tolerance,
+                    "{:?} {}",
+                    calibration,
+                    speed
+                );
+                assert!((state.position - speed * 10.0).abs() <= tolerance * 10.0);
+            }
+        }
     }
 }
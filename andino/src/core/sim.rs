@@ -0,0 +1,286 @@
+// ***************************************************************************
+// About
+// ***************************************************************************
+//
+//! Kinematic simulation of the Andino wheels, to run without a robot nor a firmware.
+//!
+//! [`SimHal`] is a [`RobotHal`] that models each wheel as a motor with first-order dynamics and an encoder
+//! quantized to the ticks per revolution of its calibration, with optional noise on the speed. The encoder counts go through the
+//! same conversion as in [`Hal`](crate::core::hal::Hal), so it reports the state of the wheels like
+//! `Hal::poll_state` does.
+//!
+//! Simulated time only advances in [`RobotHal::poll_state`], by the given `delta_time`, so that runs with the
+//! same inputs and seed are reproducible.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::core::hal::{Capabilities, HalError, HalState, RobotHal};
+use crate::core::sensors::{RadiansPerSecond, Wheel, WheelCalibration};
+
+/// Configuration of the [`SimHal`].
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    /// Number of encoder ticks per revolution of the wheels.
+    pub motor_ticks_per_revolution: u64,
+    /// The calibration of the left wheel, as in [`HalConfig`](crate::core::hal::HalConfig). When `None`, the motor
+    /// drives the wheel directly, with `motor_ticks_per_revolution` ticks per revolution.
+    pub left_wheel_calibration: Option<WheelCalibration>,
+    /// The calibration of the right wheel, like the left one.
+    pub right_wheel_calibration: Option<WheelCalibration>,
+    /// Time constant of the first-order motor dynamics, in seconds.
+    pub motor_time_constant: f64,
+    /// Speed of the wheels at full power, in encoder ticks per second. Faster commands are saturated.
+    pub max_ticks_per_second: f64,
+    /// Standard deviation of the noise added to the speed of the wheels, in rads per second. Zero disables it.
+    pub speed_noise: f64,
+    /// Seed of the noise generator.
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            motor_ticks_per_revolution: 700,
+            left_wheel_calibration: None,
+            right_wheel_calibration: None,
+            motor_time_constant: 0.1,
+            max_ticks_per_second: 1400.0,
+            speed_noise: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// A simulated motor with its encoder.
+#[derive(Debug, Default)]
+struct SimulatedMotor {
+    /// Continuous position of the wheel in encoder ticks.
+    position: f64,
+    /// Speed of the wheel in encoder ticks per second.
+    speed: f64,
+    /// Commanded speed in encoder ticks per second.
+    target_speed: f64,
+}
+
+impl SimulatedMotor {
+    /// The count reported by the encoder, counting down when it is inverted.
+    fn encoder_count(&self, encoder_inverted: bool) -> i64 {
+        let position = if encoder_inverted {
+            -self.position
+        } else {
+            self.position
+        };
+        position.floor() as i64
+    }
+
+    /// Advances the motor by `dt` seconds, with `noise` ticks per second added to the speed.
+    fn step(&mut self, config: &SimConfig, dt: f64, noise: f64) {
+        self.speed += (self.target_speed - self.speed) * (1.0 - (-dt / config.motor_time_constant).exp());
+        self.position += (self.speed + noise) * dt;
+    }
+}
+
+/// Generator of normally distributed noise, with a seeded [`StdRng`] and the Box-Muller transform.
+#[derive(Debug)]
+struct NoiseGenerator {
+    rng: StdRng,
+}
+
+impl NoiseGenerator {
+    /// Creates a generator yielding the same values for the same seed.
+    fn new(seed: u64) -> Self {
+        NoiseGenerator {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Next uniformly distributed value in (0, 1].
+    fn next_uniform(&mut self) -> f64 {
+        1.0 - self.rng.r#gen::<f64>()
+    }
+
+    /// Next normally distributed value with the given standard deviation.
+    fn next_normal(&mut self, std_dev: f64) -> f64 {
+        if std_dev == 0.0 {
+            return 0.0;
+        }
+        let (u1, u2) = (self.next_uniform(), self.next_uniform());
+        std_dev * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+/// Simulated robot, see the [module documentation](self).
+#[derive(Debug)]
+pub struct SimHal {
+    /// The configuration of the simulation.
+    config: SimConfig,
+    /// Simulated left motor.
+    left_motor: SimulatedMotor,
+    /// Simulated right motor.
+    right_motor: SimulatedMotor,
+    /// Left wheel instance, turning encoder counts into its state.
+    left_wheel: Wheel,
+    /// Right wheel instance, turning encoder counts into its state.
+    right_wheel: Wheel,
+    /// Source of the speed noise.
+    noise: NoiseGenerator,
+}
+
+impl SimHal {
+    /// Creates a new simulated robot, standing still.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration of the simulation.
+    pub fn new(config: SimConfig) -> Self {
+        let default_calibration = WheelCalibration::new(config.motor_ticks_per_revolution);
+        SimHal {
+            left_motor: SimulatedMotor::default(),
+            right_motor: SimulatedMotor::default(),
+            left_wheel: Wheel::with_calibration(config.left_wheel_calibration.unwrap_or(default_calibration)),
+            right_wheel: Wheel::with_calibration(config.right_wheel_calibration.unwrap_or(default_calibration)),
+            noise: NoiseGenerator::new(config.seed),
+            config,
+        }
+    }
+
+    // Converts a speed in rads per second to a target speed of the motors, as the HAL commands the firmware.
+    //
+    // The motors are simulated turning forwards with the wheels: an inverted motor turns the wheel back from the
+    // negated command it gets, so only the encoders are inverted.
+    fn target_speed(&self, wheel: &Wheel, speed: f64) -> f64 {
+        wheel
+            .to_ticks_per_second(RadiansPerSecond(speed))
+            .0
+            .clamp(-self.config.max_ticks_per_second, self.config.max_ticks_per_second)
+    }
+}
+
+impl RobotHal for SimHal {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    fn poll_state(&mut self, delta_time: f64) -> Result<HalState, HalError> {
+        let left_noise = self.noise.next_normal(self.config.speed_noise) * self.left_wheel.ticks_per_rad();
+        let right_noise = self.noise.next_normal(self.config.speed_noise) * self.right_wheel.ticks_per_rad();
+        self.left_motor.step(&self.config, delta_time, left_noise);
+        self.right_motor.step(&self.config, delta_time, right_noise);
+        Ok(HalState {
+            left_wheel_state: self
+                .left_wheel
+                .update(
+                    self.left_motor
+                        .encoder_count(self.left_wheel.calibration().encoder_inverted),
+                    delta_time,
+                )
+                .clone(),
+            right_wheel_state: self
+                .right_wheel
+                .update(
+                    self.right_motor
+                        .encoder_count(self.right_wheel.calibration().encoder_inverted),
+                    delta_time,
+                )
+                .clone(),
+        })
+    }
+
+    fn set_motor_speed(&mut self, left_speed: f64, right_speed: f64) -> Result<(), HalError> {
+        self.left_motor.target_speed = self.target_speed(&self.left_wheel, left_speed);
+        self.right_motor.target_speed = self.target_speed(&self.right_wheel, right_speed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Drives the simulated robot at the given speeds for a number of 10 ms steps.
+    fn drive(sim: &mut SimHal, left_speed: f64, right_speed: f64, steps: usize) -> HalState {
+        sim.set_motor_speed(left_speed, right_speed).unwrap();
+        let mut state = sim.poll_state(0.01).unwrap();
+        for _ in 1..steps {
+            state = sim.poll_state(0.01).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn test_sim_reaches_commanded_speed() {
+        let mut sim = SimHal::new(SimConfig::default());
+        let state = drive(&mut sim, 5.0, -2.5, 100);
+        // One tick per 10 ms step is about 0.9 rad/s of quantization.
+        assert!((state.left_wheel_state.velocity - 5.0).abs() < 1.0);
+        assert!((state.right_wheel_state.velocity + 2.5).abs() < 1.0);
+        // After a second, the wheels travelled less than at full speed all along because of the motor dynamics.
+        assert!(state.left_wheel_state.position > 4.0 && state.left_wheel_state.position < 5.0);
+        assert!(state.right_wheel_state.position < -2.0 && state.right_wheel_state.position > -2.5);
+
+        sim.stop().unwrap();
+        let state = drive(&mut sim, 0.0, 0.0, 100);
+        assert_eq!(state.left_wheel_state.velocity, 0.0);
+    }
+
+    #[test]
+    fn test_sim_saturates_and_quantizes() {
+        let mut sim = SimHal::new(SimConfig::default());
+        // 1400 ticks per second at most, with 700 ticks per revolution.
+        let ticks_per_rad = 700.0 / (2.0 * std::f64::consts::PI);
+        let state = drive(&mut sim, 100.0, 100.0, 200);
+        assert!(state.left_wheel_state.velocity < 1400.0 / ticks_per_rad + 1.0);
+        // Positions are multiples of a tick, like those read from encoders.
+        let ticks = state.left_wheel_state.position * ticks_per_rad;
+        assert!((ticks - ticks.round()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_sim_wheel_calibration() {
+        let config = SimConfig {
+            left_wheel_calibration: Some(WheelCalibration {
+                ticks_per_revolution: 100,
+                gear_ratio: 5.0,
+                motor_inverted: true,
+                encoder_inverted: true,
+            }),
+            ..Default::default()
+        };
+        let mut sim = SimHal::new(config);
+        // Both wheels report the speed requested, though the left encoder counts down through a gearbox.
+        let state = drive(&mut sim, 2.0, 2.0, 100);
+        assert!((state.left_wheel_state.velocity - 2.0).abs() < 1.0);
+        assert!((state.right_wheel_state.velocity - 2.0).abs() < 1.0);
+        assert!(state.left_wheel_state.position > 1.5);
+        assert!(sim.left_motor.encoder_count(true) < 0);
+        // A revolution of the left wheel is 500 ticks of its encoder.
+        let ticks = -sim.left_motor.encoder_count(true) as f64;
+        assert!((ticks / 500.0 * 2.0 * std::f64::consts::PI - state.left_wheel_state.position).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sim_noise() {
+        let noisy_config = SimConfig {
+            speed_noise: 0.5,
+            seed: 7,
+            ..Default::default()
+        };
+        let positions = |config: SimConfig| {
+            let mut sim = SimHal::new(config);
+            (0..5)
+                .map(|_| drive(&mut sim, 3.0, 3.0, 20).left_wheel_state.position)
+                .collect::<Vec<_>>()
+        };
+        // Reproducible with the same seed, but different from the noiseless run.
+        assert_eq!(positions(noisy_config.clone()), positions(noisy_config.clone()));
+        assert_ne!(positions(noisy_config), positions(SimConfig::default()));
+
+        let mut noise = NoiseGenerator::new(1);
+        let samples = (0..10000).map(|_| noise.next_normal(2.0)).collect::<Vec<_>>();
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        let variance = samples.iter().map(|sample| (sample - mean).powi(2)).sum::<f64>() / samples.len() as f64;
+        assert!(mean.abs() < 0.1);
+        assert!((variance.sqrt() - 2.0).abs() < 0.1);
+    }
+}
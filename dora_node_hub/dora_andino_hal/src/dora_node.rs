@@ -1,10 +1,34 @@
-use dora_node_api::{DoraNode, Event, arrow::array::Float64Array, dora_core::config::DataId};
+use dora_node_api::{
+    DoraNode, Event, MetadataParameters, Parameter,
+    arrow::array::{Array, Float64Array, StringArray},
+    dora_core::config::DataId,
+};
+
+use andino::core::comm::{CommStats, SerialCommandKind};
+use andino::core::hal::{ConnectionStatus, RobotHal, RobotState, SupervisedHal};
+use eyre::WrapErr;
 
 pub fn main() -> eyre::Result<()> {
     println!("Initializing Andino HAL interface...");
 
     // Configuration from environment variables
-    let serial_device = std::env::var("SERIAL_DEVICE").unwrap_or_else(|_| "/dev/ttyUSB0".to_string());
+    // What drives the wheels: `hardware` for the robot, or `sim` for a kinematic simulation of it.
+    let backend = std::env::var("BACKEND").unwrap_or_else(|_| "hardware".to_string());
+    // Rate in Hz at which a background thread polls the wheels, timing the samples with the monotonic clock.
+    // Zero polls them on every tick instead, over the time between the ticks.
+    let poll_rate = std::env::var("POLL_RATE")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse::<f64>()
+        .unwrap_or(0.0);
+    // Standard deviation of the noise on the speed of the simulated wheels, in rads per second.
+    let sim_speed_noise = std::env::var("SIM_SPEED_NOISE")
+        .unwrap_or_else(|_| "0.0".to_string())
+        .parse::<f64>()
+        .unwrap_or(0.0);
+    // Path of the device, `auto` or `usb:<vid>[:<pid>[:<serial number>]]`.
+    let serial_device = std::env::var("SERIAL_DEVICE")
+        .unwrap_or_else(|_| "/dev/ttyUSB0".to_string())
+        .parse::<andino::core::comm::SerialDeviceSelector>()?;
     let baud_rate = std::env::var("BAUD_RATE")
         .unwrap_or_else(|_| "57600".to_string())
         .parse::<u32>()
@@ -17,31 +41,210 @@ pub fn main() -> eyre::Result<()> {
         .unwrap_or_else(|_| "700".to_string())
         .parse::<u64>()
         .unwrap_or(700);
+    let ready_timeout = std::env::var("READY_TIMEOUT")
+        .unwrap_or_else(|_| "5000".to_string())
+        .parse::<u64>()
+        .unwrap_or(5000);
+    // Named GPIO pins as a comma separated list of `<name>:<kind>:<pin>`.
+    let gpio_pins = std::env::var("GPIO_PINS")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|pin_config| !pin_config.trim().is_empty())
+        .map(|pin_config| pin_config.parse::<andino::core::gpio::GpioPinConfig>())
+        .collect::<Result<Vec<_>, _>>()?;
+    // Reconnect to the hardware when the serial device disappears, instead of exiting.
+    let reconnect = std::env::var("RECONNECT")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .unwrap_or(false);
+    let reconnect_initial_backoff = std::env::var("RECONNECT_INITIAL_BACKOFF")
+        .unwrap_or_else(|_| "100".to_string())
+        .parse::<u64>()
+        .unwrap_or(100);
+    let reconnect_max_backoff = std::env::var("RECONNECT_MAX_BACKOFF")
+        .unwrap_or_else(|_| "5000".to_string())
+        .parse::<u64>()
+        .unwrap_or(5000);
+    // Times a dropped or garbled encoder read is retried before the poll fails.
+    let encoder_read_retries = std::env::var("ENCODER_READ_RETRIES")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse::<u32>()
+        .unwrap_or(0);
+    // File to record the serial traffic to, for offline replay.
+    let record_file = std::env::var("RECORD_FILE")
+        .ok()
+        .filter(|record_file| !record_file.is_empty())
+        .map(std::path::PathBuf::from);
+    // Wire protocol: `ascii` for the stock firmware, or `binary` for firmware speaking the framed protocol.
+    let protocol = std::env::var("PROTOCOL")
+        .unwrap_or_else(|_| "ascii".to_string())
+        .parse::<andino::core::comm::Protocol>()?;
+    // Stop the motors when no `joints_speed_cmd` arrives for this long in milliseconds, 0 to disable it.
+    // An invalid value is an error, as falling back to 0 would silently disable it.
+    let command_timeout = std::env::var("COMMAND_TIMEOUT")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse::<u64>()
+        .wrap_err("Invalid COMMAND_TIMEOUT")?;
+    // Period of the `diagnostics` output in milliseconds.
+    let diagnostics_period = std::env::var("DIAGNOSTICS_PERIOD")
+        .unwrap_or_else(|_| "1000".to_string())
+        .parse::<u64>()
+        .unwrap_or(1000);
+    // Gains of the PID speed controllers as `<kp>:<ki>:<kd>:<ko>`, applied at startup. Unset keeps the firmware's.
+    let pid_gains = std::env::var("PID_GAINS")
+        .ok()
+        .filter(|pid_gains| !pid_gains.trim().is_empty())
+        .map(|pid_gains| pid_gains.parse::<andino::core::hal::PidGains>())
+        .transpose()?;
+
+    // Calibration of each wheel as `<ticks_per_revolution>:<gear_ratio>:<motor_direction>:<encoder_direction>`, with
+    // directions of 1, or -1 when inverted. Unset drives the wheel directly with MOTOR_TICKS_PER_REVOLUTION.
+    let wheel_calibration = |name: &str| {
+        std::env::var(name)
+            .ok()
+            .filter(|calibration| !calibration.trim().is_empty())
+            .map(|calibration| calibration.parse::<andino::core::sensors::WheelCalibration>())
+            .transpose()
+    };
+    let left_wheel_calibration = wheel_calibration("LEFT_WHEEL_CALIBRATION")?;
+    let right_wheel_calibration = wheel_calibration("RIGHT_WHEEL_CALIBRATION")?;
+
+    // Maximum speed of the wheels in rads per second (MAX_WHEEL_VELOCITY) and maximum acceleration in rads per second
+    // squared (MAX_WHEEL_ACCELERATION), 0 for no limit. The `LEFT_` and `RIGHT_` prefixed ones override them for a
+    // wheel. An invalid value is an error, as falling back to 0 would silently drop the limit.
+    let wheel_limits = |wheel: &str| -> eyre::Result<Option<andino::core::hal::WheelLimits>> {
+        let limit = |name: &str| -> eyre::Result<f64> {
+            let wheel_name = format!("{}_{}", wheel, name);
+            let (name, value) = match std::env::var(&wheel_name) {
+                Ok(value) => (wheel_name, value),
+                Err(_) => (
+                    name.to_string(),
+                    std::env::var(name).unwrap_or_else(|_| "0".to_string()),
+                ),
+            };
+            value
+                .trim()
+                .parse::<f64>()
+                .wrap_err_with(|| format!("Invalid {}: '{}'", name, value))
+        };
+        let max_velocity = limit("MAX_WHEEL_VELOCITY")?;
+        let max_acceleration = limit("MAX_WHEEL_ACCELERATION")?;
+        let unlimited_if_zero = |limit: f64| if limit > 0.0 { limit } else { f64::INFINITY };
+        Ok(
+            (max_velocity > 0.0 || max_acceleration > 0.0).then(|| andino::core::hal::WheelLimits {
+                max_velocity: unlimited_if_zero(max_velocity),
+                max_acceleration: unlimited_if_zero(max_acceleration),
+            }),
+        )
+    };
+    let left_wheel_limits = wheel_limits("LEFT")?;
+    let right_wheel_limits = wheel_limits("RIGHT")?;
 
     let hal_config = andino::core::hal::HalConfig {
         serial_device,
         baud_rate,
         timeout,
         motor_ticks_per_revolution,
+        left_wheel_calibration,
+        right_wheel_calibration,
+        ready_timeout,
+        gpio_pins,
+        reconnect: reconnect.then_some(andino::core::hal::ReconnectPolicy {
+            initial_backoff: reconnect_initial_backoff,
+            max_backoff: reconnect_max_backoff,
+        }),
+        record_file,
+        retry_policies: std::collections::HashMap::from([(
+            andino::core::comm::SerialCommandKind::ReadEncoderValues,
+            andino::core::comm::RetryPolicy {
+                max_retries: encoder_read_retries,
+                retry_delay: 0,
+            },
+        )]),
+        protocol,
+        pid_gains,
+        command_timeout: (command_timeout > 0).then_some(command_timeout),
+        left_wheel_limits,
+        right_wheel_limits,
     };
     println!("HalConfig: {:?}", &hal_config);
 
-    let mut andino_hal = andino::core::hal::Hal::new(&hal_config)?;
+    let diagnostics_period = std::time::Duration::from_millis(diagnostics_period);
+    let (robot, reconnect): (Box<dyn RobotHal + Send>, bool) = match backend.as_str() {
+        "hardware" => {
+            let andino_hal = andino::core::hal::Hal::new(&hal_config)?;
+            println!("Firmware: {}", andino_hal.firmware_info());
+            (Box::new(andino_hal), reconnect)
+        }
+        "sim" => {
+            let sim_config = andino::core::sim::SimConfig {
+                motor_ticks_per_revolution,
+                left_wheel_calibration,
+                right_wheel_calibration,
+                speed_noise: sim_speed_noise,
+                ..Default::default()
+            };
+            println!("SimConfig: {:?}", &sim_config);
+            (Box::new(andino::core::sim::SimHal::new(sim_config)), false)
+        }
+        _ => {
+            return Err(eyre::eyre!(
+                "Unknown backend: {} (expected `hardware` or `sim`)",
+                backend
+            ));
+        }
+    };
+    if poll_rate > 0.0 {
+        let period = std::time::Duration::from_secs_f64(1.0 / poll_rate);
+        run(
+            andino::core::hal::PollingHal::spawn(robot, period)?,
+            reconnect,
+            diagnostics_period,
+        )
+    } else {
+        run(robot, reconnect, diagnostics_period)
+    }
+}
 
-    // TODO(francocipollone): Remove this sleep
-    std::thread::sleep(std::time::Duration::from_secs(3));
+/// Runs the node loop over any robot: drives its wheels with `joints_speed_cmd` and publishes their state on
+/// every `tick`.
+///
+/// # Arguments
+///
+/// * `robot` - The robot to drive.
+/// * `reconnect` - Whether the robot reconnects on its own, so that its errors are reported instead of ending the node.
+/// * `diagnostics_period` - The period of the `diagnostics` output.
+pub fn run(robot: impl RobotHal, reconnect: bool, diagnostics_period: std::time::Duration) -> eyre::Result<()> {
+    // Track the state of the robot, with the emergency stop triggered and reset from the dataflow.
+    let mut robot = SupervisedHal::new(robot);
+    let capabilities = robot.capabilities();
+    println!("Capabilities: {:?}", capabilities);
+    if capabilities.read_pid_gains {
+        println!("PID gains: {}", robot.read_pid_gains()?);
+    }
 
     let output_wheel_joint_positions = DataId::from("wheel_joint_positions".to_owned());
     let output_wheel_joint_velocities = DataId::from("wheel_joint_velocities".to_owned());
+    let output_gpio_inputs = DataId::from("gpio_inputs".to_owned());
+    let output_connection_status = DataId::from("connection_status".to_owned());
+    let output_diagnostics = DataId::from("diagnostics".to_owned());
+    let output_robot_state = DataId::from("robot_state".to_owned());
+    let has_gpio_inputs = !capabilities.gpio_inputs.is_empty();
 
     let (mut node, mut events) = DoraNode::init_from_env()?;
 
-    let mut andino_hal_state;
     let mut last_timestamp = Option::None;
+    let mut last_diagnostics = std::time::Instant::now();
+    let mut max_tick_interval: f64 = 0.0;
+    // The latest speed command, sent along with the encoder read of the next tick.
+    let mut motor_speed: Option<(f64, f64)> = None;
     while let Some(event) = events.recv() {
         match event {
             Event::Stop(_) => {
                 println!("Received stop event");
+                if let Err(err) = robot.stop() {
+                    eprintln!("Failed to stop the motors: {}", err);
+                }
                 break;
             }
             Event::Input { id, data, metadata } => {
@@ -54,7 +257,67 @@ pub fn main() -> eyre::Result<()> {
                             .timestamp()
                             .get_diff_duration(&last_timestamp.unwrap())
                             .as_secs_f64();
-                        andino_hal_state = andino_hal.poll_state(delta_time)?;
+                        // Publish the statistics of the serial link and the longest time between ticks of the period.
+                        max_tick_interval = max_tick_interval.max(delta_time);
+                        if capabilities.comm_stats && last_diagnostics.elapsed() >= diagnostics_period {
+                            let (names, mut diagnostics_data) =
+                                diagnostics(&robot.take_comm_stats(), max_tick_interval);
+                            diagnostics_data.push(metadata.timestamp().get_time().to_duration().as_secs_f64());
+                            let mut parameters = metadata.parameters.clone();
+                            parameters.insert("names".to_string(), Parameter::ListString(names));
+                            node.send_output(
+                                output_diagnostics.clone(),
+                                parameters,
+                                Float64Array::from(diagnostics_data),
+                            )?;
+                            last_diagnostics = std::time::Instant::now();
+                            max_tick_interval = 0.0;
+                        }
+                        let tick_motor_speed = motor_speed.take();
+                        let andino_hal_state = match robot.cycle(tick_motor_speed, delta_time) {
+                            Ok(andino_hal_state) => Some(andino_hal_state),
+                            // While reconnecting, keep the node alive and report the degraded state.
+                            Err(err) if reconnect => {
+                                eprintln!("Failed to poll the HAL state: {}", err);
+                                // Send the speed again on the next tick, unless a newer one arrives.
+                                motor_speed = motor_speed.or(tick_motor_speed);
+                                None
+                            }
+                            Err(err) => return Err(err.into()),
+                        };
+                        if let (Some(_), Some(clamp)) = (tick_motor_speed, robot.last_speed_clamp()) {
+                            if clamp.velocity_clamped {
+                                eprintln!(
+                                    "Speed command {:?} rad/s clamped to {:?} rad/s",
+                                    clamp.requested, clamp.limited
+                                );
+                            }
+                        }
+                        // Publish the connection status, with the reconnection details as parameters.
+                        let mut parameters = metadata.parameters.clone();
+                        let connection_status = match robot.connection_status() {
+                            ConnectionStatus::Connected => "connected",
+                            ConnectionStatus::Reconnecting { attempts, last_error } => {
+                                parameters.insert("attempts".to_string(), Parameter::Integer(i64::from(attempts)));
+                                parameters.insert("last_error".to_string(), Parameter::String(last_error));
+                                "reconnecting"
+                            }
+                        };
+                        node.send_output(
+                            output_connection_status.clone(),
+                            parameters,
+                            StringArray::from(vec![connection_status]),
+                        )?;
+                        send_robot_state(
+                            &mut node,
+                            &output_robot_state,
+                            robot.state(),
+                            metadata.parameters.clone(),
+                        )?;
+                        let Some(andino_hal_state) = andino_hal_state else {
+                            last_timestamp = Some(metadata.timestamp());
+                            continue;
+                        };
 
                         // Publish wheel joint positions
                         let wheel_joint_positions_data = Float64Array::from(vec![
@@ -78,6 +341,33 @@ pub fn main() -> eyre::Result<()> {
                             metadata.parameters.clone(),
                             wheel_joint_velocities_data,
                         )?;
+                        // Publish the configured GPIO inputs, with their names as a parameter.
+                        if has_gpio_inputs {
+                            let gpio_inputs = match robot.read_gpio_inputs() {
+                                Ok(gpio_inputs) => gpio_inputs,
+                                Err(err) if reconnect => {
+                                    eprintln!("Failed to read the GPIO inputs: {}", err);
+                                    last_timestamp = Some(metadata.timestamp());
+                                    continue;
+                                }
+                                Err(err) => return Err(err.into()),
+                            };
+                            let mut gpio_inputs_data = gpio_inputs
+                                .iter()
+                                .map(|(_, value)| value.as_f64())
+                                .collect::<Vec<f64>>();
+                            gpio_inputs_data.push(metadata.timestamp().get_time().to_duration().as_secs_f64());
+                            let mut parameters = metadata.parameters.clone();
+                            parameters.insert(
+                                "names".to_string(),
+                                Parameter::ListString(gpio_inputs.into_iter().map(|(name, _)| name).collect()),
+                            );
+                            node.send_output(
+                                output_gpio_inputs.clone(),
+                                parameters,
+                                Float64Array::from(gpio_inputs_data),
+                            )?;
+                        }
                     }
                     "joints_speed_cmd" => {
                         let values = if let Some(float_array) = data.as_any().downcast_ref::<Float64Array>() {
@@ -90,9 +380,58 @@ pub fn main() -> eyre::Result<()> {
                             eprintln!("Expected 2 elements in the list, got: {:?}", data);
                             continue;
                         }
-                        let left_speed = values.value(0);
-                        let right_speed = values.value(1);
-                        andino_hal.set_motor_speed(left_speed, right_speed)?;
+                        motor_speed = Some((values.value(0), values.value(1)));
+                    }
+                    "estop" => {
+                        // The reason, if given as a string, is reported by the state.
+                        let reason = data
+                            .as_any()
+                            .downcast_ref::<StringArray>()
+                            .filter(|reason| !reason.is_empty())
+                            .map_or_else(|| "estop input".to_string(), |reason| reason.value(0).to_string());
+                        motor_speed = None;
+                        if let Err(err) = robot.estop(reason) {
+                            eprintln!("Failed to stop the motors: {}", err);
+                        }
+                        send_robot_state(
+                            &mut node,
+                            &output_robot_state,
+                            robot.state(),
+                            metadata.parameters.clone(),
+                        )?;
+                    }
+                    "reset" => {
+                        robot.reset();
+                        send_robot_state(
+                            &mut node,
+                            &output_robot_state,
+                            robot.state(),
+                            metadata.parameters.clone(),
+                        )?;
+                    }
+                    "pid_gains" => {
+                        let values = if let Some(float_array) = data.as_any().downcast_ref::<Float64Array>() {
+                            float_array
+                        } else {
+                            eprintln!("Not a Float64Array!");
+                            continue;
+                        };
+                        if values.len() != 4 {
+                            eprintln!("Expected 4 elements in the list, got: {:?}", data);
+                            continue;
+                        }
+                        // A rejected tuning keeps the node running with the previous gains.
+                        match andino::core::hal::PidGains::new(
+                            values.value(0) as f32,
+                            values.value(1) as f32,
+                            values.value(2) as f32,
+                            values.value(3) as f32,
+                        )
+                        .and_then(|pid_gains| robot.set_pid_gains(pid_gains).map(|()| pid_gains))
+                        {
+                            Ok(pid_gains) => println!("PID gains set to {}", pid_gains),
+                            Err(err) => eprintln!("Failed to set the PID gains: {}", err),
+                        }
                     }
                     _ => {
                         println!("Unexpected input id: {:?}", id);
@@ -117,3 +456,68 @@ pub fn main() -> eyre::Result<()> {
 
     Ok(())
 }
+
+/// Publishes the state of the robot, with why it is emergency stopped or faulted as the `reason` parameter.
+fn send_robot_state(
+    node: &mut DoraNode,
+    output_robot_state: &DataId,
+    robot_state: &RobotState,
+    mut parameters: MetadataParameters,
+) -> eyre::Result<()> {
+    if let Some(reason) = robot_state.reason() {
+        parameters.insert("reason".to_string(), Parameter::String(reason.to_string()));
+    }
+    node.send_output(
+        output_robot_state.clone(),
+        parameters,
+        StringArray::from(vec![robot_state.name()]),
+    )?;
+    Ok(())
+}
+
+/// Flattens the communication statistics of a period into named values, latencies and intervals in milliseconds.
+///
+/// Latencies are `NaN` when no command got a response during the period.
+fn diagnostics(comm_stats: &CommStats, max_tick_interval: f64) -> (Vec<String>, Vec<f64>) {
+    let total = comm_stats.total();
+    let as_millis =
+        |latency: Option<std::time::Duration>| latency.map_or(f64::NAN, |latency| latency.as_secs_f64() * 1000.0);
+    [
+        ("max_tick_interval_ms", max_tick_interval * 1000.0),
+        ("exchanges", total.exchanges as f64),
+        ("timeouts", total.timeouts as f64),
+        ("parse_errors", total.parse_errors as f64),
+        ("other_errors", total.other_errors as f64),
+        ("retries", total.retries as f64),
+        ("bytes_sent", comm_stats.bytes_sent as f64),
+        ("bytes_received", comm_stats.bytes_received as f64),
+        ("latency_mean_ms", as_millis(total.latency.mean())),
+        ("latency_p50_ms", as_millis(total.latency.percentile(50.0))),
+        ("latency_p99_ms", as_millis(total.latency.percentile(99.0))),
+        ("latency_max_ms", as_millis(total.latency.max())),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    // The same per kind of command sent in the period, suffixed with its letter, e.g. `latency_p99_ms.e`.
+    .chain(
+        SerialCommandKind::ALL
+            .iter()
+            .filter_map(|command_kind| Some((command_kind.letter(), comm_stats.commands.get(command_kind)?)))
+            .flat_map(|(letter, command_stats)| {
+                [
+                    ("exchanges", command_stats.exchanges as f64),
+                    (
+                        "errors",
+                        (command_stats.timeouts + command_stats.parse_errors + command_stats.other_errors) as f64,
+                    ),
+                    ("retries", command_stats.retries as f64),
+                    ("latency_mean_ms", as_millis(command_stats.latency.mean())),
+                    ("latency_p99_ms", as_millis(command_stats.latency.percentile(99.0))),
+                    ("latency_max_ms", as_millis(command_stats.latency.max())),
+                ]
+                .into_iter()
+                .map(move |(name, value)| (format!("{}.{}", name, letter), value))
+            }),
+    )
+    .unzip()
+}
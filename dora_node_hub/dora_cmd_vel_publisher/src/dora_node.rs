@@ -0,0 +1,63 @@
+use dora_node_api::{DoraNode, Event, arrow::array::Float64Array, dora_core::config::DataId};
+use eyre::WrapErr;
+use std::vec;
+
+fn create_twist_array(linear: f64, angular: f64) -> Float64Array {
+    Float64Array::new(vec![linear, 0.0, 0.0, 0.0, 0.0, angular].into(), None)
+}
+
+pub fn main() -> eyre::Result<()> {
+    let output_cmd_vel = DataId::from("cmd_vel".to_owned());
+    println!("Command Velocity Publisher Node initialized");
+
+    // Configure the node. An invalid speed is an error rather than a robot standing still.
+    let speed = |name: &str| -> eyre::Result<f64> {
+        match std::env::var(name) {
+            Ok(speed) => speed
+                .trim()
+                .parse::<f64>()
+                .wrap_err_with(|| format!("Invalid {}: '{}'", name, speed)),
+            Err(_) => Ok(0.0),
+        }
+    };
+    let linear_speed = speed("LINEAR_SPEED")?;
+    let angular_speed = speed("ANGULAR_SPEED")?;
+    println!("Linear speed: {}", linear_speed);
+    println!("Angular speed: {}", angular_speed);
+
+    let (mut node, mut events) = DoraNode::init_from_env()?;
+    while let Some(event) = events.recv() {
+        match event {
+            Event::Stop(_) => {
+                println!("Received stop event");
+                break;
+            }
+            Event::Input { id, metadata, .. } => match id.as_str() {
+                "tick" => {
+                    node.send_output(
+                        output_cmd_vel.clone(),
+                        metadata.parameters,
+                        create_twist_array(linear_speed, angular_speed),
+                    )?;
+                }
+                _ => {
+                    println!("Unexpected input id: {:?}", id);
+                }
+            },
+            Event::Reload { operator_id } => {
+                eprintln!("Not expected: Received reload event for operator: {:?}", operator_id);
+            }
+            Event::InputClosed { id } => {
+                eprintln!("Not expected: Received input closed event: id = {:?}", id);
+            }
+            Event::Error(err) => {
+                eprintln!("Not expected: Received error event: {:?}", err);
+            }
+            _ => {
+                eprintln!("Received unexpected event: {:?}", event);
+            }
+        }
+    }
+
+    Ok(())
+}
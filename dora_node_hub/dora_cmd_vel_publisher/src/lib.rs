@@ -0,0 +1 @@
+pub mod dora_node;
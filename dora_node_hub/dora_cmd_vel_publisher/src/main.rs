@@ -0,0 +1,3 @@
+fn main() -> eyre::Result<()> {
+    dora_cmd_vel_publisher::dora_node::main()
+}